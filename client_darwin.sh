@@ -3,8 +3,8 @@ CONF=$1
 
 echo "CONF: $CONF"
 
-ADDR=$(./target/release/caetun-conf --conf $CONF | jq -r '"\(.interface.address[0])"')
-IP=$(./target/release/caetun-conf --conf $CONF | jq -r '"\(.interface.address[0])/\(.interface.address[1])"')
+ADDR=$(./target/release/caetun-conf --conf $CONF | jq -r '"\(.interface.address[0][0])"')
+IP=$(./target/release/caetun-conf --conf $CONF | jq -r '"\(.interface.address[0][0])/\(.interface.address[0][1])"')
 
 ./target/release/caetun --conf $CONF &
 
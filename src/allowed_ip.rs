@@ -1,8 +1,10 @@
 use std::collections::VecDeque;
 use std::net::IpAddr;
+use std::sync::Arc;
 
 use ip_network::IpNetwork;
 use ip_network_table::IpNetworkTable;
+use parking_lot::{Mutex, RwLock};
 
 /// AllowedIps is a trie of IP/cidr addresses.
 ///
@@ -19,7 +21,6 @@ pub struct AllowedIps<D> {
     ips: IpNetworkTable<D>,
 }
 
-#[allow(dead_code)]
 impl<D> AllowedIps<D> {
     pub fn new() -> Self {
         Self {
@@ -27,10 +28,6 @@ impl<D> AllowedIps<D> {
         }
     }
 
-    pub fn clear(&mut self) {
-        self.ips = IpNetworkTable::new();
-    }
-
     pub fn insert(&mut self, key: IpAddr, cidr: u8, data: D) -> Option<D> {
         self.ips.insert(
             IpNetwork::new_truncate(key, cidr).expect("cidr is valid length"),
@@ -42,8 +39,17 @@ impl<D> AllowedIps<D> {
         self.ips.longest_match(key).map(|(_net, data)| data)
     }
 
-    pub fn remove(&mut self, predicate: impl Fn(&D) -> bool) {
-        self.ips.retain(|_, v| !predicate(v));
+    /// remove every prefix whose data matches `predicate`, returning the removed prefixes.
+    pub fn remove(&mut self, predicate: impl Fn(&D) -> bool) -> Vec<(IpAddr, u8)> {
+        let mut removed = vec![];
+        self.ips.retain(|ipn, v| {
+            let keep = !predicate(v);
+            if !keep {
+                removed.push((ipn.network_address(), ipn.netmask()));
+            }
+            keep
+        });
+        removed
     }
 
     pub fn iter(&self) -> Iter<D> {
@@ -65,6 +71,14 @@ impl<'a, D> Iterator for Iter<'a, D> {
     }
 }
 
+impl<D: Clone> Clone for AllowedIps<D> {
+    fn clone(&self) -> Self {
+        let mut ips = Self::new();
+        ips.extend(self.iter().map(|(d, ip, cidr)| (ip, cidr, d.clone())));
+        ips
+    }
+}
+
 impl<T> Extend<(IpAddr, u8, T)> for AllowedIps<T> {
     fn extend<I: IntoIterator<Item = (IpAddr, u8, T)>>(&mut self, iter: I) {
         for (ip, cidr, value) in iter {
@@ -73,9 +87,55 @@ impl<T> Extend<(IpAddr, u8, T)> for AllowedIps<T> {
     }
 }
 
+/// SharedAllowedIps is a copy-on-write (RCU style) cell around AllowedIps.
+///
+/// Readers call `load` and get an immutable snapshot which stays consistent for as long as they hold
+/// it, they never wait for writers beyond the pointer swap. Writers are serialized, each update copies
+/// the current table, applies the change to the copy and publishes it, so an update costs a full copy
+/// of the table and is meant for route changes, not for the packet path.
+#[derive(Default)]
+pub struct SharedAllowedIps<D> {
+    current: RwLock<Arc<AllowedIps<D>>>,
+    update: Mutex<()>,
+}
+
+impl<D: Clone> SharedAllowedIps<D> {
+    /// load returns the current snapshot of the table.
+    pub fn load(&self) -> Arc<AllowedIps<D>> {
+        Arc::clone(&self.current.read())
+    }
+
+    /// get_mut gives direct access to the table when nobody else can observe it, e.g. while the
+    /// device is being set up. No copy is made unless a snapshot is still alive.
+    pub fn get_mut(&mut self) -> &mut AllowedIps<D> {
+        Arc::make_mut(self.current.get_mut())
+    }
+
+    /// update applies `f` to a copy of the table and publishes the result.
+    pub fn update<R>(&self, f: impl FnOnce(&mut AllowedIps<D>) -> R) -> R {
+        let _guard = self.update.lock();
+
+        let mut ips = AllowedIps::clone(&self.load());
+        let ret = f(&mut ips);
+        *self.current.write() = Arc::new(ips);
+
+        ret
+    }
+
+    pub fn insert(&self, key: IpAddr, cidr: u8, data: D) -> Option<D> {
+        self.update(|ips| ips.insert(key, cidr, data))
+    }
+
+    pub fn remove(&self, predicate: impl Fn(&D) -> bool) -> Vec<(IpAddr, u8)> {
+        self.update(|ips| ips.remove(predicate))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
 
     fn build_allowed_ips() -> AllowedIps<char> {
         let mut map: AllowedIps<char> = Default::default();
@@ -134,4 +194,69 @@ mod tests {
         );
         assert_eq!(map_iter.next(), None);
     }
+
+    #[test]
+    fn test_shared_allowed_ips_insert_remove() {
+        let mut shared = SharedAllowedIps::default();
+        *shared.get_mut() = build_allowed_ips();
+        let before = shared.load();
+
+        assert_eq!(shared.insert(IpAddr::from([10, 8, 0, 2]), 32, '8'), None);
+        assert_eq!(
+            shared.insert(IpAddr::from([10, 8, 0, 2]), 32, '9'),
+            Some('8')
+        );
+        shared.remove(|c| *c == '2');
+
+        let after = shared.load();
+        assert_eq!(after.find(IpAddr::from([10, 8, 0, 2])), Some(&'9'));
+        assert_eq!(after.find(IpAddr::from([127, 0, 255, 255])), None);
+
+        // a snapshot taken earlier is not affected by later updates
+        assert_eq!(before.find(IpAddr::from([10, 8, 0, 2])), None);
+        assert_eq!(before.find(IpAddr::from([127, 0, 255, 255])), Some(&'2'));
+    }
+
+    #[test]
+    fn test_shared_allowed_ips_concurrent_lookup() {
+        let lan = IpAddr::from([192, 168, 1, 10]);
+        let host = IpAddr::from([10, 8, 0, 2]);
+
+        let mut shared = SharedAllowedIps::default();
+        shared.get_mut().insert(lan, 24, 'a');
+        shared.get_mut().insert(host, 32, 'a');
+        let done = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut lookups = 0;
+                    while !done.load(Ordering::Relaxed) || lookups == 0 {
+                        // both prefixes always move between peers together, a reader must never
+                        // see a half applied update
+                        let snapshot = shared.load();
+                        let owner = snapshot.find(lan);
+                        assert!(owner.is_some());
+                        assert_eq!(owner, snapshot.find(host));
+                        lookups += 1;
+                    }
+                });
+            }
+
+            s.spawn(|| {
+                for i in 0..2000 {
+                    let (from, to) = if i % 2 == 0 { ('a', 'b') } else { ('b', 'a') };
+                    shared.update(|ips| {
+                        ips.remove(|c| *c == from);
+                        ips.insert(lan, 24, to);
+                        ips.insert(host, 32, to);
+                    });
+                }
+                done.store(true, Ordering::Relaxed);
+            });
+        });
+
+        assert_eq!(shared.load().find(host), Some(&'a'));
+        assert_eq!(shared.load().iter().count(), 2);
+    }
 }
@@ -0,0 +1,339 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::fd::IntoRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use serde::Serialize;
+use tracing::info;
+
+use crate::device::{Device, DeviceConfig};
+use crate::mtu::MAX_MTU;
+use crate::peer::{Peer, PeerName};
+
+const SENDER_ADDR: Ipv4Addr = Ipv4Addr::new(10, 99, 0, 1);
+const RECEIVER_ADDR: Ipv4Addr = Ipv4Addr::new(10, 99, 0, 2);
+
+/// ipv4 header followed by the sequence number and the send timestamp of a probe.
+pub const MIN_PACKET_SIZE: usize = 20 + 16;
+
+/// how long the receiver waits for the next packet before the rest are counted as lost.
+const RECV_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// how long to wait for the handshake between the two devices to complete.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// BenchConfig describes a benchmark run.
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    /// inner packet sizes to measure, including the ipv4 header.
+    pub sizes: Vec<usize>,
+    /// number of packets sent for every size.
+    pub count: usize,
+    /// maximum number of packets in flight, bounds the queueing part of the latency.
+    pub window: usize,
+    /// let the devices aggregate packets into `Batch` datagrams.
+    pub batch: bool,
+    /// talk over IPv6 loopback instead of IPv4.
+    pub ipv6: bool,
+    /// use a connected socket per peer, like `Device` does by default.
+    pub use_connected_peer: bool,
+}
+
+/// BenchReport is the result of a benchmark run, one entry per packet size.
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub results: Vec<BenchResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BenchResult {
+    pub size: usize,
+    pub sent: usize,
+    pub received: usize,
+    pub elapsed_secs: f64,
+    pub packets_per_sec: f64,
+    pub gbit_per_sec: f64,
+    pub latency_us: Latency,
+}
+
+/// Latency percentiles in microseconds, measured from the write into the sending device's tun to
+/// the read from the receiving device's tun.
+#[derive(Debug, Default, Serialize)]
+pub struct Latency {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+/// In-memory stand-in for a tun interface.
+///
+/// The device owns one end of a datagram socketpair through the "name is a fd" path of `TunSocket`,
+/// the benchmark reads and writes raw ip packets on the other end.
+struct MemTun {
+    sock: UnixDatagram,
+    fd_name: String,
+}
+
+impl MemTun {
+    fn new() -> io::Result<Self> {
+        let (sock, dev) = UnixDatagram::pair()?;
+        Ok(Self {
+            sock,
+            fd_name: dev.into_raw_fd().to_string(),
+        })
+    }
+}
+
+/// run spins up two devices over loopback udp and pushes packets from one to the other through
+/// their tun stand-ins.
+pub fn run(config: &BenchConfig) -> anyhow::Result<BenchReport> {
+    if cfg!(not(target_os = "linux")) {
+        bail!(
+            "bench hands a socketpair to the device as its tun, which is only supported on linux"
+        );
+    }
+    if config.count == 0 || config.window == 0 {
+        bail!("count and window must be greater than 0");
+    }
+
+    let loopback = if config.ipv6 {
+        IpAddr::from(Ipv6Addr::LOCALHOST)
+    } else {
+        IpAddr::from(Ipv4Addr::LOCALHOST)
+    };
+    let sender_port = free_udp_port(loopback)?;
+    let receiver_port = free_udp_port(loopback)?;
+
+    let sender_tun = MemTun::new()?;
+    let receiver_tun = MemTun::new()?;
+
+    let mut sender = Device::new(DeviceConfig::new(
+        PeerName::new("bench-sender")?,
+        &sender_tun.fd_name,
+        sender_port,
+        config.use_connected_peer,
+    ))?;
+    // the tun stand-ins report the default mtu of 1500
+    let mtu = sender.mtu();
+    for &size in &config.sizes {
+        if !(MIN_PACKET_SIZE..=mtu).contains(&size) {
+            bail!("packet size {size} out of range {MIN_PACKET_SIZE}..={mtu}");
+        }
+    }
+    let mut peer = Peer::default();
+    peer.set_endpoint(SocketAddr::new(loopback, receiver_port));
+    peer.set_batch(config.batch);
+    let allowed_ips = [(RECEIVER_ADDR.into(), 32)];
+    sender.add_peer(PeerName::new("bench-receiver")?, peer, &allowed_ips);
+
+    let mut receiver = Device::new(DeviceConfig::new(
+        PeerName::new("bench-receiver")?,
+        &receiver_tun.fd_name,
+        receiver_port,
+        config.use_connected_peer,
+    ))?;
+    let mut peer = Peer::default();
+    peer.set_batch(config.batch);
+    let allowed_ips = [(SENDER_ADDR.into(), 32)];
+    receiver.add_peer(PeerName::new("bench-sender")?, peer, &allowed_ips);
+
+    // the devices never return from `wait`, their threads end with the process
+    for dev in [receiver, sender] {
+        let dev = Arc::new(dev);
+        dev.start()?;
+        thread::spawn(move || dev.wait());
+    }
+
+    let start = Instant::now();
+    wait_for_handshake(&sender_tun, &receiver_tun, start)?;
+
+    let results = config
+        .sizes
+        .iter()
+        .map(|&size| run_size(config, size, &sender_tun, &receiver_tun, start))
+        .collect::<anyhow::Result<_>>()?;
+
+    Ok(BenchReport { results })
+}
+
+fn free_udp_port(loopback: IpAddr) -> io::Result<u16> {
+    let sock = UdpSocket::bind((loopback, 0))?;
+    Ok(sock.local_addr()?.port())
+}
+
+fn wait_for_handshake(tx: &MemTun, rx: &MemTun, start: Instant) -> anyhow::Result<()> {
+    let mut packet = [0u8; MIN_PACKET_SIZE];
+    let mut buf = vec![0u8; MAX_MTU as usize];
+
+    rx.sock.set_read_timeout(Some(Duration::from_millis(50)))?;
+
+    // data is dropped by the sender until the handshake completes, so keep probing
+    while start.elapsed() < HANDSHAKE_TIMEOUT {
+        format_probe(&mut packet, 0, start);
+        tx.sock.send(&packet)?;
+
+        if rx.sock.recv(&mut buf).is_ok() {
+            // drain late probes before measuring
+            while rx.sock.recv(&mut buf).is_ok() {}
+            info!("handshake completed in {:?}", start.elapsed());
+            return Ok(());
+        }
+    }
+
+    bail!("handshake did not complete within {HANDSHAKE_TIMEOUT:?}")
+}
+
+fn run_size(
+    config: &BenchConfig,
+    size: usize,
+    tx: &MemTun,
+    rx: &MemTun,
+    start: Instant,
+) -> anyhow::Result<BenchResult> {
+    let mut packet = vec![0u8; size];
+    let mut buf = vec![0u8; MAX_MTU as usize];
+    let mut latencies = Vec::with_capacity(config.count);
+
+    rx.sock.set_read_timeout(Some(RECV_TIMEOUT))?;
+
+    let begin = Instant::now();
+    let mut elapsed = Duration::ZERO;
+    let mut sent = 0;
+
+    while latencies.len() < sent || sent < config.count {
+        while sent < config.count && sent - latencies.len() < config.window {
+            format_probe(&mut packet, sent as u64 + 1, start);
+            tx.sock.send(&packet).context("write to sender tun")?;
+            sent += 1;
+        }
+
+        match rx.sock.recv(&mut buf) {
+            Ok(n) if n >= MIN_PACKET_SIZE => {
+                let sent_at = u64::from_le_bytes(buf[28..36].try_into().unwrap());
+                let now = start.elapsed().as_nanos() as u64;
+                latencies.push(now.saturating_sub(sent_at));
+                elapsed = begin.elapsed();
+            }
+            Ok(_) => (),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                // whatever is still in flight is lost
+                break;
+            }
+            Err(e) => return Err(e).context("read from receiver tun"),
+        }
+    }
+
+    // stop the clock at the last packet received, not at the timeout that detected a loss
+    let elapsed = elapsed.as_secs_f64().max(f64::EPSILON);
+    let received = latencies.len();
+
+    Ok(BenchResult {
+        size,
+        sent,
+        received,
+        elapsed_secs: elapsed,
+        packets_per_sec: received as f64 / elapsed,
+        gbit_per_sec: (received * size * 8) as f64 / elapsed / 1e9,
+        latency_us: percentiles(latencies),
+    })
+}
+
+/// fills `packet` with an ipv4 header from the sender to the receiver, followed by the sequence
+/// number and the send timestamp relative to `start`.
+fn format_probe(packet: &mut [u8], seq: u64, start: Instant) {
+    let len = packet.len() as u16;
+
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&len.to_be_bytes());
+    packet[8] = 64;
+    packet[9] = 17;
+    packet[12..16].copy_from_slice(&SENDER_ADDR.octets());
+    packet[16..20].copy_from_slice(&RECEIVER_ADDR.octets());
+    packet[20..28].copy_from_slice(&seq.to_le_bytes());
+
+    let sent_at = start.elapsed().as_nanos() as u64;
+    packet[28..36].copy_from_slice(&sent_at.to_le_bytes());
+}
+
+fn percentiles(mut latencies: Vec<u64>) -> Latency {
+    if latencies.is_empty() {
+        return Latency::default();
+    }
+    latencies.sort_unstable();
+
+    let at = |p: f64| {
+        let idx = ((latencies.len() - 1) as f64 * p).round() as usize;
+        latencies[idx] as f64 / 1e3
+    };
+
+    Latency {
+        p50: at(0.5),
+        p90: at(0.9),
+        p99: at(0.99),
+        max: at(1.0),
+    }
+}
+
+impl std::fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:>6} {:>9} {:>9} {:>12} {:>9} {:>9} {:>9} {:>9} {:>9}",
+            "size", "sent", "received", "pps", "Gbit/s", "p50 us", "p90 us", "p99 us", "max us"
+        )?;
+        for r in &self.results {
+            writeln!(
+                f,
+                "{:>6} {:>9} {:>9} {:>12.0} {:>9.3} {:>9.1} {:>9.1} {:>9.1} {:>9.1}",
+                r.size,
+                r.sent,
+                r.received,
+                r.packets_per_sec,
+                r.gbit_per_sec,
+                r.latency_us.p50,
+                r.latency_us.p90,
+                r.latency_us.p99,
+                r.latency_us.max
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let latency = percentiles((1..=100).map(|us| us * 1000).collect());
+        assert_eq!(latency.p50, 51.0);
+        assert_eq!(latency.p90, 90.0);
+        assert_eq!(latency.p99, 99.0);
+        assert_eq!(latency.max, 100.0);
+
+        let latency = percentiles(vec![]);
+        assert_eq!(latency.max, 0.0);
+    }
+
+    #[test]
+    fn test_format_probe() {
+        let mut packet = [0u8; 64];
+        format_probe(&mut packet, 7, Instant::now());
+
+        let iph = etherparse::Ipv4HeaderSlice::from_slice(&packet).unwrap();
+        assert_eq!(iph.source_addr(), SENDER_ADDR);
+        assert_eq!(iph.destination_addr(), RECEIVER_ADDR);
+        assert_eq!(u64::from_le_bytes(packet[20..28].try_into().unwrap()), 7);
+    }
+}
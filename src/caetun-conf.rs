@@ -1,29 +1,521 @@
-use caetun::conf::Conf;
-use clap::Parser;
-use std::path::PathBuf;
+use anyhow::Context;
+use caetun::conf::{Conf, ConfError, Endpoint, PeerConf};
+use caetun::edit;
+use caetun::format::{self, Format};
+use caetun::generate::{self, Topology};
+use caetun::graph::Graph;
+use caetun::ini;
+use caetun::qr;
+use caetun::validate::{self, Finding, NetworkFinding, Severity};
+use caetun::wg;
+use clap::{Args, Parser, Subcommand};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
 #[derive(Parser)]
-#[clap(author, version, about, long_about = None)]
-struct Args {
+#[clap(author, version, about, long_about = None, subcommand_negates_reqs = true)]
+struct Cli {
+    #[arg(long, short, required = true)]
+    conf: Option<PathBuf>,
+
     #[arg(long, short)]
+    pretty: bool,
+
+    /// Format of --conf, by its extension when it's not given
+    #[arg(long)]
+    from: Option<Format>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check a config for mistakes beyond syntax, exits with 1 when it has errors
+    Validate(ValidateArgs),
+    /// Check the configs of one network against each other, exits with 1 when they have errors
+    CheckNetwork(CheckNetworkArgs),
+    /// Write the configs of a hub and its clients from a JSON topology, keeping the addresses of
+    /// configs written before
+    Generate(GenerateArgs),
+    /// Add a peer to a config, keeping its comments and layout
+    AddPeer(AddPeerArgs),
+    /// Remove a peer from a config, with the comments right above it
+    RemovePeer(RemovePeerArgs),
+    /// Set or remove a key of the interface or of a peer of a config, keeping its comments and
+    /// layout
+    Set(SetArgs),
+    /// Convert a config between INI, JSON, TOML and YAML
+    Convert(ConvertArgs),
+    /// Import a wg-quick config, reporting the keys caetun has no counterpart for
+    ImportWg(ImportWgArgs),
+    /// Export a config as a wg-quick config, reporting the settings WireGuard has no counterpart
+    /// for
+    ExportWg(ExportWgArgs),
+    /// Show a config as a QR code in the terminal, or write it as a PNG or SVG image
+    Qr(QrArgs),
+    /// Print the interfaces of configs and their peers as a Graphviz DOT graph, with the findings of
+    /// check-network highlighted
+    Graph(GraphArgs),
+}
+
+#[derive(Args)]
+struct ValidateArgs {
+    conf: PathBuf,
+    /// Print the findings as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct CheckNetworkArgs {
+    #[arg(required = true)]
+    confs: Vec<PathBuf>,
+    /// Print the findings as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct GenerateArgs {
+    topology: PathBuf,
+    /// Directory the configs are written to, as <name>.conf
+    #[arg(long, short, default_value = ".")]
+    out: PathBuf,
+}
+
+#[derive(Args)]
+struct AddPeerArgs {
+    conf: PathBuf,
+    name: String,
+    #[arg(long)]
+    endpoint: Option<String>,
+    /// Comma separated networks routed to the peer
+    #[arg(long, value_delimiter = ',')]
+    allowed_ips: Vec<String>,
+    #[arg(long)]
+    batch: bool,
+}
+
+#[derive(Args)]
+struct RemovePeerArgs {
+    conf: PathBuf,
+    name: String,
+}
+
+#[derive(Args)]
+struct SetArgs {
+    conf: PathBuf,
+    /// Set the key of this peer instead of the interface
+    #[arg(long)]
+    peer: Option<String>,
+    key: String,
+    #[arg(required_unless_present = "unset")]
+    value: Option<String>,
+    /// Remove the key instead
+    #[arg(long, conflicts_with = "value")]
+    unset: bool,
+}
+
+#[derive(Args)]
+struct ConvertArgs {
+    input: PathBuf,
+    /// Written to standard output when it's not given
+    output: Option<PathBuf>,
+    /// Format of the input, by its extension when it's not given
+    #[arg(long)]
+    from: Option<Format>,
+    /// Format of the output, by its extension when it's not given, or else INI
+    #[arg(long)]
+    to: Option<Format>,
+}
+
+#[derive(Args)]
+struct ImportWgArgs {
+    wg: PathBuf,
+    /// Written to standard output when it's not given
+    output: Option<PathBuf>,
+    /// Name of the interface, the `# Name = ` comment of the config or its file name when it's not
+    /// given
+    #[arg(long)]
+    name: Option<String>,
+    /// Format of the output, by its extension when it's not given, or else INI
+    #[arg(long)]
+    to: Option<Format>,
+}
+
+#[derive(Args)]
+struct ExportWgArgs {
     conf: PathBuf,
+    /// Written to standard output when it's not given
+    output: Option<PathBuf>,
+    /// Format of the config, by its extension when it's not given
+    #[arg(long)]
+    from: Option<Format>,
+}
 
+#[derive(Args)]
+struct QrArgs {
+    conf: PathBuf,
+    /// A .png or .svg image to write, readable by whoever can read the config
     #[arg(long, short)]
-    pretty: bool,
+    output: Option<PathBuf>,
+    /// Format of the config, by its extension when it's not given
+    #[arg(long)]
+    from: Option<Format>,
+}
+
+#[derive(Args)]
+struct GraphArgs {
+    #[arg(required = true)]
+    confs: Vec<PathBuf>,
+    /// Print a Mermaid flowchart instead
+    #[arg(long)]
+    mermaid: bool,
+}
+
+/// Report is what `validate --json` prints.
+#[derive(Serialize)]
+struct Report {
+    conf: PathBuf,
+    valid: bool,
+    findings: Vec<Finding>,
+}
+
+/// NetworkReport is what `check-network --json` prints.
+#[derive(Serialize)]
+struct NetworkReport {
+    valid: bool,
+    findings: Vec<NetworkFinding>,
+}
+
+/// load the config at `path`, one that doesn't parse is a finding like the others.
+fn load(path: &Path) -> anyhow::Result<Result<Conf, Finding>> {
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read config {}", path.display()))?;
+    Ok(Format::from_path(path)
+        .parse_file(&source, path)
+        .map_err(|err| Finding {
+            severity: Severity::Error,
+            check: "syntax",
+            peer: None,
+            message: format!("{err:#}"),
+        }))
+}
+
+fn exit_code(valid: bool) -> ExitCode {
+    match valid {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    }
+}
+
+fn run_validate(args: ValidateArgs) -> anyhow::Result<ExitCode> {
+    let findings = match load(&args.conf)? {
+        Ok(conf) => validate::validate(&conf),
+        Err(finding) => vec![finding],
+    };
+    let valid = findings.iter().all(|f| f.severity != Severity::Error);
+
+    if args.json {
+        let report = Report {
+            conf: args.conf,
+            valid,
+            findings,
+        };
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for finding in &findings {
+            println!("{}: {finding}", args.conf.display());
+        }
+    }
+
+    Ok(exit_code(valid))
+}
+
+fn run_check_network(args: CheckNetworkArgs) -> anyhow::Result<ExitCode> {
+    let mut confs = Vec::new();
+    let mut findings = Vec::new();
+    for path in &args.confs {
+        let name = path.display().to_string();
+        match load(path)? {
+            Ok(conf) => confs.push((name, conf)),
+            Err(finding) => findings.push(NetworkFinding {
+                conf: name,
+                finding,
+            }),
+        }
+    }
+    findings.extend(validate::validate_network(&confs));
+    let valid = findings
+        .iter()
+        .all(|f| f.finding.severity != Severity::Error);
+
+    if args.json {
+        let report = NetworkReport { valid, findings };
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for finding in &findings {
+            println!("{finding}");
+        }
+    }
+
+    Ok(exit_code(valid))
+}
+
+fn run_graph(args: GraphArgs) -> anyhow::Result<()> {
+    let mut confs = Vec::new();
+    for path in &args.confs {
+        let name = path.display().to_string();
+        match load(path)? {
+            Ok(conf) => confs.push((name, conf)),
+            Err(finding) => eprintln!("{name}: {finding}, left out"),
+        }
+    }
+    let findings = validate::validate_network(&confs);
+    let graph = Graph::new(&confs, &findings);
+    match args.mermaid {
+        true => print!("{}", graph.mermaid()),
+        false => print!("{}", graph.dot()),
+    }
+    Ok(())
+}
+
+fn run_generate(args: GenerateArgs) -> anyhow::Result<ExitCode> {
+    let topology = std::fs::read_to_string(&args.topology)
+        .with_context(|| format!("failed to read topology {}", args.topology.display()))?;
+    let topology: Topology = serde_json::from_str(&topology)
+        .with_context(|| format!("invalid topology {}", args.topology.display()))?;
+
+    let path = |name: &str| args.out.join(format!("{name}.conf"));
+    let names = std::iter::once(&topology.hub.name).chain(topology.clients.iter().map(|c| &c.name));
+    let mut existing = HashMap::new();
+    for name in names {
+        let path = path(name);
+        if path.exists() {
+            let conf = format::load_plain(&path, None)
+                .map_err(|err| anyhow::anyhow!("{}: {err:#}", path.display()))?;
+            existing.insert(name.clone(), conf);
+        }
+    }
+
+    let confs = generate::generate(&topology, &existing)?;
+    let named: Vec<(String, Conf)> = confs
+        .into_iter()
+        .map(|conf| (path(&conf.interface.name).display().to_string(), conf))
+        .collect();
+    let findings = validate::validate_network(&named);
+    for finding in &findings {
+        println!("{finding}");
+    }
+    if findings
+        .iter()
+        .any(|f| f.finding.severity == Severity::Error)
+    {
+        println!("nothing was written");
+        return Ok(ExitCode::FAILURE);
+    }
+
+    std::fs::create_dir_all(&args.out)?;
+    for (path, conf) in &named {
+        let ini = conf.to_ini();
+        if std::fs::read_to_string(path).is_ok_and(|old| old == ini) {
+            println!("{path} is up to date");
+            continue;
+        }
+        std::fs::write(path, ini).with_context(|| format!("failed to write {path}"))?;
+        println!("wrote {path}");
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// change the config at `path` with `edit`, replacing the file only once the result is known to be
+/// valid.
+fn edit(path: &Path, edit: impl FnOnce(&str) -> anyhow::Result<String>) -> anyhow::Result<()> {
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read config {}", path.display()))?;
+    // the other formats are edited as INI, they have nothing INI would lose
+    let edited = match Format::from_path(path) {
+        Format::Ini => edit(&source)?,
+        format => {
+            // without resolving it, a `${` of the original is a value and not a substitution
+            let edited = edit(&format.parse(&source)?.to_ini())?;
+            let sections = ini::parse(&edited).map_err(ConfError::from)?;
+            format.write(&Conf::from_sections(sections)?)?
+        }
+    };
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, edited)?;
+    std::fs::set_permissions(&tmp, std::fs::metadata(path)?.permissions())?;
+    std::fs::rename(&tmp, path).with_context(|| format!("failed to write {}", path.display()))
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    let conf = std::fs::read_to_string(&args.conf)?;
-    let conf = Conf::parse_from(&conf)?;
+fn run_add_peer(args: AddPeerArgs) -> anyhow::Result<()> {
+    let mut peer = PeerConf::new(&args.name);
+    if let Some(endpoint) = &args.endpoint {
+        peer.endpoint = Some(endpoint.parse::<Endpoint>()?);
+    }
+    for allowed_ip in &args.allowed_ips {
+        let ipn = ip_network::IpNetwork::from_str_truncate(allowed_ip.trim())
+            .with_context(|| format!("invalid allowed ip {allowed_ip}"))?;
+        peer.allowed_ips
+            .push((ipn.network_address(), ipn.netmask()));
+    }
+    peer.batch = args.batch;
+    edit(&args.conf, |source| {
+        edit::add_peer(source, &peer, Some(&args.conf))
+    })
+}
+
+/// write `conf` to `output` in `to` or else the format of its extension, to standard output in `to`
+/// or else INI without one.
+fn write_conf(conf: &Conf, output: Option<&Path>, to: Option<Format>) -> anyhow::Result<()> {
+    let to = to.unwrap_or_else(|| output.map_or(Format::Ini, Format::from_path));
+    write_output(output, &to.write(conf)?)
+}
+
+fn write_output(output: Option<&Path>, content: &str) -> anyhow::Result<()> {
+    match output {
+        Some(output) => std::fs::write(output, content)
+            .with_context(|| format!("failed to write {}", output.display())),
+        None => {
+            print!("{content}");
+            Ok(())
+        }
+    }
+}
+
+fn run_convert(args: ConvertArgs) -> anyhow::Result<()> {
+    let conf = format::load(&args.input, args.from)?;
+    write_conf(&conf, args.output.as_deref(), args.to)
+}
+
+fn run_import_wg(args: ImportWgArgs) -> anyhow::Result<()> {
+    let source = std::fs::read_to_string(&args.wg)
+        .with_context(|| format!("failed to read config {}", args.wg.display()))?;
+    let stem = args.wg.file_stem().unwrap_or_default().to_string_lossy();
+    let (mut conf, unmapped) = wg::import(&source, &stem)
+        .with_context(|| format!("invalid config {}", args.wg.display()))?;
+    if let Some(name) = args.name {
+        conf.interface.name = name;
+    }
+    for unmapped in &unmapped {
+        eprintln!("not imported: {unmapped}");
+    }
+    write_conf(&conf, args.output.as_deref(), args.to)
+}
+
+fn run_export_wg(args: ExportWgArgs) -> anyhow::Result<()> {
+    let conf = format::load(&args.conf, args.from)?;
+    let (exported, unmapped) = wg::export(&conf);
+    for unmapped in &unmapped {
+        eprintln!("not exported: {unmapped}");
+    }
+    write_output(args.output.as_deref(), &exported)
+}
 
+fn run_qr(args: QrArgs) -> anyhow::Result<()> {
+    let conf = format::load(&args.conf, args.from)?;
+    let code = qr::encode(&conf).with_context(|| format!("{}", args.conf.display()))?;
+    let Some(output) = &args.output else {
+        print!("{}", qr::ansi(&code));
+        return Ok(());
+    };
+
+    let image = match output.extension().and_then(|ext| ext.to_str()) {
+        Some("png") => qr::png(&code)?,
+        Some("svg") => qr::svg(&code).into_bytes(),
+        _ => anyhow::bail!("{} is neither a .png nor a .svg", output.display()),
+    };
+    // the image holds the whole config, it's kept as private as the config is
+    let permissions = std::fs::metadata(&args.conf)?.permissions();
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(permissions.mode())
+        .open(output)
+        .with_context(|| format!("failed to write {}", output.display()))?;
+    file.set_permissions(permissions)?;
+    file.write_all(&image)?;
+    Ok(())
+}
+
+fn main() -> anyhow::Result<ExitCode> {
+    let args = Cli::parse();
+
+    match args.command {
+        Some(Command::Validate(validate_args)) => return run_validate(validate_args),
+        Some(Command::CheckNetwork(check_args)) => return run_check_network(check_args),
+        Some(Command::Generate(generate_args)) => return run_generate(generate_args),
+        Some(Command::AddPeer(add_args)) => {
+            run_add_peer(add_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::RemovePeer(remove_args)) => {
+            edit(&remove_args.conf, |source| {
+                edit::remove_peer(source, &remove_args.name, Some(&remove_args.conf))
+            })?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Convert(convert_args)) => {
+            run_convert(convert_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::ImportWg(import_args)) => {
+            run_import_wg(import_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::ExportWg(export_args)) => {
+            run_export_wg(export_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Qr(qr_args)) => {
+            run_qr(qr_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Graph(graph_args)) => {
+            run_graph(graph_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Set(set_args)) => {
+            edit(&set_args.conf, |source| {
+                let peer = set_args.peer.as_deref();
+                edit::set(
+                    source,
+                    peer,
+                    &set_args.key,
+                    set_args.value.as_deref(),
+                    Some(&set_args.conf),
+                )
+            })?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        None => (),
+    }
+
+    let Some(conf) = args.conf else {
+        anyhow::bail!("--conf is required")
+    };
+    let conf = format::load(&conf, args.from)?;
+
+    // the JSON `convert` writes, so that it can be read back
+    let value = format::to_value(&conf);
     let json = if args.pretty {
-        serde_json::to_string_pretty(&conf)?
+        serde_json::to_string_pretty(&value)?
     } else {
-        serde_json::to_string(&conf)?
+        serde_json::to_string(&value)?
     };
 
     println!("{json}");
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
@@ -1,15 +1,50 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::fmt::{self, Write as _};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use ip_network::IpNetworkParseError;
-use serde::{Deserialize, Serialize};
+
+use crate::dns::Dns;
+use crate::hooks::Hooks;
+use crate::include;
+use crate::ini;
+use crate::mtu::{MAX_MTU, MIN_MTU};
+use crate::resolve::Host;
+use serde::{Serialize, Serializer};
 use thiserror::Error;
 
+/// ErrorKind is what is wrong in a config, `ConfError` adds where it is.
 #[derive(Error, Debug)]
-#[allow(dead_code)]
-pub enum ConfError {
-    #[error("invalid ini: {0}")]
-    Ini(#[from] serde_ini::de::Error),
+pub enum ErrorKind {
+    #[error("{0}")]
+    Syntax(String),
+
+    #[error("unknown section [{name}]{}", did_you_mean(.suggestion))]
+    UnknownSection {
+        name: String,
+        suggestion: Option<&'static str>,
+    },
+
+    #[error("unknown key {key}{}", did_you_mean(.suggestion))]
+    UnknownKey {
+        key: String,
+        suggestion: Option<&'static str>,
+    },
+
+    #[error("duplicate key {key}, it's already set on line {first_line}")]
+    DuplicateKey { key: String, first_line: usize },
+
+    #[error("missing key {0}")]
+    MissingKey(&'static str),
+
+    #[error("invalid value `{value}`, expected {expected}")]
+    Invalid {
+        value: String,
+        expected: &'static str,
+    },
 
     #[error("invalid cidr address: {0}")]
     IpFormat(String),
@@ -17,14 +52,109 @@ pub enum ConfError {
     #[error("invalid cidr notation: {0}")]
     IpNetworkParseError(#[from] IpNetworkParseError),
 
-    #[error("multiple interface definition")]
-    ExtraInterface,
+    #[error("invalid listen address: {0}")]
+    ListenAddress(String),
+
+    #[error("invalid interface name to bind to: {0}")]
+    BindInterface(String),
+
+    #[error("invalid mtu {0}, must be in the range {MIN_MTU}-{MAX_MTU}")]
+    Mtu(u32),
+
+    #[error("invalid table {0}, expected off, auto, main or a routing table number")]
+    Table(String),
+
+    #[error("invalid fwmark {0}, expected off or a number")]
+    FwMark(String),
+
+    #[error("invalid endpoint: {0}")]
+    Endpoint(String),
+
+    #[error("invalid resolve interval {0}, expected off or a number of seconds")]
+    ResolveInterval(String),
+
+    #[error("invalid dns server or search domain: {0}")]
+    Dns(String),
+
+    #[error("multiple interface definition, the first is on line {0}")]
+    ExtraInterface(usize),
 
     #[error("missing interface definition")]
     MissingInterface,
+
+    /// a file to include or substitute that can't be read, its path and why.
+    #[error("cannot read {0}")]
+    Read(String),
+
+    #[error("include cycle {0}")]
+    IncludeCycle(String),
+
+    /// a `${...}` that can't be substituted and why.
+    #[error("cannot substitute {0}")]
+    Substitution(String),
+
+    /// an `Include` or a `${...}` in a config that is about to be written back.
+    #[error("Include and ${{...}} would be lost when the config is written back")]
+    Unresolved,
+}
+
+fn did_you_mean(suggestion: &Option<&'static str>) -> String {
+    suggestion.map_or_else(String::new, |s| format!(", did you mean {s}?"))
+}
+
+/// ConfError is an error in a config and where it is: the included file, the section, the key and
+/// the 1-based line and column. Errors about the config as a whole have none of them.
+#[derive(Debug)]
+pub struct ConfError {
+    pub kind: ErrorKind,
+    /// the included file the error is in, none for the config itself.
+    pub file: Option<Arc<PathBuf>>,
+    pub section: Option<String>,
+    pub key: Option<String>,
+    pub position: Option<(u32, u32)>,
 }
 
-#[derive(Debug, Serialize, PartialEq)]
+impl fmt::Display for ConfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file.display())?;
+        }
+        if let Some((line, column)) = self.position {
+            write!(f, "line {line}, column {column}: ")?;
+        }
+        match (&self.section, &self.key) {
+            (Some(section), Some(key)) => write!(f, "[{section}] {key}: ")?,
+            (Some(section), None) => write!(f, "[{section}]: ")?,
+            _ => (),
+        }
+        self.kind.fmt(f)
+    }
+}
+
+impl std::error::Error for ConfError {}
+
+impl From<ErrorKind> for ConfError {
+    fn from(kind: ErrorKind) -> Self {
+        Self {
+            kind,
+            file: None,
+            section: None,
+            key: None,
+            position: None,
+        }
+    }
+}
+
+impl From<ini::Error> for ConfError {
+    fn from(err: ini::Error) -> Self {
+        Self {
+            position: position(err.line, err.column),
+            ..ErrorKind::Syntax(err.message).into()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
 pub struct Conf {
     pub interface: InterfaceConf,
     pub peers: Vec<PeerConf>,
@@ -33,114 +163,584 @@ pub struct Conf {
 #[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct InterfaceConf {
     pub name: String,
-    pub address: (Ipv4Addr, u8),
+    pub address: Vec<(IpAddr, u8)>,
     pub listen_port: u16,
+    /// the address the udp sockets are bound to, all addresses when it's not set.
+    pub listen_address: Option<IpAddr>,
+    /// the network device the udp sockets are bound to.
+    pub bind_interface: Option<String>,
+    /// the tun mtu, derived from the route to the peers when it's not set.
+    pub mtu: Option<u32>,
+    /// the routing table the allowed prefixes of the peers are routed in, `None` with `Table=off`.
+    pub table: Option<u32>,
+    /// the firewall mark of the packets caetun sends, which keeps them out of its own routes.
+    pub fwmark: Option<u32>,
+    /// shell commands run by `caetun up` and `caetun down`, `%i` is replaced by the interface name.
+    pub hooks: Hooks,
+    /// name servers and search domains applied by `caetun up`.
+    pub dns: Dns,
+    /// how often host name endpoints are resolved again, `None` with `ResolveInterval=off`.
+    pub resolve_interval: Option<Duration>,
+}
+
+impl InterfaceConf {
+    /// the `[Interface]` keys of the interface and their values, in the order `Conf::to_ini`
+    /// writes them. Keys at their default are left out.
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        let mut entries = vec![
+            ("Name", self.name.clone()),
+            ("Address", Cidrs(&self.address).to_string()),
+        ];
+        let mut key = |key, value: &dyn fmt::Display| entries.push((key, value.to_string()));
+
+        if self.listen_port != Conf::DEFAULT_LISTEN_PORT {
+            key("ListenPort", &self.listen_port);
+        }
+        if let Some(address) = &self.listen_address {
+            key("ListenAddress", address);
+        }
+        if let Some(iface) = &self.bind_interface {
+            key("BindInterface", iface);
+        }
+        if let Some(mtu) = &self.mtu {
+            key("MTU", mtu);
+        }
+        match self.table {
+            Some(Conf::DEFAULT_TABLE) => (),
+            Some(table) => key("Table", &table),
+            None => key("Table", &"off"),
+        }
+        if let Some(fwmark) = self.fwmark {
+            key("FwMark", &format_args!("{fwmark:#x}"));
+        }
+        for (name, hook) in [
+            ("PreUp", &self.hooks.pre_up),
+            ("PostUp", &self.hooks.post_up),
+            ("PreDown", &self.hooks.pre_down),
+            ("PostDown", &self.hooks.post_down),
+        ] {
+            if let Some(hook) = hook {
+                key(name, hook);
+            }
+        }
+        if !self.dns.is_empty() {
+            let servers = self.dns.servers.iter().map(ToString::to_string);
+            let dns: Vec<String> = servers.chain(self.dns.search.iter().cloned()).collect();
+            key("DNS", &dns.join(", "));
+        }
+        match self.resolve_interval {
+            Some(Conf::DEFAULT_RESOLVE_INTERVAL) => (),
+            Some(interval) => key("ResolveInterval", &interval.as_secs()),
+            None => key("ResolveInterval", &"off"),
+        }
+        entries
+    }
+
+    /// an interface named `name` with every other setting at the default of its key.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            address: Vec::new(),
+            listen_port: Conf::DEFAULT_LISTEN_PORT,
+            listen_address: None,
+            bind_interface: None,
+            mtu: None,
+            table: Some(Conf::DEFAULT_TABLE),
+            fwmark: None,
+            hooks: Hooks::default(),
+            dns: Dns::default(),
+            resolve_interval: Some(Conf::DEFAULT_RESOLVE_INTERVAL),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct PeerConf {
     pub name: String,
-    pub endpoint: Option<SocketAddrV4>,
-    pub allowed_ips: Vec<(Ipv4Addr, u8)>,
+    pub endpoint: Option<Endpoint>,
+    pub allowed_ips: Vec<(IpAddr, u8)>,
+    /// offer to aggregate small packets into one datagram, only used if the peer agrees.
+    pub batch: bool,
+}
+
+impl PeerConf {
+    /// the `[Peer]` keys of the peer and their values, keys at their default are left out.
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        let mut entries = vec![("Name", self.name.clone())];
+        if let Some(endpoint) = &self.endpoint {
+            entries.push(("Endpoint", endpoint.to_string()));
+        }
+        if !self.allowed_ips.is_empty() {
+            entries.push(("AllowedIPs", Cidrs(&self.allowed_ips).to_string()));
+        }
+        if self.batch {
+            entries.push(("Batch", "true".to_string()));
+        }
+        entries
+    }
+
+    /// a peer named `name` without an endpoint or allowed ips.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            endpoint: None,
+            allowed_ips: Vec::new(),
+            batch: false,
+        }
+    }
+}
+
+/// Endpoint is where a peer is reached: an address, or a host name that is resolved when the device
+/// starts and again every `ResolveInterval`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Addr(SocketAddr),
+    Host(Host),
+}
+
+impl Endpoint {
+    /// the address of the endpoint, `None` for a host name.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        match self {
+            Endpoint::Addr(addr) => Some(*addr),
+            Endpoint::Host(_) => None,
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = ErrorKind;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(addr) = s.parse() {
+            return Ok(Endpoint::Addr(addr));
+        }
+        s.parse().map(Endpoint::Host).map_err(ErrorKind::Endpoint)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Addr(addr) => addr.fmt(f),
+            Endpoint::Host(host) => host.fmt(f),
+        }
+    }
+}
+
+impl Serialize for Endpoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
 }
 
 impl Conf {
     pub const DEFAULT_LISTEN_PORT: u16 = 19988;
 
+    /// how often host name endpoints are resolved again without `ResolveInterval=`.
+    pub const DEFAULT_RESOLVE_INTERVAL: Duration = Duration::from_secs(300);
+
+    /// the main routing table.
+    pub const DEFAULT_TABLE: u32 = 254;
+
+    /// the sections a config has.
+    const SECTIONS: &'static [&'static str] = &["Interface", "Peer"];
+
+    const INTERFACE_KEYS: &'static [&'static str] = &[
+        "Name",
+        "Address",
+        "ListenPort",
+        "ListenAddress",
+        "BindInterface",
+        "MTU",
+        "Table",
+        "FwMark",
+        "PreUp",
+        "PostUp",
+        "PreDown",
+        "PostDown",
+        "DNS",
+        "ResolveInterval",
+    ];
+
+    const PEER_KEYS: &'static [&'static str] = &["Name", "Endpoint", "AllowedIPs", "Batch"];
+
+    /// parse a config, strictly: unknown sections and keys, duplicate keys and invalid values are
+    /// all errors that point at where they are. `Include` entries are read with the sections of
+    /// the files they name, relative to the working directory, and `${...}` in values is
+    /// substituted, see `include::resolve`.
     pub fn parse_from(source: &str) -> Result<Self, ConfError> {
-        let sections: Vec<Section> = serde_ini::from_str(source)?;
+        Self::from_sections(include::resolve(ini::parse(source)?, None)?)
+    }
+
+    /// parse `source`, the config in the file at `path`, like `parse_from` with its includes and
+    /// files to substitute relative to the directory of the file.
+    pub fn parse_file(source: &str, path: &Path) -> Result<Self, ConfError> {
+        Self::from_sections(include::resolve(ini::parse(source)?, Some(path))?)
+    }
 
-        let mut interface = None;
+    /// the config of `sections`, checked like `parse_from` checks a file. Sections that weren't
+    /// read from an INI file have 0 for their lines, errors in them have no position.
+    pub fn from_sections(sections: Vec<ini::Section>) -> Result<Self, ConfError> {
+        let mut interface: Option<(InterfaceConf, usize)> = None;
         let mut peers = vec![];
 
-        for section in sections.into_iter() {
-            match section {
-                Section::Peer {
-                    Name,
-                    Endpoint,
-                    AllowedIPs,
-                } => {
-                    let allowed_ips: Result<Vec<_>, _> = AllowedIPs
-                        .as_deref()
-                        .unwrap_or("")
-                        .split(',')
-                        .filter_map(|allowed_ip| Some(allowed_ip.trim()).filter(|s| !s.is_empty()))
-                        .map(|allowed_ip| -> Result<_, IpNetworkParseError> {
-                            let ipn = ip_network::Ipv4Network::from_str_truncate(allowed_ip)?;
-                            Ok((ipn.network_address(), ipn.netmask()))
-                        })
-                        .collect();
-                    let endpoint = Endpoint.and_then(|ep| SocketAddrV4::from_str(&ep).ok());
-                    let peer = PeerConf {
-                        name: Name,
-                        allowed_ips: allowed_ips?,
-                        endpoint,
+        for section in sections {
+            match section.name.as_str() {
+                "Interface" => {
+                    if let Some((_, first_line)) = interface {
+                        return Err(Fields::section_error(
+                            &section,
+                            ErrorKind::ExtraInterface(first_line),
+                        ));
+                    }
+                    let fields = Fields::new(&section, Self::INTERFACE_KEYS)?;
+                    interface = Some((Self::parse_interface(&fields)?, section.line));
+                }
+                "Peer" => {
+                    let fields = Fields::new(&section, Self::PEER_KEYS)?;
+                    peers.push(Self::parse_peer(&fields)?);
+                }
+                name => {
+                    let kind = ErrorKind::UnknownSection {
+                        name: name.to_string(),
+                        suggestion: suggest(name, Self::SECTIONS),
                     };
-                    peers.push(peer);
+                    return Err(ConfError {
+                        file: section.file.clone(),
+                        position: position(section.line, section.column),
+                        ..kind.into()
+                    });
                 }
-                Section::Interface {
-                    Name,
-                    Address,
-                    ListenPort,
-                } => {
-                    if interface.is_none() {
-                        let address = parse_cidr(Address.trim())?;
-                        interface = Some(InterfaceConf {
-                            name: Name,
-                            address,
-                            listen_port: ListenPort.unwrap_or(Self::DEFAULT_LISTEN_PORT),
-                        });
-                    } else {
-                        return Err(ConfError::ExtraInterface);
-                    }
+            }
+        }
+
+        match interface {
+            Some((interface, _)) => Ok(Conf { interface, peers }),
+            None => Err(ErrorKind::MissingInterface.into()),
+        }
+    }
+
+    /// the config in the INI form `parse_from` reads, keys at their default are left out.
+    pub fn to_ini(&self) -> String {
+        let mut ini = String::new();
+        let sections = std::iter::once(("Interface", self.interface.entries()))
+            .chain(self.peers.iter().map(|peer| ("Peer", peer.entries())));
+        for (i, (name, entries)) in sections.enumerate() {
+            if i > 0 {
+                ini.push('\n');
+            }
+            let _ = writeln!(ini, "[{name}]");
+            for (key, value) in entries {
+                let _ = writeln!(ini, "{key}={value}");
+            }
+        }
+        ini
+    }
+
+    fn parse_interface(fields: &Fields) -> Result<InterfaceConf, ConfError> {
+        let mtu = fields.parse("MTU", |value| match value.parse() {
+            Ok(mtu) if (MIN_MTU..=MAX_MTU).contains(&mtu) => Ok(mtu),
+            Ok(mtu) => Err(ErrorKind::Mtu(mtu)),
+            Err(_) => Err(invalid(value, "a number")),
+        })?;
+
+        let mut dns = Dns::default();
+        for item in fields.parse_list("DNS", |item| Dns::parse(item).map_err(ErrorKind::Dns))? {
+            dns.servers.extend(item.servers);
+            dns.search.extend(item.search);
+        }
+
+        Ok(InterfaceConf {
+            name: fields.required("Name")?.value.clone(),
+            address: {
+                fields.required("Address")?;
+                fields.parse_list("Address", parse_cidr)?
+            },
+            listen_port: fields
+                .parse("ListenPort", |value| {
+                    value.parse().map_err(|_| invalid(value, "a port number"))
+                })?
+                .unwrap_or(Self::DEFAULT_LISTEN_PORT),
+            listen_address: fields.parse("ListenAddress", |value| {
+                value
+                    .parse()
+                    .map_err(|_| ErrorKind::ListenAddress(value.to_string()))
+            })?,
+            bind_interface: fields.parse("BindInterface", parse_iface_name)?,
+            mtu,
+            table: fields
+                .parse("Table", parse_table)?
+                .unwrap_or(Some(Self::DEFAULT_TABLE)),
+            fwmark: fields.parse("FwMark", parse_fwmark)?.flatten(),
+            hooks: Hooks {
+                pre_up: fields.string("PreUp"),
+                post_up: fields.string("PostUp"),
+                pre_down: fields.string("PreDown"),
+                post_down: fields.string("PostDown"),
+            },
+            dns,
+            resolve_interval: fields
+                .parse("ResolveInterval", parse_resolve_interval)?
+                .unwrap_or(Some(Self::DEFAULT_RESOLVE_INTERVAL)),
+        })
+    }
+
+    fn parse_peer(fields: &Fields) -> Result<PeerConf, ConfError> {
+        Ok(PeerConf {
+            name: fields.required("Name")?.value.clone(),
+            endpoint: fields.parse("Endpoint", Endpoint::from_str)?,
+            allowed_ips: fields.parse_list("AllowedIPs", |allowed_ip| {
+                let ipn = ip_network::IpNetwork::from_str_truncate(allowed_ip)?;
+                Ok((ipn.network_address(), ipn.netmask()))
+            })?,
+            batch: fields
+                .parse("Batch", |value| {
+                    value.parse().map_err(|_| invalid(value, "true or false"))
+                })?
+                .unwrap_or(false),
+        })
+    }
+}
+
+/// Cidrs formats a list of addresses the way `Address=` and `AllowedIPs=` take them.
+struct Cidrs<'a>(&'a [(IpAddr, u8)]);
+
+impl fmt::Display for Cidrs<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (ip, prefix)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{ip}/{prefix}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Fields are the entries of one section, checked against the keys the section takes. Errors in
+/// their values are located at the entry.
+struct Fields<'a> {
+    section: &'a ini::Section,
+}
+
+impl<'a> Fields<'a> {
+    fn new(section: &'a ini::Section, keys: &[&'static str]) -> Result<Self, ConfError> {
+        for (i, entry) in section.entries.iter().enumerate() {
+            let kind = if !keys.contains(&entry.key.as_str()) {
+                ErrorKind::UnknownKey {
+                    key: entry.key.clone(),
+                    suggestion: suggest(&entry.key, keys),
+                }
+            } else if let Some(first) = section.entries[..i].iter().find(|e| e.key == entry.key) {
+                ErrorKind::DuplicateKey {
+                    key: entry.key.clone(),
+                    first_line: first.line,
                 }
+            } else {
+                continue;
+            };
+            return Err(ConfError {
+                kind,
+                file: section.file.clone(),
+                section: Some(section.name.clone()),
+                key: None,
+                position: position(entry.line, entry.key_column),
+            });
+        }
+        Ok(Self { section })
+    }
+
+    fn section_error(section: &ini::Section, kind: ErrorKind) -> ConfError {
+        ConfError {
+            kind,
+            file: section.file.clone(),
+            section: Some(section.name.clone()),
+            key: None,
+            position: position(section.line, section.column),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&'a ini::Entry> {
+        self.section.entries.iter().find(|entry| entry.key == key)
+    }
+
+    fn error(&self, entry: &ini::Entry, column: usize, kind: ErrorKind) -> ConfError {
+        ConfError {
+            kind,
+            file: self.section.file.clone(),
+            section: Some(self.section.name.clone()),
+            key: Some(entry.key.clone()),
+            position: position(entry.line, column),
+        }
+    }
+
+    fn required(&self, key: &'static str) -> Result<&'a ini::Entry, ConfError> {
+        self.get(key)
+            .ok_or_else(|| Self::section_error(self.section, ErrorKind::MissingKey(key)))
+    }
+
+    fn string(&self, key: &str) -> Option<String> {
+        self.get(key).map(|entry| entry.value.clone())
+    }
+
+    /// parse the value of `key`, `None` if it's not set.
+    fn parse<T>(
+        &self,
+        key: &str,
+        parse: impl FnOnce(&str) -> Result<T, ErrorKind>,
+    ) -> Result<Option<T>, ConfError> {
+        let Some(entry) = self.get(key) else {
+            return Ok(None);
+        };
+        parse(&entry.value)
+            .map(Some)
+            .map_err(|kind| self.error(entry, entry.value_column, kind))
+    }
+
+    /// parse every item of the comma separated value of `key`, an error points at the item.
+    fn parse_list<T>(
+        &self,
+        key: &str,
+        parse: impl Fn(&str) -> Result<T, ErrorKind>,
+    ) -> Result<Vec<T>, ConfError> {
+        let Some(entry) = self.get(key) else {
+            return Ok(Vec::new());
+        };
+
+        let mut items = Vec::new();
+        let mut offset = 0;
+        for item in entry.value.split(',') {
+            let trimmed = item.trim();
+            if !trimmed.is_empty() {
+                let start = offset + item.len() - item.trim_start().len();
+                let column = entry.value_column + entry.value[..start].chars().count();
+                items.push(parse(trimmed).map_err(|kind| self.error(entry, column, kind))?);
             }
+            offset += item.len() + 1;
         }
-        if let Some(interface) = interface {
-            Ok(Conf { interface, peers })
-        } else {
-            Err(ConfError::MissingInterface)
+        Ok(items)
+    }
+}
+
+/// the position of an error at `column` of `line`, none for line 0.
+pub(crate) fn position(line: usize, column: usize) -> Option<(u32, u32)> {
+    (line > 0).then_some((line as u32, column as u32))
+}
+
+fn invalid(value: &str, expected: &'static str) -> ErrorKind {
+    ErrorKind::Invalid {
+        value: value.to_string(),
+        expected,
+    }
+}
+
+/// the known name closest to `name`, if it's close enough to be a typo of it. Case doesn't count.
+fn suggest(name: &str, known: &[&'static str]) -> Option<&'static str> {
+    let name = name.to_ascii_lowercase();
+    known
+        .iter()
+        .map(|k| (edit_distance(&name, &k.to_ascii_lowercase()), *k))
+        .filter(|&(distance, k)| distance <= 2 && distance < k.len())
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, k)| k)
+}
+
+/// the levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = prev[j] + usize::from(ca != cb);
+            cur[j + 1] = substitute.min(prev[j + 1] + 1).min(cur[j] + 1);
         }
+        prev = cur;
     }
+    prev[b.len()]
 }
 
-fn parse_cidr(cidr: &str) -> Result<(Ipv4Addr, u8), ConfError> {
+fn parse_table(table: &str) -> Result<Option<u32>, ErrorKind> {
+    match table.trim() {
+        "off" => Ok(None),
+        "auto" | "main" => Ok(Some(Conf::DEFAULT_TABLE)),
+        id => match id.parse() {
+            Ok(0) | Err(_) => Err(ErrorKind::Table(table.to_string())),
+            Ok(id) => Ok(Some(id)),
+        },
+    }
+}
+
+/// seconds between two resolutions of a host name, `off` or 0 never resolves it again.
+fn parse_resolve_interval(interval: &str) -> Result<Option<Duration>, ErrorKind> {
+    match interval.trim() {
+        "off" | "0" => Ok(None),
+        secs => secs
+            .parse()
+            .map(|secs| Some(Duration::from_secs(secs)))
+            .map_err(|_| ErrorKind::ResolveInterval(interval.to_string())),
+    }
+}
+
+/// a network device name, which the kernel limits to IFNAMSIZ - 1 bytes without slashes or
+/// whitespace.
+fn parse_iface_name(iface: &str) -> Result<String, ErrorKind> {
+    let name = iface.trim();
+    let valid = !name.is_empty()
+        && name.len() < libc::IFNAMSIZ
+        && !name.contains(|c: char| c == '/' || c == ':' || c.is_whitespace());
+    match valid {
+        true => Ok(name.to_string()),
+        false => Err(ErrorKind::BindInterface(iface.to_string())),
+    }
+}
+
+/// a mark in decimal or `0x` hex, like `ip rule` takes it. `off` and 0 mean no mark.
+fn parse_fwmark(fwmark: &str) -> Result<Option<u32>, ErrorKind> {
+    let mark = match fwmark.trim() {
+        "off" => return Ok(None),
+        hex if hex.starts_with("0x") => u32::from_str_radix(&hex[2..], 16),
+        dec => dec.parse(),
+    };
+    match mark {
+        Ok(0) => Ok(None),
+        Ok(mark) => Ok(Some(mark)),
+        Err(_) => Err(ErrorKind::FwMark(fwmark.to_string())),
+    }
+}
+
+fn parse_cidr(cidr: &str) -> Result<(IpAddr, u8), ErrorKind> {
     let (ip_str, subnet_str) = cidr
         .split_once('/')
-        .ok_or_else(|| ConfError::IpFormat("Invalid CIDR format: {cidr}".to_string()))?;
+        .ok_or_else(|| ErrorKind::IpFormat(format!("Invalid CIDR format: {cidr}")))?;
 
     let ip = ip_str
-        .parse::<Ipv4Addr>()
-        .map_err(|_| ConfError::IpFormat("Invalid IP address: {cidr}".to_string()))?;
+        .parse::<IpAddr>()
+        .map_err(|_| ErrorKind::IpFormat(format!("Invalid IP address: {cidr}")))?;
 
     let subnet = subnet_str
         .parse::<u8>()
-        .map_err(|_| ConfError::IpFormat("Invalid subnet mask: {cidr}".to_string()))?;
+        .map_err(|_| ErrorKind::IpFormat(format!("Invalid subnet mask: {cidr}")))?;
 
-    if subnet > 32 {
-        return Err(ConfError::IpFormat(
-            "Subnet mask must be in the range 0-32: {cidr}".to_string(),
-        ));
+    match ip {
+        IpAddr::V4(_) if subnet > 32 => {
+            return Err(ErrorKind::IpFormat(format!(
+                "Subnet mask must be in the range 0-32: {cidr}"
+            )))
+        }
+        IpAddr::V6(_) if subnet > 128 => {
+            return Err(ErrorKind::IpFormat(format!(
+                "Subnet mask must be in the range 0-128: {cidr}"
+            )))
+        }
+        _ => (),
     }
 
     Ok((ip, subnet))
 }
 
-#[derive(Debug, Deserialize, Eq, PartialEq)]
-#[allow(non_snake_case)]
-pub enum Section {
-    Interface {
-        Name: String,
-        Address: String,
-        ListenPort: Option<u16>,
-    },
-    Peer {
-        Name: String,
-        Endpoint: Option<String>,
-        AllowedIPs: Option<String>,
-    },
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -166,23 +766,404 @@ AllowedIPs=192.0.2.1/24
             Conf {
                 interface: InterfaceConf {
                     name: "server".into(),
-                    address: (Ipv4Addr::from([192, 0, 2, 2]), 24),
-                    listen_port: 19988
+                    address: vec![(IpAddr::from([192, 0, 2, 2]), 24)],
+                    listen_port: 19988,
+                    listen_address: None,
+                    bind_interface: None,
+                    mtu: None,
+                    table: Some(Conf::DEFAULT_TABLE),
+                    fwmark: None,
+                    hooks: Hooks::default(),
+                    dns: Dns::default(),
+                    resolve_interval: Some(Conf::DEFAULT_RESOLVE_INTERVAL),
                 },
                 peers: vec![
                     PeerConf {
                         name: "client1".into(),
                         endpoint: None,
                         allowed_ips: vec![],
+                        batch: false,
                     },
                     PeerConf {
                         name: "client2".into(),
                         endpoint: None,
-                        allowed_ips: vec![(Ipv4Addr::from([192, 0, 2, 0]), 24)],
+                        allowed_ips: vec![(IpAddr::from([192, 0, 2, 0]), 24)],
+                        batch: false,
                     }
                 ],
             },
             conf
         );
     }
+
+    #[test]
+    fn test_parse_batch() {
+        let input = r#"
+[Interface]
+Name=server
+Address=10.8.0.1/24
+
+[Peer]
+Name=client1
+Batch=true
+
+[Peer]
+Name=client2
+Batch=false
+"#;
+        let conf = Conf::parse_from(input).unwrap();
+        let batch: Vec<bool> = conf.peers.iter().map(|peer| peer.batch).collect();
+        assert_eq!(batch, [true, false]);
+    }
+
+    #[test]
+    fn test_parse_ipv6_config() {
+        let input = r#"
+[Interface]
+Name=server
+Address=10.8.0.1/24, fd00:8::1/64
+
+[Peer]
+Name=client1
+Endpoint=[2001:db8::2]:19988
+AllowedIPs=10.8.0.2/32, fd00:8::2/128, fd00:1:2:3::7/48
+"#;
+
+        let conf = Conf::parse_from(input).unwrap();
+        assert_eq!(
+            conf.peers[0].endpoint,
+            Some("[2001:db8::2]:19988".parse().unwrap())
+        );
+        assert_eq!(
+            conf.interface.address,
+            vec![
+                (IpAddr::from([10, 8, 0, 1]), 24),
+                ("fd00:8::1".parse().unwrap(), 64)
+            ]
+        );
+        assert_eq!(
+            conf.peers[0].allowed_ips,
+            vec![
+                (IpAddr::from([10, 8, 0, 2]), 32),
+                ("fd00:8::2".parse().unwrap(), 128),
+                ("fd00:1:2::".parse().unwrap(), 48)
+            ]
+        );
+
+        let input = r#"
+[Interface]
+Name=server
+Address=fd00:8::1/129
+"#;
+        assert!(matches!(
+            Conf::parse_from(input),
+            Err(ConfError {
+                kind: ErrorKind::IpFormat(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        let endpoint: Endpoint = "192.0.2.1:19988".parse().unwrap();
+        assert_eq!(endpoint.addr(), Some("192.0.2.1:19988".parse().unwrap()));
+        let endpoint: Endpoint = "vpn.example.com:19988".parse().unwrap();
+        assert_eq!(endpoint.addr(), None);
+        assert_eq!(endpoint.to_string(), "vpn.example.com:19988");
+
+        // an unusable endpoint is an error, not a peer that silently never connects
+        for endpoint in ["vpn.example.com", "2001:db8::2:19988", "192.0.2.1:http"] {
+            let input = format!(
+                "[Interface]\nName=a\nAddress=10.8.0.1/24\n[Peer]\nName=b\nEndpoint={endpoint}\n"
+            );
+            assert!(matches!(
+                Conf::parse_from(&input),
+                Err(ConfError {
+                    kind: ErrorKind::Endpoint(_),
+                    ..
+                })
+            ));
+        }
+
+        assert_eq!(parse_resolve_interval("off").unwrap(), None);
+        assert_eq!(parse_resolve_interval("0").unwrap(), None);
+        assert_eq!(
+            parse_resolve_interval("30").unwrap(),
+            Some(Duration::from_secs(30))
+        );
+        assert!(parse_resolve_interval("5m").is_err());
+
+        let input = r#"
+[Interface]
+Name=server
+Address=10.8.0.1/24
+ResolveInterval=60
+
+[Peer]
+Name=client1
+Endpoint=vpn.example.com:19988
+"#;
+        let conf = Conf::parse_from(input).unwrap();
+        assert_eq!(
+            conf.interface.resolve_interval,
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            conf.peers[0].endpoint,
+            Some(Endpoint::Host(Host {
+                name: "vpn.example.com".into(),
+                port: 19988,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_mtu() {
+        let input = r#"
+[Interface]
+Name=server
+Address=10.8.0.1/24
+"#;
+        let conf = Conf::parse_from(input).unwrap();
+        assert_eq!(conf.interface.mtu, None);
+        assert_eq!(conf.interface.table, Some(Conf::DEFAULT_TABLE));
+
+        let input = "[Interface]\nName=server\nAddress=10.8.0.1/24\nMTU=9000\n";
+        assert_eq!(Conf::parse_from(input).unwrap().interface.mtu, Some(9000));
+
+        for mtu in [0, MIN_MTU - 1, MAX_MTU + 1] {
+            let input = format!("[Interface]\nName=server\nAddress=10.8.0.1/24\nMTU={mtu}\n");
+            assert!(matches!(
+                Conf::parse_from(&input),
+                Err(ConfError { kind: ErrorKind::Mtu(m), .. }) if m == mtu
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_table() {
+        assert_eq!(parse_table("off").unwrap(), None);
+        assert_eq!(parse_table("auto").unwrap(), Some(Conf::DEFAULT_TABLE));
+        assert_eq!(parse_table("main").unwrap(), Some(Conf::DEFAULT_TABLE));
+        assert_eq!(parse_table("51820").unwrap(), Some(51820));
+        assert!(matches!(parse_table("0"), Err(ErrorKind::Table(_))));
+        assert!(matches!(parse_table("local"), Err(ErrorKind::Table(_))));
+
+        let input = "[Interface]\nName=server\nAddress=10.8.0.1/24\nTable=1000\n";
+        assert_eq!(Conf::parse_from(input).unwrap().interface.table, Some(1000));
+    }
+
+    #[test]
+    fn test_parse_hooks() {
+        let input = r#"
+[Interface]
+Name=server
+Address=10.8.0.1/24
+PreUp=modprobe tun
+PostUp=iptables -A FORWARD -i %i -j ACCEPT
+PostDown=iptables -D FORWARD -i %i -j ACCEPT
+"#;
+        let conf = Conf::parse_from(input).unwrap();
+        assert_eq!(
+            conf.interface.hooks,
+            Hooks {
+                pre_up: Some("modprobe tun".into()),
+                post_up: Some("iptables -A FORWARD -i %i -j ACCEPT".into()),
+                pre_down: None,
+                post_down: Some("iptables -D FORWARD -i %i -j ACCEPT".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_dns() {
+        let input = "[Interface]\nName=server\nAddress=10.8.0.1/24\nDNS=192.0.2.53, corp.example\n";
+        let conf = Conf::parse_from(input).unwrap();
+        assert_eq!(
+            conf.interface.dns,
+            Dns {
+                servers: vec![IpAddr::from([192, 0, 2, 53])],
+                search: vec!["corp.example".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_bind() {
+        let input = "[Interface]\nName=server\nAddress=10.8.0.1/24\nListenAddress=fd00::1\n";
+        let conf = Conf::parse_from(input).unwrap();
+        assert_eq!(
+            conf.interface.listen_address,
+            Some("fd00::1".parse().unwrap())
+        );
+        assert_eq!(conf.interface.bind_interface, None);
+
+        let input = "[Interface]\nName=server\nAddress=10.8.0.1/24\nListenAddress=192.0.2.2\n\
+                     BindInterface=eth0\n";
+        let conf = Conf::parse_from(input).unwrap();
+        assert_eq!(
+            conf.interface.listen_address,
+            Some(IpAddr::from([192, 0, 2, 2]))
+        );
+        assert_eq!(conf.interface.bind_interface.as_deref(), Some("eth0"));
+
+        let input = "[Interface]\nName=server\nAddress=10.8.0.1/24\nListenAddress=eth0\n";
+        assert!(matches!(
+            Conf::parse_from(input),
+            Err(ConfError {
+                kind: ErrorKind::ListenAddress(_),
+                ..
+            })
+        ));
+
+        assert_eq!(parse_iface_name(" eth0 ").unwrap(), "eth0");
+        assert!(parse_iface_name("").is_err());
+        assert!(parse_iface_name("averyveryverylongname").is_err());
+        assert!(parse_iface_name("eth0/1").is_err());
+    }
+
+    #[test]
+    fn test_parse_fwmark() {
+        assert_eq!(parse_fwmark("off").unwrap(), None);
+        assert_eq!(parse_fwmark("0").unwrap(), None);
+        assert_eq!(parse_fwmark("51820").unwrap(), Some(51820));
+        assert_eq!(parse_fwmark("0xca6c").unwrap(), Some(0xca6c));
+        assert!(matches!(parse_fwmark("0x"), Err(ErrorKind::FwMark(_))));
+        assert!(matches!(parse_fwmark("-1"), Err(ErrorKind::FwMark(_))));
+
+        let input = "[Interface]\nName=server\nAddress=10.8.0.1/24\nFwMark=0xca6c\n";
+        assert_eq!(
+            Conf::parse_from(input).unwrap().interface.fwmark,
+            Some(0xca6c)
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (input, line, column, message) in [
+            (
+                "[Interface]\nName=server\nAdress=10.8.0.1/24\n",
+                3,
+                1,
+                "line 3, column 1: [Interface]: unknown key Adress, did you mean Address?",
+            ),
+            (
+                "[Interface]\nName=server\nAddress=10.8.0.1/24\nmtu=1420\n",
+                4,
+                1,
+                "line 4, column 1: [Interface]: unknown key mtu, did you mean MTU?",
+            ),
+            (
+                "[Interface]\nName=server\nAddress=10.8.0.1/24\nPersistentKeepalive=25\n",
+                4,
+                1,
+                "line 4, column 1: [Interface]: unknown key PersistentKeepalive",
+            ),
+            (
+                "[Interface]\nName=server\nAddress=10.8.0.1/24\n[Peers]\nName=a\n",
+                4,
+                2,
+                "line 4, column 2: unknown section [Peers], did you mean Peer?",
+            ),
+            (
+                "[Interface]\nName=server\nName=client\nAddress=10.8.0.1/24\n",
+                3,
+                1,
+                "line 3, column 1: [Interface]: duplicate key Name, it's already set on line 2",
+            ),
+            (
+                "\n[Interface]\nAddress=10.8.0.1/24\n",
+                2,
+                2,
+                "line 2, column 2: [Interface]: missing key Name",
+            ),
+            (
+                "[Interface]\nName=server\nAddress=10.8.0.1/24, 10.9.0.1\n",
+                3,
+                22,
+                "line 3, column 22: [Interface] Address: invalid cidr address: Invalid CIDR format: 10.9.0.1",
+            ),
+            (
+                "[Interface]\nName=server\nAddress=10.8.0.1/24\nListenPort = 70000\n",
+                4,
+                14,
+                "line 4, column 14: [Interface] ListenPort: invalid value `70000`, expected a port number",
+            ),
+            (
+                "[Interface]\nName=server\nAddress=10.8.0.1/24\n[Interface]\n",
+                4,
+                2,
+                "line 4, column 2: [Interface]: multiple interface definition, the first is on line 1",
+            ),
+            (
+                "[Interface]\nName=server\nAddress=10.8.0.1/24\n[Peer]\nName=a\nBatch=yes\n",
+                6,
+                7,
+                "line 6, column 7: [Peer] Batch: invalid value `yes`, expected true or false",
+            ),
+            (
+                "[Interface]\nName=server\nAddress=10.8.0.1/24\nName server\n",
+                4,
+                1,
+                "line 4, column 1: expected a `[Section]`, a `Key = value` entry or a comment",
+            ),
+        ] {
+            let err = Conf::parse_from(input).unwrap_err();
+            assert_eq!(err.position, Some((line, column)), "{input:?}");
+            assert_eq!(err.to_string(), message);
+        }
+
+        let err = Conf::parse_from("[Peer]\nName=a\n").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::MissingInterface));
+        assert_eq!(err.position, None);
+    }
+
+    #[test]
+    fn test_suggest() {
+        let keys = Conf::INTERFACE_KEYS;
+        assert_eq!(suggest("ListenPrt", keys), Some("ListenPort"));
+        assert_eq!(suggest("listenport", keys), Some("ListenPort"));
+        assert_eq!(suggest("Dns", keys), Some("DNS"));
+        assert_eq!(suggest("PrivateKey", keys), None);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn test_to_ini() {
+        let input = r#"
+[Interface]
+Name=server
+Address=192.0.2.2/24, fd00:8::1/64
+ListenPort=51820
+ListenAddress=192.0.2.2
+BindInterface=eth0
+MTU=1380
+Table=off
+FwMark=0xca6c
+PostUp=iptables -A FORWARD -i %i -j ACCEPT
+DNS=192.0.2.53, corp.example
+ResolveInterval=off
+
+[Peer]
+Name=client1
+Endpoint=vpn.example.com:19988
+
+[Peer]
+Name=client2
+AllowedIPs=192.0.2.0/24, fd00:8::2/128
+Batch=true
+"#;
+        let conf = Conf::parse_from(input).unwrap();
+        assert_eq!(conf.to_ini(), input.trim_start());
+        assert_eq!(Conf::parse_from(&conf.to_ini()).unwrap(), conf);
+
+        let conf = Conf {
+            interface: InterfaceConf::new("client"),
+            peers: vec![],
+        };
+        assert_eq!(conf.to_ini(), "[Interface]\nName=client\nAddress=\n");
+        assert_eq!(Conf::parse_from(&conf.to_ini()).unwrap(), conf);
+    }
 }
@@ -1,30 +1,91 @@
 use crate::tun::TunSocket;
 use std::collections::HashMap;
 use std::io;
-use std::net::{SocketAddr, UdpSocket};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, UdpSocket};
 use std::os::fd::{AsRawFd, BorrowedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::allowed_ip::AllowedIps;
-use crate::packet::Packet;
-use crate::peer::{Action, Peer, PeerName};
+use crate::mtu::{MAX_MTU, MIN_MTU, OVERHEAD};
+#[cfg(target_os = "linux")]
+use crate::netlink::Netlink;
+use crate::packet::{self, Packet, PacketBatch, PacketData, BATCH_LEN_SIZE, DATA_MIN_SIZE};
+use crate::peer::{Action, Endpoint, Peer, PeerName};
+use crate::peer_table::PeerTable;
 use crate::poll::{Poll, SockID, Token};
+use crate::resolve::{self, Host, Resolver, SystemResolver};
+use crate::route::Routes;
+use anyhow::Context;
 use socket2::{Domain, Protocol, Socket, Type};
 use tracing::{debug, error, info, instrument, warn};
 
-const BUF_SIZE: usize = 1504;
+// `OVERHEAD` counts the header of `Data` packets
+const _: () = assert!(OVERHEAD == 40 + 8 + DATA_MIN_SIZE as u32);
+
+/// The outer mtu assumed when the route to the peers can not be looked up.
+const FALLBACK_LINK_MTU: u32 = 1500;
+
+/// How long a handshake to a peer with a host name endpoint may go unanswered before the host is
+/// resolved again and the handshake is retried, also how often that is checked.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Device is responsible for driving the main event loop and peer lookup logic.
 pub struct Device {
     name: PeerName,
     udp: Arc<UdpSocket>,
+    udp_ipv6: bool,
     iface: TunSocket,
-    peers_by_name: HashMap<PeerName, Arc<Peer>>,
-    peers_by_idx: Vec<Arc<Peer>>,
-    peers_by_ip: AllowedIps<Arc<Peer>>,
+    peers: PeerTable,
     poll: Poll,
     use_connected_peer: bool,
-    listen_port: u16,
+    socket: SocketOptions,
+    mtu: usize,
+    routes: Option<Routes>,
+    resolver: Arc<dyn Resolver>,
+    resolve_interval: Option<Duration>,
+}
+
+/// SocketOptions are what every udp socket of a device is created with, the listening socket and
+/// the connected per-peer ones alike.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    pub port: u16,
+    /// the address to bind instead of all addresses, `ListenAddress=`.
+    pub address: Option<IpAddr>,
+    /// the network device the sockets are bound to, `BindInterface=`.
+    pub bind_interface: Option<String>,
+    /// the firewall mark of every packet sent, `FwMark=`.
+    pub fwmark: Option<u32>,
+}
+
+/// PendingBatch collects the packets of one `handle_tun` drain headed for the same peer.
+struct PendingBatch {
+    peer: Arc<Peer>,
+    data: Vec<u8>,
+    count: usize,
+    mtu: usize,
+}
+
+impl PendingBatch {
+    fn new(peer: Arc<Peer>, mtu: usize) -> Self {
+        Self {
+            peer,
+            data: Vec::with_capacity(mtu),
+            count: 0,
+            mtu,
+        }
+    }
+
+    fn fits(&self, len: usize) -> bool {
+        self.data.len() + BATCH_LEN_SIZE + len <= self.mtu
+    }
+
+    fn push(&mut self, packet: &[u8]) {
+        PacketBatch::append(&mut self.data, packet);
+        self.count += 1;
+    }
 }
 
 pub struct DeviceConfig<'a> {
@@ -32,6 +93,14 @@ pub struct DeviceConfig<'a> {
     use_connected_peer: bool,
     listen_port: u16,
     tun_name: &'a str,
+    addresses: Vec<(IpAddr, u8)>,
+    mtu: Option<u32>,
+    table: Option<u32>,
+    listen_address: Option<IpAddr>,
+    bind_interface: Option<String>,
+    fwmark: Option<u32>,
+    resolver: Arc<dyn Resolver>,
+    resolve_interval: Option<Duration>,
 }
 
 impl<'a> DeviceConfig<'a> {
@@ -46,14 +115,170 @@ impl<'a> DeviceConfig<'a> {
             tun_name,
             listen_port,
             use_connected_peer,
+            addresses: Vec::new(),
+            mtu: None,
+            table: None,
+            listen_address: None,
+            bind_interface: None,
+            fwmark: None,
+            resolver: Arc::new(SystemResolver),
+            resolve_interval: None,
         }
     }
+
+    /// assign `addresses` to the tun interface when the device is created.
+    pub fn with_addresses(mut self, addresses: Vec<(IpAddr, u8)>) -> Self {
+        self.addresses = addresses;
+        self
+    }
+
+    /// set the mtu of the tun interface when the device is created, buffers are sized after it.
+    ///
+    /// without it the device keeps the mtu the interface already has.
+    pub fn with_mtu(mut self, mtu: u32) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// install a route into the tun interface for the allowed prefixes of every peer, in routing
+    /// table `table`.
+    pub fn with_table(mut self, table: u32) -> Self {
+        self.table = Some(table);
+        self
+    }
+
+    /// bind the sockets to `address` instead of all addresses.
+    pub fn with_listen_address(mut self, address: IpAddr) -> Self {
+        self.listen_address = Some(address);
+        self
+    }
+
+    /// bind the sockets to network device `iface`, so that the device only sends and receives
+    /// through it.
+    pub fn with_bind_interface(mut self, iface: impl Into<String>) -> Self {
+        self.bind_interface = Some(iface.into());
+        self
+    }
+
+    /// mark every packet the device sends with `fwmark`. Together with `with_table`, the routes
+    /// go into a table that the marked packets skip, see `Routes`.
+    pub fn with_fwmark(mut self, fwmark: u32) -> Self {
+        self.fwmark = Some(fwmark);
+        self
+    }
+
+    /// resolve the host name endpoints of the peers with `resolver` instead of the system resolver.
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// resolve the host name endpoints of the peers again every `interval`, not only when the device
+    /// starts and after a handshake timed out.
+    pub fn with_resolve_interval(mut self, interval: Duration) -> Self {
+        self.resolve_interval = Some(interval);
+        self
+    }
+}
+
+/// assign the addresses and the mtu to the tun interface and bring it up, like `ip addr add`,
+/// `ip link set mtu` and `ip link set up` would.
+#[cfg(target_os = "linux")]
+fn configure_iface(name: &str, addresses: &[(IpAddr, u8)], mtu: Option<u32>) -> anyhow::Result<()> {
+    let mut nl = Netlink::new()?;
+
+    if let Some(mtu) = mtu {
+        nl.set_mtu(name, mtu)?;
+    }
+    for (addr, prefix_len) in addresses {
+        nl.add_address(name, *addr, *prefix_len)?;
+    }
+    nl.set_up(name, true)?;
+
+    info!("configured {name}: addresses {addresses:?}, mtu {mtu:?}, up");
+    Ok(())
 }
 
-pub fn new_udp_socket(addr: Option<SocketAddr>, port: u16) -> io::Result<UdpSocket> {
-    let socket_addr = SocketAddr::from(([0, 0, 0, 0], port));
+#[cfg(target_os = "macos")]
+fn configure_iface(name: &str, addresses: &[(IpAddr, u8)], mtu: Option<u32>) -> anyhow::Result<()> {
+    warn!(
+        "configuring {name} ({addresses:?}, mtu {mtu:?}) is not supported on macos, use ifconfig"
+    );
+    Ok(())
+}
+
+/// derive the tun mtu from the mtu of the routes to `endpoints`, or of the default route if there
+/// are none, minus `OVERHEAD`.
+///
+/// the smallest route mtu wins, so that packets to every peer fit. Routes that can't be looked up
+/// are logged and count as `FALLBACK_LINK_MTU`.
+pub fn auto_mtu(endpoints: impl IntoIterator<Item = SocketAddr>) -> u32 {
+    let mut dsts: Vec<IpAddr> = endpoints.into_iter().map(|ep| ep.ip()).collect();
+    if dsts.is_empty() {
+        // any address outside the local networks resolves to the default route
+        dsts.push(IpAddr::from([192, 0, 2, 1]));
+    }
+
+    let link_mtu = dsts
+        .into_iter()
+        .map(|dst| {
+            route_mtu(dst).unwrap_or_else(|err| {
+                warn!("no route mtu for {dst}, assuming {FALLBACK_LINK_MTU}: {err:#}");
+                FALLBACK_LINK_MTU
+            })
+        })
+        .min()
+        .unwrap_or(FALLBACK_LINK_MTU);
+
+    let mtu = link_mtu.saturating_sub(OVERHEAD).clamp(MIN_MTU, MAX_MTU);
+    info!("route mtu {link_mtu}, tun mtu {mtu}");
+    mtu
+}
 
-    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+#[cfg(target_os = "linux")]
+fn route_mtu(dst: IpAddr) -> anyhow::Result<u32> {
+    Ok(Netlink::new()?.route_mtu(dst)?)
+}
+
+#[cfg(target_os = "macos")]
+fn route_mtu(_dst: IpAddr) -> anyhow::Result<u32> {
+    anyhow::bail!("route lookup is not supported on macos")
+}
+
+/// creates a udp socket bound to `opts.port`, connected to `addr` if it's set.
+///
+/// Without a listen address the socket is a dual-stack IPv6 socket on all addresses, which serves
+/// IPv4 peers through IPv4-mapped addresses, unless the host has no IPv6 support at all, then it
+/// falls back to IPv4. A listen address binds a socket of its own family, only an unspecified IPv6
+/// address is dual-stack.
+pub fn new_udp_socket(addr: Option<SocketAddr>, opts: &SocketOptions) -> io::Result<UdpSocket> {
+    let port = opts.port;
+    let (socket, socket_addr) = match opts.address {
+        Some(ip) => {
+            let socket_addr = SocketAddr::new(ip, port);
+            let socket = Socket::new(
+                Domain::for_address(socket_addr),
+                Type::DGRAM,
+                Some(Protocol::UDP),
+            )?;
+            if ip.is_ipv6() {
+                socket.set_only_v6(!ip.is_unspecified())?;
+            }
+            (socket, socket_addr)
+        }
+        None => match Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP)) {
+            Ok(socket) => {
+                socket.set_only_v6(false)?;
+                (socket, SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))
+            }
+            Err(err) if err.raw_os_error() == Some(libc::EAFNOSUPPORT) => {
+                warn!("no ipv6 support, falling back to an ipv4 only socket");
+                let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+                (socket, SocketAddr::from(([0, 0, 0, 0], port)))
+            }
+            Err(err) => return Err(err),
+        },
+    };
 
     // SO_REUSEADDR is a socket option that influences how the underlying operating system manages socket bindings,
     // particularly regarding address and port reuse.
@@ -62,55 +287,227 @@ pub fn new_udp_socket(addr: Option<SocketAddr>, port: u16) -> io::Result<UdpSock
     // https://stackoverflow.com/questions/14388706/how-do-so-reuseaddr-and-so-reuseport-differ
     socket.set_reuse_address(true)?;
     socket.set_nonblocking(true)?;
+    if let Some(mark) = opts.fwmark {
+        set_mark(&socket, mark)?;
+    }
+    if let Some(iface) = &opts.bind_interface {
+        bind_device(&socket, iface)?;
+    }
 
-    socket.bind(&socket_addr.into())?;
+    socket
+        .bind(&socket_addr.into())
+        .map_err(|err| io::Error::new(err.kind(), format!("bind {socket_addr}: {err}")))?;
 
     // connect to addr if it's set
     if let Some(addr) = addr {
-        socket.connect(&addr.into())?;
+        socket.connect(&to_socket_family(addr, socket_addr.is_ipv6()).into())?;
     }
 
     Ok(socket.into())
 }
 
+// asm-generic/socket.h, libc doesn't export them for every target
+#[cfg(target_os = "linux")]
+const SO_BINDTODEVICE: libc::c_int = 25;
+#[cfg(target_os = "linux")]
+const SO_MARK: libc::c_int = 36;
+
+/// set SO_MARK, the firewall mark of every packet sent through `socket`. It takes CAP_NET_ADMIN.
+#[cfg(target_os = "linux")]
+fn set_mark(socket: &Socket, mark: u32) -> io::Result<()> {
+    set_socket_option(socket, SO_MARK, &mark.to_ne_bytes())
+        .map_err(|err| io::Error::new(err.kind(), format!("set fwmark {mark:#x}: {err}")))
+}
+
+/// set SO_BINDTODEVICE, `socket` only sends and receives through interface `iface`.
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, iface: &str) -> io::Result<()> {
+    set_socket_option(socket, SO_BINDTODEVICE, iface.as_bytes())
+        .map_err(|err| io::Error::new(err.kind(), format!("bind to device {iface}: {err}")))
+}
+
+#[cfg(target_os = "linux")]
+fn set_socket_option(socket: &Socket, name: libc::c_int, value: &[u8]) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            name,
+            value.as_ptr() as _,
+            value.len() as _,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn set_mark(_socket: &Socket, mark: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("fwmark {mark:#x} is not supported on macos"),
+    ))
+}
+
+#[cfg(target_os = "macos")]
+fn bind_device(_socket: &Socket, iface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("binding to device {iface} is not supported on macos"),
+    ))
+}
+
+/// maps `addr` into the address family of a socket, a dual-stack IPv6 socket reaches IPv4 peers
+/// through IPv4-mapped IPv6 addresses.
+fn to_socket_family(addr: SocketAddr, ipv6: bool) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if ipv6 => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+        _ => addr,
+    }
+}
+
+/// the reverse of `to_socket_family`: IPv4 peers seen on a dual-stack socket are reported with
+/// their plain IPv4 address, so that an endpoint compares equal whichever socket it came from.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
 impl Device {
     pub fn new(config: DeviceConfig) -> anyhow::Result<Self> {
         let iface = TunSocket::new(config.tun_name)?.set_non_blocking()?;
 
+        if !config.addresses.is_empty() || config.mtu.is_some() {
+            let name = iface.name()?;
+            configure_iface(&name, &config.addresses, config.mtu)
+                .with_context(|| format!("failed to configure interface {name}"))?;
+        }
+        let mtu = match config.mtu {
+            Some(mtu) => mtu as usize,
+            None => iface.mtu()?,
+        };
+        let routes = match config.table {
+            Some(table) => Some(
+                Routes::new(&iface.name()?, table, &config.addresses, config.fwmark)
+                    .context("failed to set up route installation")?,
+            ),
+            None => None,
+        };
+
         let poll = Poll::new()?;
 
-        let udp = Arc::new(new_udp_socket(None, config.listen_port)?);
+        let socket = SocketOptions {
+            port: config.listen_port,
+            address: config.listen_address,
+            bind_interface: config.bind_interface,
+            fwmark: config.fwmark,
+        };
+        let udp = Arc::new(new_udp_socket(None, &socket)?);
+        let udp_ipv6 = udp.local_addr()?.is_ipv6();
 
         Ok(Self {
             name: config.name,
             udp,
+            udp_ipv6,
             iface,
-            peers_by_name: HashMap::new(),
-            peers_by_idx: Vec::new(),
-            peers_by_ip: AllowedIps::new(),
+            peers: PeerTable::new(),
             poll,
             use_connected_peer: config.use_connected_peer,
-            listen_port: config.listen_port,
+            socket,
+            mtu,
+            routes,
+            resolver: config.resolver,
+            resolve_interval: config.resolve_interval,
         })
     }
 
-    pub fn add_peer(&mut self, name: PeerName, mut peer: Peer) {
-        let local_idx = self.peers_by_idx.len();
-        peer.set_local_idx(local_idx as u32);
+    /// the mtu of the tun interface, no inner packet is larger.
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// the routing table the routes of the allowed prefixes go into, if they are installed.
+    pub fn route_table(&self) -> Option<u32> {
+        self.routes.as_ref().map(Routes::table)
+    }
 
-        let peer = Arc::new(peer);
+    /// the size of the buffers that hold an encapsulated packet.
+    ///
+    /// one byte more than the largest valid packet, so that a truncated read of an oversize
+    /// packet can be told apart from one that fits exactly.
+    fn buf_size(&self) -> usize {
+        self.mtu + DATA_MIN_SIZE + 1
+    }
 
-        self.peers_by_name.insert(name, Arc::clone(&peer));
-        self.peers_by_ip.extend(
-            peer.allowed_ips()
-                .iter()
-                .map(|(_, ip, cidr)| (ip, cidr, Arc::clone(&peer))),
-        );
-        self.peers_by_idx.push(peer);
+    /// reserve room for `additional` more peers ahead of a bulk `add_peer`.
+    pub fn reserve_peers(&mut self, additional: usize) {
+        self.peers.reserve(additional);
+    }
+
+    /// add a peer that is allowed to use `allowed_ips`, they're routed to it.
+    pub fn add_peer(&mut self, name: PeerName, peer: Peer, allowed_ips: &[(IpAddr, u8)]) {
+        self.peers.insert(name, peer, allowed_ips);
+        if let Some(routes) = &self.routes {
+            for &(ip, cidr) in allowed_ips {
+                routes.add(ip, cidr);
+            }
+        }
+    }
+
+    /// find a peer by its configured name.
+    pub fn peer(&self, name: &str) -> Option<&Arc<Peer>> {
+        self.peers.by_name(PeerName::from(name.as_bytes()))
+    }
+
+    /// route `ip/cidr` to `peer`, this can be called from another thread while `wait` is running.
+    pub fn insert_allowed_ip(&self, peer: &Arc<Peer>, ip: IpAddr, cidr: u8) {
+        match self.peers.insert_allowed_ip(peer, ip, cidr) {
+            Some(prev) => {
+                if prev.local_idx() != peer.local_idx() {
+                    info!(
+                        "{ip}/{cidr} moved from peer {} to peer {}",
+                        prev.local_idx(),
+                        peer.local_idx()
+                    );
+                }
+            }
+            None => {
+                if let Some(routes) = &self.routes {
+                    routes.add(ip, cidr);
+                }
+            }
+        }
+    }
+
+    /// remove every prefix routed to `peer`, this can be called from another thread while `wait`
+    /// is running.
+    pub fn remove_allowed_ips(&self, peer: &Peer) {
+        let removed = self.peers.remove_allowed_ips(peer);
+        if let Some(routes) = &self.routes {
+            for (ip, cidr) in removed {
+                routes.remove(ip, cidr);
+            }
+        }
     }
 
+    /// run the event loop, and next to it the re-resolution of host name endpoints if there are
+    /// any.
     pub fn wait(&self) {
-        let mut buf = [0u8; BUF_SIZE];
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            if self.peers.iter().any(|peer| peer.endpoint().host.is_some()) {
+                s.spawn(|| self.refresh_endpoints(&done));
+            }
+            self.event_loop();
+            done.store(true, Ordering::Relaxed);
+        });
+    }
+
+    fn event_loop(&self) {
+        // what is read, and what it's encapsulated or decapsulated into
+        let mut buf = vec![0u8; self.buf_size()];
+        let mut out = vec![0u8; self.buf_size()];
 
         // there will be three IO resources in this loop
         //
@@ -121,23 +518,24 @@ impl Device {
             match token {
                 Token::Tun => {
                     debug!("handle Token::Tun");
-                    if let Err(err) = self.handle_tun(&mut buf) {
+                    if let Err(err) = self.handle_tun(&mut buf, &mut out) {
                         error!("tun error: {:?}", err);
                     }
                 }
                 Token::Sock(SockID::Unconnected) => {
                     debug!("handle Token::Sock(SockID::BindOnly)");
-                    if let Err(err) = self.handle_udp(&mut buf) {
+                    if let Err(err) = self.handle_udp(&mut buf, &mut out) {
                         error!("udp error: {:?}", err);
                     }
                 }
                 Token::Sock(SockID::Connected(i)) => {
                     debug!("handle Token::Sock(SockID::ConnectedPeer)");
-                    let Some(peer) = self.peers_by_idx.get(i as usize) else {
+                    let Some(peer) = self.peers.by_idx(i) else {
                         continue;
                     };
                     if let Some(conn) = peer.endpoint().conn.as_deref() {
-                        if let Err(err) = self.handle_connected_udp(conn, peer, &mut buf) {
+                        if let Err(err) = self.handle_connected_udp(conn, peer, &mut buf, &mut out)
+                        {
                             error!("udp error: {:?}", err);
                         }
                     }
@@ -155,65 +553,202 @@ impl Device {
             .register_read(Token::Sock(SockID::Unconnected), self.udp.as_ref())?;
         self.poll.register_read::<_, SockID>(Token::Tun, &tun)?;
 
-        let mut buf = [0u8; BUF_SIZE];
-        for (_, peer) in self.peers_by_name.iter() {
+        let mut buf = vec![0u8; self.buf_size()];
+        for peer in self.peers.iter() {
+            let host = peer.endpoint().host.clone();
+            if let Some(host) = host {
+                self.resolve_endpoint(peer, &host, false, &mut buf);
+            }
             self.take_action(peer.initiate_handshake(self.name.as_ref(), &mut buf))
         }
 
         Ok(())
     }
 
+    /// resolve the host name endpoints of the peers again: every `resolve_interval`, every
+    /// `HANDSHAKE_TIMEOUT` while one doesn't resolve, and when a handshake timed out. Runs until
+    /// `done` is set.
+    fn refresh_endpoints(&self, done: &AtomicBool) {
+        let mut buf = vec![0u8; self.buf_size()];
+        let started = Instant::now();
+        let mut resolved_at = HashMap::new();
+
+        while !done.load(Ordering::Relaxed) {
+            thread::sleep(HANDSHAKE_TIMEOUT);
+
+            for peer in self.peers.iter() {
+                let (host, unresolved) = match &*peer.endpoint() {
+                    Endpoint {
+                        host: Some(host),
+                        addr,
+                        ..
+                    } => (host.clone(), addr.is_none()),
+                    _ => continue,
+                };
+                let last = *resolved_at.get(&peer.local_idx()).unwrap_or(&started);
+                let due = self
+                    .resolve_interval
+                    .is_some_and(|interval| last.elapsed() >= interval);
+                let timed_out = peer.handshake_timed_out(HANDSHAKE_TIMEOUT);
+                if !(due || unresolved || timed_out) {
+                    continue;
+                }
+
+                if timed_out {
+                    warn!(
+                        "no handshake response from {host}, peer {}, resolving it again",
+                        peer.local_idx()
+                    );
+                }
+                resolved_at.insert(peer.local_idx(), Instant::now());
+                self.resolve_endpoint(peer, &host, timed_out, &mut buf);
+            }
+        }
+    }
+
+    /// resolve `host` and move `peer` to the address it resolves to. A new handshake is sent when
+    /// the address changed or `retry` is set, failures are logged and leave the peer as it is.
+    fn resolve_endpoint(&self, peer: &Peer, host: &Host, retry: bool, buf: &mut [u8]) {
+        let current = peer.endpoint().addr;
+        let addrs = match self.resolver.resolve(host) {
+            Ok(addrs) => addrs,
+            Err(err) => {
+                error!(
+                    "failed to resolve endpoint {host} of peer {}: {err}",
+                    peer.local_idx()
+                );
+                return;
+            }
+        };
+        let Some(addr) = resolve::pick(addrs.into_iter().filter(|a| self.reaches(*a)), current)
+        else {
+            error!(
+                "endpoint {host} of peer {} has no address the socket can reach",
+                peer.local_idx()
+            );
+            return;
+        };
+
+        let (changed, conn) = peer.set_endpoint(addr);
+        if changed {
+            info!("endpoint {host} of peer {} is {addr}", peer.local_idx());
+        }
+        // a peer that had a connected socket gets one for the new address
+        if let Some(conn) = conn {
+            self.poll.delete(conn.as_ref()).expect("poll delete");
+            if let Err(err) = self.connect_peer(peer) {
+                error!("error connecting to peer: {:?}", err);
+            }
+        }
+        if changed || retry {
+            peer.reset_handshake();
+            self.take_action(peer.initiate_handshake(self.name.as_ref(), buf));
+        }
+    }
+
+    /// whether the listening socket can send to `addr`, it may be bound to one address family.
+    fn reaches(&self, addr: SocketAddr) -> bool {
+        let dual_stack = self.udp_ipv6
+            && self
+                .socket
+                .address
+                .is_none_or(|ip| ip.is_ipv6() && ip.is_unspecified());
+        dual_stack || addr.is_ipv6() == self.udp_ipv6
+    }
+
     // Handle incoming data from tun interface
     #[instrument(name = "handle_tun", skip_all)]
-    pub fn handle_tun(&self, buf: &mut [u8]) -> io::Result<()> {
+    pub fn handle_tun(&self, buf: &mut [u8], out: &mut [u8]) -> io::Result<()> {
+        // route the whole drain with one snapshot, concurrent route updates are picked up on the
+        // next readiness event
+        let routes = self.peers.routes();
+        // packets for peers that agreed to aggregation, sent at the end of the drain
+        let mut batches: HashMap<u32, PendingBatch> = HashMap::new();
+
         while let Ok(data) = self.iface.read(buf) {
-            let (_, dst) = match etherparse::Ipv4HeaderSlice::from_slice(data) {
-                Ok(h) => {
-                    let src = h.source_addr();
-                    let dst = h.destination_addr();
-                    info!(
-                        "got Ipv4 packet of size: {}, {src} -> {dst}, from tunnel: {}",
-                        data.len(),
-                        self.iface.name().unwrap()
-                    );
-                    (src, dst)
-                }
-                Err(e) => {
-                    warn!("not an Ipv4 packet: {:?}", e);
-                    continue;
-                }
+            if data.len() > self.mtu {
+                warn!(
+                    "drop packet of at least {} bytes from tun, larger than the mtu {}",
+                    data.len(),
+                    self.mtu
+                );
+                continue;
+            }
+            let Some((src, dst)) = packet::ip_addrs(data) else {
+                warn!("not an ip packet, first byte: {:?}", data.first());
+                continue;
             };
+            info!(
+                "got ip packet of size: {}, {src} -> {dst}, from tunnel: {}",
+                data.len(),
+                self.iface.name().unwrap()
+            );
 
             // peer selection for outgoing packets: determines which peer an outgoing IP packet
             // should be routed to based on its destination address.
-            let Some(peer) = self.peers_by_ip.find(dst.into()) else {
+            let Some(peer) = routes.find(dst) else {
                 warn!("no peer for this ip: {dst}");
                 continue;
             };
 
-            let mut dst = [0u8; BUF_SIZE];
-            let action = peer.encapsulate(data, &mut dst);
+            if peer.batching() {
+                let batch = batches
+                    .entry(peer.local_idx())
+                    .or_insert_with(|| PendingBatch::new(Arc::clone(peer), self.mtu));
+                if !batch.fits(data.len()) {
+                    self.flush_batch(batch, out);
+                }
+                if batch.fits(data.len()) {
+                    batch.push(data);
+                    continue;
+                }
+                // too large for any batch, it goes out on its own after what was queued before it
+            }
+
+            let action = peer.encapsulate(data, out);
             self.take_action(action);
         }
 
+        for batch in batches.values_mut() {
+            self.flush_batch(batch, out);
+        }
+
         Ok(())
     }
 
+    // Send the packets collected in `batch`, a single packet is sent as plain `Data`
+    fn flush_batch(&self, batch: &mut PendingBatch, dst: &mut [u8]) {
+        let action = match batch.count {
+            0 => return,
+            1 => batch.peer.encapsulate(&batch.data[BATCH_LEN_SIZE..], dst),
+            _ => batch.peer.encapsulate_batch(&batch.data, dst),
+        };
+        debug!(
+            "flush {} packets to peer {}",
+            batch.count,
+            batch.peer.local_idx()
+        );
+        self.take_action(action);
+
+        batch.data.clear();
+        batch.count = 0;
+    }
+
     // Handle incoming data from an unconnected UdpSocket
     #[instrument(name = "handle_udp", skip_all)]
-    pub fn handle_udp(&self, buf: &mut [u8]) -> io::Result<()> {
+    pub fn handle_udp(&self, buf: &mut [u8], out: &mut [u8]) -> io::Result<()> {
         self.handle_udp_generic(
             self.udp.as_ref(),
             buf,
+            out,
             |packet| {
                 match packet {
-                    Packet::HandshakeInit(ref msg) => {
-                        self.peers_by_name.get(msg.sender_name.as_slice())
-                    }
-                    Packet::HandshakeResponse(ref msg) => {
-                        self.peers_by_idx.get(msg.sender_idx as usize)
-                    }
-                    Packet::Data(ref msg) => self.peers_by_idx.get(msg.sender_idx as usize),
+                    Packet::HandshakeInit(ref msg) => self
+                        .peers
+                        .by_name(PeerName::from(msg.sender_name.as_slice())),
+                    Packet::HandshakeResponse(ref msg) => self.peers.by_idx(msg.sender_idx),
+                    Packet::Data(ref msg) => self.peers.by_idx(msg.sender_idx),
+                    Packet::Batch(ref msg) => self.peers.by_idx(msg.sender_idx),
 
                     Packet::Empty => None,
                 }
@@ -230,14 +765,16 @@ impl Device {
         socket: &UdpSocket,
         peer: &Arc<Peer>,
         buf: &mut [u8],
+        out: &mut [u8],
     ) -> io::Result<()> {
-        self.handle_udp_generic(socket, buf, |_| Some(peer), true)
+        self.handle_udp_generic(socket, buf, out, |_| Some(peer), true)
     }
 
     fn handle_udp_generic<'b, F>(
         &self,
         socket: &UdpSocket,
         buf: &mut [u8],
+        out: &mut [u8],
         get_peer: F,
         connected: bool,
     ) -> io::Result<()>
@@ -245,13 +782,19 @@ impl Device {
         F: for<'a> Fn(&'a Packet) -> Option<&'b Peer>,
     {
         while let Ok((n, addr)) = socket.recv_from(buf) {
-            let SocketAddr::V4(addr) = addr else {
-                warn!("not an ipv4 addr: {addr}");
-                continue;
-            };
+            let addr = canonical(addr);
 
             info!("got packet of size: {n}, from addr: {addr}, connected: {connected}");
 
+            if n > self.mtu + DATA_MIN_SIZE {
+                warn!(
+                    "drop packet of at least {n} bytes from {addr}, larger than the mtu {} plus \
+                     {DATA_MIN_SIZE} bytes of header, does the peer use a larger mtu?",
+                    self.mtu
+                );
+                continue;
+            }
+
             let packet = match Packet::parse_from(&buf[..n]) {
                 Ok(packet) => packet,
                 Err(e) => {
@@ -274,9 +817,19 @@ impl Device {
                     }
                 }
 
-                let mut response_buf = [0u8; BUF_SIZE];
-                let action = peer.handle_incoming_packet(packet, &mut response_buf);
-                self.take_action(action);
+                if let Packet::Batch(batch) = packet {
+                    for data in batch.packets() {
+                        let packet = Packet::Data(PacketData {
+                            sender_idx: batch.sender_idx,
+                            data,
+                        });
+                        let action = peer.handle_incoming_packet(packet, out);
+                        self.take_action(action);
+                    }
+                } else {
+                    let action = peer.handle_incoming_packet(packet, out);
+                    self.take_action(action);
+                }
             }
         }
 
@@ -285,15 +838,12 @@ impl Device {
 
     // Helper method to connect to a peer
     fn connect_peer(&self, peer: &Peer) -> io::Result<()> {
-        match peer.connect_endpoint(self.listen_port) {
-            Ok(conn) => {
-                self.poll
-                    .register_read(Token::Sock(SockID::Connected(peer.local_idx())), &*conn)
-                    .expect("poll register_read");
-                Ok(())
-            }
-            Err(err) => Err(err),
-        }
+        peer.connect_endpoint(&self.socket, |conn| {
+            self.poll
+                .register_read(Token::Sock(SockID::Connected(peer.local_idx())), conn)
+                .expect("poll register_read");
+            Ok(())
+        })
     }
 
     /// take an action
@@ -303,10 +853,18 @@ impl Device {
             Action::WriteToTun(peer, data, src_addr) => {
                 // source address filtering for incoming packets: ensures that incoming packets
                 // are from an allowed source before forwarding them to the tun interface.
-                if peer.is_allowed_ip(src_addr) {
+                if self.peers.is_allowed_ip(peer, src_addr) {
                     // send packet back to network stack
-                    let n = self.iface.write4(data);
+                    let n = match src_addr {
+                        IpAddr::V4(_) => self.iface.write4(data),
+                        IpAddr::V6(_) => self.iface.write6(data),
+                    };
                     info!("write to tun {:?} bytes", n);
+                } else {
+                    warn!(
+                        "drop packet from {src_addr}, not allowed for peer {}",
+                        peer.local_idx()
+                    );
                 }
             }
             Action::WriteToNetwork(peer, data) => {
@@ -324,8 +882,172 @@ impl Device {
         let endpoint = peer.endpoint();
         match (endpoint.conn.as_ref(), endpoint.addr) {
             (Some(conn), _) => conn.send(data),
-            (_, Some(ref addr)) => self.udp.send_to(data, addr),
+            (_, Some(addr)) => self
+                .udp
+                .send_to(data, to_socket_family(addr, self.udp_ipv6)),
             _ => Ok(0),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::time::Duration;
+
+    #[test]
+    fn test_socket_family() {
+        let v4 = SocketAddr::from((Ipv4Addr::new(172, 18, 0, 22), 19988));
+        let mapped = to_socket_family(v4, true);
+        assert_eq!(mapped.to_string(), "[::ffff:172.18.0.22]:19988");
+        assert_eq!(canonical(mapped), v4);
+        assert_eq!(to_socket_family(v4, false), v4);
+
+        let v6 = "[2001:db8::1]:19988".parse().unwrap();
+        assert_eq!(to_socket_family(v6, true), v6);
+        assert_eq!(canonical(v6), v6);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_auto_mtu() {
+        // loopback has a 64K mtu, more than any tun mtu
+        let lo = SocketAddr::from((Ipv4Addr::LOCALHOST, 19988));
+        assert_eq!(auto_mtu([lo]), MAX_MTU);
+    }
+
+    #[test]
+    fn test_dual_stack_socket() {
+        let listener = new_udp_socket(None, &SocketOptions::default()).unwrap();
+        listener.set_nonblocking(false).unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut buf = [0u8; 16];
+        let sources = [
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            SocketAddr::from((Ipv6Addr::LOCALHOST, 0)),
+        ];
+        for source in sources {
+            // skip families the host does not have
+            let Ok(sender) = UdpSocket::bind(source) else {
+                continue;
+            };
+            let target = SocketAddr::new(source.ip(), port);
+            sender.send_to(b"ping", target).unwrap();
+
+            let (n, from) = listener.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..n], b"ping");
+            assert_eq!(canonical(from), sender.local_addr().unwrap());
+        }
+
+        // a connected socket on the listening port reaches an IPv4 peer
+        let peer = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let opts = SocketOptions {
+            port,
+            ..Default::default()
+        };
+        let conn = new_udp_socket(Some(peer.local_addr().unwrap()), &opts).unwrap();
+        conn.send(b"pong").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let (n, from) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"pong");
+        assert_eq!(from.port(), port);
+    }
+
+    #[test]
+    fn test_listen_address() {
+        let opts = SocketOptions {
+            address: Some(IpAddr::from(Ipv4Addr::LOCALHOST)),
+            ..Default::default()
+        };
+        let listener = new_udp_socket(None, &opts).unwrap();
+        let local = listener.local_addr().unwrap();
+        assert_eq!(local.ip(), IpAddr::from(Ipv4Addr::LOCALHOST));
+
+        // a connected socket shares the address and the port
+        let peer = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let opts = SocketOptions {
+            port: local.port(),
+            ..opts
+        };
+        let conn = new_udp_socket(Some(peer.local_addr().unwrap()), &opts).unwrap();
+        assert_eq!(conn.local_addr().unwrap(), local);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_bind_interface() {
+        let opts = SocketOptions {
+            bind_interface: Some("caetun-missing".into()),
+            ..Default::default()
+        };
+        let err = new_udp_socket(None, &opts).unwrap_err();
+        assert!(err.to_string().contains("caetun-missing"), "{err}");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_resolve_endpoint() {
+        use crate::resolve::tests::StubResolver;
+        use std::os::fd::IntoRawFd;
+        use std::os::unix::net::UnixDatagram;
+
+        let resolver = Arc::new(StubResolver::default());
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let moved = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        moved
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        // a socketpair stands in for the tun interface
+        let (_tun, dev_tun) = UnixDatagram::pair().unwrap();
+        let tun_name = dev_tun.into_raw_fd().to_string();
+        let mut dev = Device::new(
+            DeviceConfig::new(PeerName::new("client").unwrap(), &tun_name, 0, false)
+                .with_listen_address(Ipv4Addr::LOCALHOST.into())
+                .with_resolver(resolver.clone()),
+        )
+        .unwrap();
+        let host: Host = "vpn.example.com:19988".parse().unwrap();
+        let mut peer = Peer::default();
+        peer.set_endpoint_host(host.clone());
+        dev.add_peer(PeerName::new("server").unwrap(), peer, &[]);
+        let peer = dev.peer("server").unwrap().clone();
+
+        // resolved when the device starts, an IPv6 address is no use to an IPv4 socket
+        let v6 = SocketAddr::from((Ipv6Addr::LOCALHOST, 19988));
+        let addrs = vec![v6, server.local_addr().unwrap()];
+        resolver.hosts.lock().insert(host.name.clone(), addrs);
+        dev.start().unwrap();
+        assert_eq!(peer.endpoint().addr, Some(server.local_addr().unwrap()));
+        let mut buf = vec![0u8; dev.buf_size()];
+        let n = server.recv(&mut buf).unwrap();
+        assert!(matches!(
+            Packet::parse_from(&buf[..n]),
+            Ok(Packet::HandshakeInit(_))
+        ));
+
+        // a failure leaves the peer where it is
+        resolver.hosts.lock().clear();
+        dev.resolve_endpoint(&peer, &host, false, &mut buf);
+        assert_eq!(peer.endpoint().addr, Some(server.local_addr().unwrap()));
+
+        // the host moved, a new handshake goes to the new address
+        let addrs = vec![moved.local_addr().unwrap()];
+        resolver.hosts.lock().insert(host.name.clone(), addrs);
+        dev.resolve_endpoint(&peer, &host, false, &mut buf);
+        assert_eq!(peer.endpoint().addr, Some(moved.local_addr().unwrap()));
+        let n = moved.recv(&mut buf).unwrap();
+        assert!(matches!(
+            Packet::parse_from(&buf[..n]),
+            Ok(Packet::HandshakeInit(_))
+        ));
+    }
+}
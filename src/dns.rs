@@ -0,0 +1,252 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write as _};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// the resolver configuration managed when there is no resolvconf.
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Dns is the `DNS=` setting of an interface: a comma separated list of name servers and search
+/// domains, like wg-quick takes it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Dns {
+    pub servers: Vec<IpAddr>,
+    pub search: Vec<String>,
+}
+
+impl Dns {
+    /// split `value` into servers, the entries that are ip addresses, and search domains.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut dns = Self::default();
+        for entry in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            if let Ok(ip) = entry.parse() {
+                dns.servers.push(ip);
+            } else if is_domain(entry) {
+                dns.search.push(entry.to_string());
+            } else {
+                return Err(entry.to_string());
+            }
+        }
+        Ok(dns)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty() && self.search.is_empty()
+    }
+
+    /// the resolv.conf(5) lines for this setting.
+    pub fn resolv_conf(&self) -> String {
+        let mut conf = String::new();
+        for server in &self.servers {
+            let _ = writeln!(conf, "nameserver {server}");
+        }
+        if !self.search.is_empty() {
+            let _ = writeln!(conf, "search {}", self.search.join(" "));
+        }
+        conf
+    }
+}
+
+pub(crate) fn is_domain(s: &str) -> bool {
+    s.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    })
+}
+
+/// Applied records how a `Dns` setting was applied, so that it can be undone by another process.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Applied {
+    /// registered with resolvconf, which also covers systemd-resolved through its resolvconf
+    /// compatible frontend.
+    Resolvconf { record: String },
+    /// written to a resolv.conf directly, `previous` is how it looked before.
+    File { path: PathBuf, previous: Previous },
+}
+
+/// Previous is the state of a resolv.conf before it was replaced.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Previous {
+    /// a regular file, its content was copied to `backup`.
+    File {
+        backup: PathBuf,
+    },
+    /// a symlink, e.g. to the stub file of systemd-resolved.
+    Symlink {
+        target: PathBuf,
+    },
+    Missing,
+}
+
+/// apply `dns` for interface `iface`: through resolvconf if it's installed, by replacing
+/// `RESOLV_CONF` otherwise. `backup` is where the replaced file is copied to.
+pub fn apply(iface: &str, dns: &Dns, backup: &Path) -> anyhow::Result<Applied> {
+    if find_in_path("resolvconf").is_some() {
+        let record = format!("tun.{iface}");
+        resolvconf(&["-a", &record, "-m", "0", "-x"], Some(&dns.resolv_conf()))?;
+        info!("registered dns {:?} with resolvconf as {record}", dns);
+        return Ok(Applied::Resolvconf { record });
+    }
+    apply_file(iface, dns, Path::new(RESOLV_CONF), backup)
+}
+
+/// replace the resolv.conf at `path` with one for `dns`, keeping what it was in `backup`.
+pub fn apply_file(iface: &str, dns: &Dns, path: &Path, backup: &Path) -> anyhow::Result<Applied> {
+    let previous = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_symlink() => Previous::Symlink {
+            target: fs::read_link(path)?,
+        },
+        Ok(_) => {
+            fs::copy(path, backup)
+                .with_context(|| format!("failed to back up {}", path.display()))?;
+            Previous::File {
+                backup: backup.to_path_buf(),
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Previous::Missing,
+        Err(err) => return Err(err).context(format!("failed to read {}", path.display())),
+    };
+
+    // write next to it and rename, which also replaces a symlink rather than writing through it
+    let tmp = path.with_extension("caetun.tmp");
+    let content = format!("# generated by caetun for {iface}\n{}", dns.resolv_conf());
+    fs::write(&tmp, &content)?;
+    match fs::rename(&tmp, path) {
+        Ok(()) => (),
+        // a bind mount, as containers get it, can only be written in place
+        Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {
+            fs::remove_file(&tmp)?;
+            fs::write(path, &content)
+                .with_context(|| format!("failed to write {}", path.display()))?;
+        }
+        Err(err) => return Err(err).context(format!("failed to write {}", path.display())),
+    }
+
+    info!("wrote dns {:?} to {}", dns, path.display());
+    Ok(Applied::File {
+        path: path.to_path_buf(),
+        previous,
+    })
+}
+
+/// undo `apply`.
+pub fn restore(applied: &Applied) -> anyhow::Result<()> {
+    match applied {
+        Applied::Resolvconf { record } => resolvconf(&["-d", record, "-f"], None),
+        Applied::File { path, previous } => {
+            match previous {
+                Previous::File { backup } => {
+                    fs::copy(backup, path)
+                        .with_context(|| format!("failed to restore {}", path.display()))?;
+                    fs::remove_file(backup)?;
+                }
+                Previous::Symlink { target } => {
+                    fs::remove_file(path)?;
+                    std::os::unix::fs::symlink(target, path)?;
+                }
+                Previous::Missing => fs::remove_file(path)?,
+            }
+            info!("restored {}", path.display());
+            Ok(())
+        }
+    }
+}
+
+fn resolvconf(args: &[&str], input: Option<&str>) -> anyhow::Result<()> {
+    let mut child = Command::new("resolvconf")
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("failed to run resolvconf")?;
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("resolvconf {} failed with {status}", args.join(" "));
+    }
+    Ok(())
+}
+
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|bin| bin.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dns() {
+        let dns = Dns::parse("10.8.0.1, fd00:8::1, corp.example, example").unwrap();
+        assert_eq!(
+            dns.servers,
+            vec![IpAddr::from([10, 8, 0, 1]), "fd00:8::1".parse().unwrap()]
+        );
+        assert_eq!(dns.search, vec!["corp.example", "example"]);
+        assert_eq!(
+            dns.resolv_conf(),
+            "nameserver 10.8.0.1\nnameserver fd00:8::1\nsearch corp.example example\n"
+        );
+
+        assert!(Dns::parse("").unwrap().is_empty());
+        assert_eq!(
+            Dns::parse("10.8.0.1, bad_domain").unwrap_err(),
+            "bad_domain"
+        );
+        assert!(Dns::parse("corp..example").is_err());
+    }
+
+    #[test]
+    fn test_apply_and_restore_file() {
+        let dir = std::env::temp_dir().join(format!("caetun-dns-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("resolv.conf");
+        let backup = dir.join("tun0.resolv.conf");
+        let dns = Dns::parse("10.8.0.1, corp.example").unwrap();
+
+        // a regular file
+        fs::write(&path, "nameserver 192.0.2.53\n").unwrap();
+        let applied = apply_file("tun0", &dns, &path, &backup).unwrap();
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .ends_with("nameserver 10.8.0.1\nsearch corp.example\n"));
+        restore(&applied).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "nameserver 192.0.2.53\n"
+        );
+        assert!(!backup.exists());
+
+        // a symlink
+        fs::remove_file(&path).unwrap();
+        std::os::unix::fs::symlink("stub-resolv.conf", &path).unwrap();
+        let applied = apply_file("tun0", &dns, &path, &backup).unwrap();
+        assert!(!fs::symlink_metadata(&path).unwrap().is_symlink());
+        restore(&applied).unwrap();
+        assert_eq!(
+            fs::read_link(&path).unwrap(),
+            PathBuf::from("stub-resolv.conf")
+        );
+
+        // nothing
+        fs::remove_file(&path).unwrap();
+        let applied = apply_file("tun0", &dns, &path, &backup).unwrap();
+        restore(&applied).unwrap();
+        assert!(!path.exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+use crate::conf::{Conf, PeerConf};
+use crate::ini::Document;
+use crate::validate::{self, Severity};
+
+/// add `peer` to the config `source`, after its last section. `path` is the file `source` is in,
+/// which its includes are relative to.
+pub fn add_peer(source: &str, peer: &PeerConf, path: Option<&Path>) -> anyhow::Result<String> {
+    let mut doc = Document::parse(source)?;
+    if doc.section("Peer", Some(("Name", &peer.name))).is_some() {
+        bail!("there is a peer named {} already", peer.name);
+    }
+    doc.push_section("Peer", &peer.entries())?;
+    finish(doc, path)
+}
+
+/// remove the peer named `name` from the config `source`, with the comments right above it.
+pub fn remove_peer(source: &str, name: &str, path: Option<&Path>) -> anyhow::Result<String> {
+    let mut doc = Document::parse(source)?;
+    let section = peer_section(&doc, name)?;
+    doc.remove_section(section);
+    finish(doc, path)
+}
+
+/// set `key` of the interface, or of the peer named `peer`, to `value` in the config `source`,
+/// `None` removes the key.
+pub fn set(
+    source: &str,
+    peer: Option<&str>,
+    key: &str,
+    value: Option<&str>,
+    path: Option<&Path>,
+) -> anyhow::Result<String> {
+    let mut doc = Document::parse(source)?;
+    let section = match peer {
+        Some(name) => peer_section(&doc, name)?,
+        None => doc
+            .section("Interface", None)
+            .context("there is no [Interface]")?,
+    };
+    match value {
+        Some(value) => doc.set(section, key, value)?,
+        None => {
+            if !doc.unset(section, key) {
+                bail!("{key} isn't set");
+            }
+        }
+    }
+    finish(doc, path)
+}
+
+fn peer_section(doc: &Document, name: &str) -> anyhow::Result<usize> {
+    doc.section("Peer", Some(("Name", name)))
+        .with_context(|| format!("there is no peer named {name}"))
+}
+
+/// the edited config, if it's still one `caetun up` accepts.
+fn finish(doc: Document, path: Option<&Path>) -> anyhow::Result<String> {
+    let source = doc.to_string();
+    let conf = match path {
+        Some(path) => Conf::parse_file(&source, path),
+        None => Conf::parse_from(&source),
+    };
+    let conf = conf.context("the edited config would be invalid")?;
+    let errors: Vec<String> = validate::validate(&conf)
+        .into_iter()
+        .filter(|f| f.severity == Severity::Error)
+        .map(|f| f.to_string())
+        .collect();
+    if !errors.is_empty() {
+        bail!(
+            "the edited config would be invalid:\n  {}",
+            errors.join("\n  ")
+        );
+    }
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONF: &str = "# the hub\n\
+        [Interface]\n\
+        Name = server\n\
+        Address = 10.8.0.1/24\n\
+        \n\
+        # laptop\n\
+        [Peer]\n\
+        Name = client1\n\
+        AllowedIPs = 10.8.0.2/32\n";
+
+    #[test]
+    fn test_add_and_remove_peer() {
+        let mut peer = PeerConf::new("client2");
+        peer.allowed_ips = vec![([10, 8, 0, 3].into(), 32)];
+        let added = add_peer(CONF, &peer, None).unwrap();
+        assert_eq!(
+            added,
+            format!("{CONF}\n[Peer]\nName = client2\nAllowedIPs = 10.8.0.3/32\n")
+        );
+        assert_eq!(Conf::parse_from(&added).unwrap().peers[1], peer);
+
+        // the same name, or AllowedIPs another peer has
+        assert!(add_peer(&added, &peer, None).is_err());
+        peer.name = "client3".into();
+        assert!(add_peer(&added, &peer, None).is_err());
+
+        assert_eq!(remove_peer(&added, "client2", None).unwrap(), CONF);
+        assert_eq!(
+            remove_peer(CONF, "client1", None).unwrap(),
+            "# the hub\n[Interface]\nName = server\nAddress = 10.8.0.1/24\n"
+        );
+        assert!(remove_peer(CONF, "client2", None).is_err());
+    }
+
+    #[test]
+    fn test_set() {
+        let conf = set(CONF, None, "MTU", Some("1380"), None).unwrap();
+        assert!(conf.contains("Address = 10.8.0.1/24\nMTU = 1380\n\n# laptop\n"));
+        let conf = set(
+            &conf,
+            Some("client1"),
+            "AllowedIPs",
+            Some("10.8.0.9/32"),
+            None,
+        )
+        .unwrap();
+        assert!(conf.ends_with("AllowedIPs = 10.8.0.9/32\n"));
+        assert_eq!(
+            set(&conf, None, "MTU", None, None).unwrap(),
+            conf.replace("MTU = 1380\n", "")
+        );
+
+        assert!(set(CONF, None, "MTU", Some("20"), None).is_err());
+        assert!(set(CONF, None, "Mtu", Some("1380"), None).is_err());
+        assert!(set(CONF, None, "Name", None, None).is_err());
+        assert!(set(CONF, None, "DNS", None, None).is_err());
+        assert!(set(CONF, Some("client2"), "Batch", Some("true"), None).is_err());
+        // a line break would add lines, a section even, the config doesn't know about
+        let err = set(CONF, None, "PostUp", Some("x\n[Peer]\nName=evil"), None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 5, column 11: a value can't contain a line break"
+        );
+    }
+}
@@ -0,0 +1,370 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Context;
+use serde_json::{Map, Value};
+
+use crate::conf::{Conf, ConfError, ErrorKind};
+use crate::include;
+use crate::ini;
+
+/// Format is a file format configs are read from and written in. INI is the native one, the others
+/// hold the same sections and keys, `Peer` as a list, `Address`, `AllowedIPs` and `DNS` as lists:
+///
+/// ```yaml
+/// Interface:
+///   Name: server
+///   Address: [10.8.0.1/24]
+/// Peer:
+///   - Name: client1
+///     AllowedIPs: [10.8.0.2/32]
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Ini,
+    Json,
+    Toml,
+    Yaml,
+}
+
+/// the keys whose values are comma separated lists in INI.
+const LIST_KEYS: [&str; 3] = ["Address", "AllowedIPs", "DNS"];
+
+impl Format {
+    pub const ALL: [Format; 4] = [Format::Ini, Format::Json, Format::Toml, Format::Yaml];
+
+    /// the format of the file at `path` by its extension, INI unless it's .json, .toml, .yaml or
+    /// .yml.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Format::Json,
+            Some("toml") => Format::Toml,
+            Some("yaml" | "yml") => Format::Yaml,
+            _ => Format::Ini,
+        }
+    }
+
+    /// parse a config in this format, every format goes through the checks of `Conf::parse_from`.
+    /// Includes and substitutions are INI only.
+    pub fn parse(self, source: &str) -> anyhow::Result<Conf> {
+        let value: Value = match self {
+            Format::Ini => return Ok(Conf::parse_from(source)?),
+            Format::Json => serde_json::from_str(source)?,
+            Format::Toml => toml::from_str(source)?,
+            Format::Yaml => serde_yaml_ng::from_str(source)?,
+        };
+        Ok(from_value(&value)?)
+    }
+
+    /// parse `source`, the config in the file at `path`, with its includes relative to the file.
+    pub fn parse_file(self, source: &str, path: &Path) -> anyhow::Result<Conf> {
+        match self {
+            Format::Ini => Ok(Conf::parse_file(source, path)?),
+            format => format.parse(source),
+        }
+    }
+
+    pub fn write(self, conf: &Conf) -> anyhow::Result<String> {
+        Ok(match self {
+            Format::Ini => conf.to_ini(),
+            Format::Json => serde_json::to_string_pretty(&to_value(conf))? + "\n",
+            Format::Toml => toml::to_string(&to_value(conf))?,
+            Format::Yaml => serde_yaml_ng::to_string(&to_value(conf))?,
+        })
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ini" | "conf" => Ok(Format::Ini),
+            "json" => Ok(Format::Json),
+            "toml" => Ok(Format::Toml),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            _ => Err(format!(
+                "unknown format {s}, expected ini, json, toml or yaml"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Ini => "ini",
+            Format::Json => "json",
+            Format::Toml => "toml",
+            Format::Yaml => "yaml",
+        })
+    }
+}
+
+/// read the config at `path`, in `format` or else the format of its extension.
+pub fn load(path: &Path, format: Option<Format>) -> anyhow::Result<Conf> {
+    let source = fs::read_to_string(path)
+        .with_context(|| format!("failed to read config {}", path.display()))?;
+    format
+        .unwrap_or_else(|| Format::from_path(path))
+        .parse_file(&source, path)
+}
+
+/// read the config at `path` like `load`, for writing it back: an INI config with `Include` or
+/// `${...}` is refused rather than resolved.
+pub fn load_plain(path: &Path, format: Option<Format>) -> anyhow::Result<Conf> {
+    let source = fs::read_to_string(path)
+        .with_context(|| format!("failed to read config {}", path.display()))?;
+    match format.unwrap_or_else(|| Format::from_path(path)) {
+        Format::Ini => {
+            let sections = ini::parse(&source).map_err(ConfError::from)?;
+            include::check_unresolved(&sections)?;
+            Ok(Conf::from_sections(sections)?)
+        }
+        format => format.parse(&source),
+    }
+}
+
+/// the config in `value`, a map of sections to maps of keys, or lists of them.
+fn from_value(value: &Value) -> Result<Conf, ConfError> {
+    let Value::Object(map) = value else {
+        return Err(ErrorKind::Syntax("expected a map of sections".to_string()).into());
+    };
+
+    let mut sections = Vec::new();
+    for (name, value) in map {
+        let tables = match value {
+            Value::Array(tables) => tables.iter().collect(),
+            table => vec![table],
+        };
+        for table in tables {
+            let error = |key: Option<&str>, kind| ConfError {
+                kind,
+                file: None,
+                section: Some(name.clone()),
+                key: key.map(str::to_string),
+                position: None,
+            };
+            let Value::Object(table) = table else {
+                return Err(error(
+                    None,
+                    ErrorKind::Syntax("expected a map of keys".into()),
+                ));
+            };
+
+            let mut entries = Vec::new();
+            for (key, value) in table.iter().filter(|(_, value)| !value.is_null()) {
+                let Some(value) = entry_value(value) else {
+                    let kind = ErrorKind::Invalid {
+                        value: value.to_string(),
+                        expected: "a string, number, boolean or a list of them",
+                    };
+                    return Err(error(Some(key), kind));
+                };
+                // a YAML `|` block, say, would make an INI line of each of its lines
+                if value.contains(['\n', '\r']) {
+                    let kind = ErrorKind::Invalid {
+                        value: value.escape_debug().to_string(),
+                        expected: "a value on one line",
+                    };
+                    return Err(error(Some(key), kind));
+                }
+                entries.push(ini::Entry {
+                    key: key.clone(),
+                    value,
+                    line: 0,
+                    key_column: 0,
+                    value_column: 0,
+                });
+            }
+            sections.push(ini::Section {
+                name: name.clone(),
+                line: 0,
+                column: 0,
+                entries,
+                file: None,
+            });
+        }
+    }
+
+    Conf::from_sections(sections)
+}
+
+/// the INI value of `value`, lists are joined with commas.
+fn entry_value(value: &Value) -> Option<String> {
+    let scalar = |value: &Value| match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    };
+    match value {
+        Value::Array(items) => Some(
+            items
+                .iter()
+                .map(scalar)
+                .collect::<Option<Vec<_>>>()?
+                .join(", "),
+        ),
+        value => scalar(value),
+    }
+}
+
+/// the sections and keys of `conf`, what the formats other than INI write.
+pub fn to_value(conf: &Conf) -> Value {
+    let section = |entries: Vec<(&str, String)>| {
+        let map: Map<String, Value> = entries
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), typed(key, value)))
+            .collect();
+        Value::Object(map)
+    };
+
+    let mut map = Map::new();
+    map.insert("Interface".into(), section(conf.interface.entries()));
+    if !conf.peers.is_empty() {
+        let peers = conf.peers.iter().map(|peer| section(peer.entries()));
+        map.insert("Peer".into(), Value::Array(peers.collect()));
+    }
+    Value::Object(map)
+}
+
+/// the INI `value` of `key` as the type it's written with in the other formats: a list, a number,
+/// a boolean or else a string.
+fn typed(key: &str, value: String) -> Value {
+    if LIST_KEYS.contains(&key) {
+        let items = value.split(',').map(str::trim).filter(|s| !s.is_empty());
+        return Value::Array(items.map(|item| Value::String(item.into())).collect());
+    }
+    match value.as_str() {
+        "true" => return Value::Bool(true),
+        "false" => return Value::Bool(false),
+        _ => (),
+    }
+    match value.parse::<u64>() {
+        Ok(n) if n.to_string() == value => Value::from(n),
+        _ => Value::String(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONF: &str = r#"[Interface]
+Name=server
+Address=192.0.2.2/24, fd00:8::1/64
+ListenPort=51820
+ListenAddress=192.0.2.2
+BindInterface=eth0
+MTU=1380
+Table=1000
+FwMark=0xca6c
+PreUp=echo "pre, up"
+PostUp=iptables -A FORWARD -i %i -j ACCEPT
+PreDown=true
+PostDown=iptables -D FORWARD -i %i -j ACCEPT
+DNS=192.0.2.53, corp.example
+ResolveInterval=60
+
+[Peer]
+Name=client1
+Endpoint=vpn.example.com:19988
+
+[Peer]
+Name=1234
+Endpoint=[2001:db8::2]:19988
+AllowedIPs=192.0.2.0/24, fd00:8::2/128
+Batch=true
+"#;
+
+    #[test]
+    fn test_round_trip() {
+        let conf = Conf::parse_from(CONF).unwrap();
+        for format in Format::ALL {
+            let written = format.write(&conf).unwrap();
+            let parsed = format.parse(&written).unwrap();
+            assert_eq!(parsed, conf, "{format}:\n{written}");
+            assert_eq!(format.write(&parsed).unwrap(), written, "{format}");
+            for other in Format::ALL {
+                let converted = other.parse(&other.write(&parsed).unwrap()).unwrap();
+                assert_eq!(converted, conf, "{format} to {other}");
+            }
+        }
+        assert_eq!(Format::Ini.write(&conf).unwrap(), CONF);
+
+        let conf = Conf::parse_from("[Interface]\nName=client\nAddress=\n").unwrap();
+        for format in Format::ALL {
+            let written = format.write(&conf).unwrap();
+            assert_eq!(
+                format.parse(&written).unwrap(),
+                conf,
+                "{format}:\n{written}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let yaml = "Interface:\n  Name: server\n  Address: 10.8.0.1/24\n  ListenPort: 51820\n\
+            Peer:\n  - Name: client1\n    AllowedIPs: [10.8.0.2/32]\n    Batch: true\n";
+        let toml =
+            "[Interface]\nName = \"server\"\nAddress = [\"10.8.0.1/24\"]\nListenPort = \"51820\"\n\
+            [[Peer]]\nName = \"client1\"\nAllowedIPs = \"10.8.0.2/32\"\nBatch = \"true\"\n";
+        let ini = "[Interface]\nName=server\nAddress=10.8.0.1/24\nListenPort=51820\n\
+            [Peer]\nName=client1\nAllowedIPs=10.8.0.2/32\nBatch=true\n";
+        let conf = Conf::parse_from(ini).unwrap();
+        assert_eq!(Format::Yaml.parse(yaml).unwrap(), conf);
+        assert_eq!(Format::Toml.parse(toml).unwrap(), conf);
+
+        // the same checks as INI, without lines
+        for (json, message) in [
+            (
+                r#"{"Interface": {"Name": "a", "Address": [], "Mtu": 1380}}"#,
+                "[Interface]: unknown key Mtu, did you mean MTU?",
+            ),
+            (
+                r#"{"Interface": {"Name": "a", "Address": [], "MTU": 20}}"#,
+                "[Interface] MTU: invalid mtu 20, must be in the range 576-",
+            ),
+            (
+                r#"{"Interface": {"Name": "a", "Address": [], "DNS": {"a": 1}}}"#,
+                r#"[Interface] DNS: invalid value `{"a":1}`, expected a string, number, boolean or a list of them"#,
+            ),
+            (
+                r#"{"Interface": {"Name": "a"}}"#,
+                "[Interface]: missing key Address",
+            ),
+            (
+                r#"{"Peers": [{"Name": "a"}]}"#,
+                "unknown section [Peers], did you mean Peer?",
+            ),
+            (r#"[]"#, "expected a map of sections"),
+        ] {
+            let err = Format::Json.parse(json).unwrap_err().to_string();
+            assert!(err.starts_with(message), "{err}");
+        }
+        assert!(Format::Yaml.parse("Interface: [").is_err());
+
+        let yaml =
+            "Interface:\n  Name: server\n  Address: [10.8.0.1/24]\n  PostUp: |\n    true\n    \
+            [Peer]\n";
+        let err = Format::Yaml.parse(yaml).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            r"[Interface] PostUp: invalid value `true\n[Peer]\n`, expected a value on one line"
+        );
+    }
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(Format::from_path(Path::new("tun0.conf")), Format::Ini);
+        assert_eq!(Format::from_path(Path::new("tun0")), Format::Ini);
+        assert_eq!(Format::from_path(Path::new("a/tun0.yml")), Format::Yaml);
+        assert_eq!(Format::from_path(Path::new("tun0.toml")), Format::Toml);
+        assert_eq!("yaml".parse::<Format>(), Ok(Format::Yaml));
+        assert!("xml".parse::<Format>().is_err());
+    }
+}
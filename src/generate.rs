@@ -0,0 +1,354 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use crate::conf::{Conf, Endpoint, InterfaceConf, PeerConf};
+use crate::validate::Net;
+
+/// Topology describes a hub and spoke network, `caetun-conf generate` writes the config of every
+/// node of it. It's read from JSON:
+///
+/// ```json
+/// {
+///   "hub": { "name": "server", "endpoint": "vpn.example.com:19988", "subnet": "10.8.0.0/24" },
+///   "clients": [{ "name": "client1" }, { "name": "client2", "routes": ["192.168.0.0/16"] }]
+/// }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Topology {
+    pub hub: Hub,
+    #[serde(default)]
+    pub clients: Vec<Client>,
+}
+
+/// Hub is the node every client connects to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hub {
+    pub name: String,
+    /// where the clients reach the hub, its port is the listen port of the hub.
+    pub endpoint: String,
+    /// the tunnel network the addresses of the nodes are allocated in.
+    pub subnet: String,
+    /// the address of the hub, the first one of the subnet when it's not set.
+    #[serde(default)]
+    pub address: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Client {
+    pub name: String,
+    /// the address of the client, allocated when it's not set.
+    #[serde(default)]
+    pub address: Option<IpAddr>,
+    /// networks besides the subnet the client routes to the hub, `0.0.0.0/0` for everything.
+    #[serde(default)]
+    pub routes: Vec<String>,
+}
+
+/// generate the configs of `topology`, the hub's first. `existing` are the configs a previous run
+/// wrote, by interface name: the nodes keep their addresses and the settings the topology doesn't
+/// cover, new clients get the lowest free addresses.
+pub fn generate(
+    topology: &Topology,
+    existing: &HashMap<String, Conf>,
+) -> anyhow::Result<Vec<Conf>> {
+    let hub = &topology.hub;
+    let endpoint: Endpoint = hub
+        .endpoint
+        .parse()
+        .with_context(|| format!("invalid hub endpoint {}", hub.endpoint))?;
+    let listen_port = match &endpoint {
+        Endpoint::Addr(addr) => addr.port(),
+        Endpoint::Host(host) => host.port,
+    };
+    let subnet = ip_network::IpNetwork::from_str_truncate(&hub.subnet)
+        .with_context(|| format!("invalid subnet {}", hub.subnet))?;
+    let subnet = Net::new(subnet.network_address(), subnet.netmask());
+
+    let mut names = HashSet::new();
+    let nodes: Vec<(&str, Option<IpAddr>)> = std::iter::once((hub.name.as_str(), hub.address))
+        .chain(
+            topology
+                .clients
+                .iter()
+                .map(|c| (c.name.as_str(), c.address)),
+        )
+        .collect();
+    for &(name, _) in &nodes {
+        if !names.insert(name) {
+            bail!("{name} is in the topology more than once");
+        }
+    }
+
+    let addresses = allocate(&nodes, subnet, |name| {
+        previous_address(hub, name, subnet, existing)
+    })?;
+
+    let interface = |name: &str| {
+        let mut interface = existing
+            .get(name)
+            .map_or_else(|| InterfaceConf::new(name), |conf| conf.interface.clone());
+        interface.address.retain(|&(ip, _)| !subnet.contains(ip));
+        interface
+            .address
+            .insert(0, (addresses[name], subnet.prefix()));
+        interface
+    };
+
+    let hub_conf = existing.get(&hub.name);
+    let mut hub_interface = interface(&hub.name);
+    hub_interface.listen_port = listen_port;
+    let mut confs = vec![Conf {
+        interface: hub_interface,
+        peers: topology
+            .clients
+            .iter()
+            .map(|client| {
+                let previous = hub_conf.and_then(|conf| peer(conf, &client.name));
+                let mut peer = previous
+                    .cloned()
+                    .unwrap_or_else(|| PeerConf::new(&client.name));
+                // the client's address is replaced, routes behind it that were added by hand stay
+                peer.allowed_ips.retain(|&(ip, _)| !subnet.contains(ip));
+                let ip = addresses[client.name.as_str()];
+                peer.allowed_ips.insert(0, (ip, host_prefix(ip)));
+                peer
+            })
+            .collect(),
+    }];
+
+    for client in &topology.clients {
+        let mut allowed_ips = vec![(
+            subnet.nth(0).expect("0 is in every network"),
+            subnet.prefix(),
+        )];
+        for route in &client.routes {
+            let route = ip_network::IpNetwork::from_str_truncate(route)
+                .with_context(|| format!("invalid route {route} of {}", client.name))?;
+            allowed_ips.push((route.network_address(), route.netmask()));
+        }
+
+        let previous = existing.get(&client.name);
+        let mut hub_peer = previous
+            .and_then(|conf| peer(conf, &hub.name))
+            .cloned()
+            .unwrap_or_else(|| PeerConf::new(&hub.name));
+        hub_peer.endpoint = Some(endpoint.clone());
+        hub_peer.allowed_ips = allowed_ips;
+
+        let mut peers = vec![hub_peer];
+        if let Some(previous) = previous {
+            peers.extend(
+                previous
+                    .peers
+                    .iter()
+                    .filter(|p| p.name != hub.name)
+                    .cloned(),
+            );
+        }
+        confs.push(Conf {
+            interface: interface(&client.name),
+            peers,
+        });
+    }
+
+    Ok(confs)
+}
+
+/// the addresses of `nodes` in `subnet`: the one a node is given, else the one it had before,
+/// else the lowest free one.
+fn allocate<'a>(
+    nodes: &[(&'a str, Option<IpAddr>)],
+    subnet: Net,
+    previous: impl Fn(&str) -> Option<IpAddr>,
+) -> anyhow::Result<HashMap<&'a str, IpAddr>> {
+    let mut addresses = HashMap::new();
+    let mut used = HashSet::new();
+
+    for &(name, address) in nodes {
+        let Some(ip) = address else { continue };
+        if !subnet.contains(ip) {
+            bail!("address {ip} of {name} is outside the subnet {subnet}");
+        }
+        if !used.insert(ip) {
+            bail!("address {ip} of {name} is given to another node too");
+        }
+        addresses.insert(name, ip);
+    }
+
+    for &(name, _) in nodes {
+        if addresses.contains_key(name) {
+            continue;
+        }
+        let previous = previous(name).filter(|ip| !used.contains(ip));
+        if let Some(ip) = previous {
+            used.insert(ip);
+            addresses.insert(name, ip);
+        }
+    }
+
+    // neither the network address nor the last one, the broadcast address of an IPv4 subnet
+    let mut free = (1..).map_while(|n| subnet.nth(n + 1).and(subnet.nth(n)));
+    for &(name, _) in nodes {
+        if addresses.contains_key(name) {
+            continue;
+        }
+        let Some(ip) = free.find(|ip| !used.contains(ip)) else {
+            bail!("the subnet {subnet} has no address left for {name}");
+        };
+        used.insert(ip);
+        addresses.insert(name, ip);
+    }
+
+    Ok(addresses)
+}
+
+/// the address `name` had in the configs of a previous run: the one the hub allows for it, or the
+/// address of its own interface.
+fn previous_address(
+    hub: &Hub,
+    name: &str,
+    subnet: Net,
+    existing: &HashMap<String, Conf>,
+) -> Option<IpAddr> {
+    let host_route = |&(ip, prefix): &(IpAddr, u8)| {
+        (subnet.contains(ip) && host_prefix(ip) == prefix).then_some(ip)
+    };
+    let from_hub = existing
+        .get(&hub.name)
+        .filter(|_| name != hub.name)
+        .and_then(|conf| peer(conf, name))
+        .and_then(|peer| peer.allowed_ips.iter().find_map(host_route));
+    from_hub.or_else(|| {
+        let interface = &existing.get(name)?.interface;
+        interface
+            .address
+            .iter()
+            .map(|&(ip, _)| ip)
+            .find(|&ip| subnet.contains(ip))
+    })
+}
+
+fn peer<'a>(conf: &'a Conf, name: &str) -> Option<&'a PeerConf> {
+    conf.peers.iter().find(|peer| peer.name == name)
+}
+
+/// the prefix length of a route to `ip` alone.
+fn host_prefix(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topology(json: &str) -> Topology {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn by_name(confs: Vec<Conf>) -> HashMap<String, Conf> {
+        confs
+            .into_iter()
+            .map(|conf| (conf.interface.name.clone(), conf))
+            .collect()
+    }
+
+    #[test]
+    fn test_generate() {
+        let spec = topology(
+            r#"{
+                "hub": { "name": "server", "endpoint": "172.18.0.22:19988", "subnet": "10.8.0.0/24" },
+                "clients": [
+                    { "name": "client1" },
+                    { "name": "client2", "routes": ["0.0.0.0/0"] },
+                    { "name": "macos", "address": "10.8.0.4" }
+                ]
+            }"#,
+        );
+        let confs = generate(&spec, &HashMap::new()).unwrap();
+        assert_eq!(
+            confs[0].to_ini(),
+            "[Interface]\nName=server\nAddress=10.8.0.1/24\n\n\
+            [Peer]\nName=client1\nAllowedIPs=10.8.0.2/32\n\n\
+            [Peer]\nName=client2\nAllowedIPs=10.8.0.3/32\n\n\
+            [Peer]\nName=macos\nAllowedIPs=10.8.0.4/32\n"
+        );
+        assert_eq!(
+            confs[2].to_ini(),
+            "[Interface]\nName=client2\nAddress=10.8.0.3/24\n\n\
+            [Peer]\nName=server\nEndpoint=172.18.0.22:19988\nAllowedIPs=10.8.0.0/24, 0.0.0.0/0\n"
+        );
+
+        let named: Vec<(String, Conf)> = confs
+            .iter()
+            .map(|conf| (conf.interface.name.clone(), conf.clone()))
+            .collect();
+        assert!(crate::validate::validate_network(&named)
+            .iter()
+            .all(|f| f.finding.severity != crate::validate::Severity::Error));
+    }
+
+    #[test]
+    fn test_generate_again() {
+        let spec = topology(
+            r#"{
+                "hub": { "name": "server", "endpoint": "vpn.example.com:51820", "subnet": "fd00:8::/64" },
+                "clients": [{ "name": "a" }, { "name": "b" }, { "name": "c" }]
+            }"#,
+        );
+        let mut existing = by_name(generate(&spec, &HashMap::new()).unwrap());
+        existing.get_mut("server").unwrap().interface.mtu = Some(1380);
+        existing.get_mut("b").unwrap().peers[0].batch = true;
+
+        // a leaves, d joins and takes the address a had, b and c keep theirs
+        let spec = topology(
+            r#"{
+                "hub": { "name": "server", "endpoint": "vpn.example.com:51820", "subnet": "fd00:8::/64" },
+                "clients": [{ "name": "c" }, { "name": "b" }, { "name": "d" }]
+            }"#,
+        );
+        let confs = by_name(generate(&spec, &existing).unwrap());
+        let address = |name: &str| confs[name].interface.address[0].0.to_string();
+        assert_eq!(address("server"), "fd00:8::1");
+        assert_eq!(address("b"), "fd00:8::3");
+        assert_eq!(address("c"), "fd00:8::4");
+        assert_eq!(address("d"), "fd00:8::2");
+        assert_eq!(confs["server"].interface.listen_port, 51820);
+        assert_eq!(confs["server"].interface.mtu, Some(1380));
+        assert!(confs["b"].peers[0].batch);
+        let peers: Vec<&str> = confs["server"]
+            .peers
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        assert_eq!(peers, ["c", "b", "d"]);
+    }
+
+    #[test]
+    fn test_generate_errors() {
+        for json in [
+            r#"{ "hub": { "name": "s", "endpoint": "vpn", "subnet": "10.8.0.0/24" } }"#,
+            r#"{ "hub": { "name": "s", "endpoint": "vpn:1", "subnet": "10.8.0.0" } }"#,
+            r#"{ "hub": { "name": "s", "endpoint": "vpn:1", "subnet": "10.8.0.0/24" }, "clients": [{ "name": "s" }] }"#,
+            r#"{ "hub": { "name": "s", "endpoint": "vpn:1", "subnet": "10.8.0.0/24" }, "clients": [{ "name": "a", "address": "10.9.0.1" }] }"#,
+            r#"{ "hub": { "name": "s", "endpoint": "vpn:1", "subnet": "10.8.0.0/30" }, "clients": [{ "name": "a" }, { "name": "b" }] }"#,
+        ] {
+            assert!(
+                generate(&topology(json), &HashMap::new()).is_err(),
+                "{json}"
+            );
+        }
+        assert!(serde_json::from_str::<Topology>(
+            r#"{ "hub": { "name": "s", "endpoint": "vpn:1", "subnet": "10.8.0.0/24", "port": 1 } }"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,290 @@
+use std::fmt::Write as _;
+
+use crate::conf::Conf;
+use crate::validate::{Finding, NetworkFinding, Severity};
+
+/// Graph is the interfaces of a network and who they have as peers, with the findings of
+/// `validate_network` on the interfaces and peers they're about.
+#[derive(Debug)]
+pub struct Graph<'a> {
+    nodes: Vec<Node<'a>>,
+    edges: Vec<Edge<'a>>,
+}
+
+/// Node is the interface of a config, or a peer none of the configs is for.
+#[derive(Debug)]
+struct Node<'a> {
+    name: String,
+    addresses: Vec<String>,
+    known: bool,
+    findings: Vec<&'a Finding>,
+}
+
+/// Edge is a peer of an interface, what it routes to the peer and where it sends it.
+#[derive(Debug)]
+struct Edge<'a> {
+    from: usize,
+    to: usize,
+    peer: String,
+    allowed_ips: Vec<String>,
+    endpoint: Option<String>,
+    findings: Vec<&'a Finding>,
+}
+
+impl<'a> Graph<'a> {
+    /// the graph of `confs`, named like in `validate_network` and with the `findings` it reported
+    /// on them.
+    pub fn new(confs: &[(String, Conf)], findings: &'a [NetworkFinding]) -> Self {
+        let mut nodes: Vec<Node> = confs
+            .iter()
+            .map(|(_, conf)| Node {
+                name: conf.interface.name.clone(),
+                addresses: cidrs(&conf.interface.address),
+                known: true,
+                findings: Vec::new(),
+            })
+            .collect();
+
+        let mut edges = Vec::new();
+        for (from, (_, conf)) in confs.iter().enumerate() {
+            for peer in &conf.peers {
+                let to = match nodes.iter().position(|node| node.name == peer.name) {
+                    Some(to) => to,
+                    None => {
+                        nodes.push(Node {
+                            name: peer.name.clone(),
+                            addresses: Vec::new(),
+                            known: false,
+                            findings: Vec::new(),
+                        });
+                        nodes.len() - 1
+                    }
+                };
+                edges.push(Edge {
+                    from,
+                    to,
+                    peer: peer.name.clone(),
+                    allowed_ips: cidrs(&peer.allowed_ips),
+                    endpoint: peer.endpoint.as_ref().map(ToString::to_string),
+                    findings: Vec::new(),
+                });
+            }
+        }
+
+        for NetworkFinding { conf, finding } in findings {
+            let Some(from) = confs.iter().position(|(name, _)| name == conf) else {
+                continue;
+            };
+            let mut peers = edges
+                .iter_mut()
+                .filter(|edge| edge.from == from && Some(&edge.peer) == finding.peer.as_ref())
+                .peekable();
+            if peers.peek().is_none() {
+                nodes[from].findings.push(finding);
+            }
+            for edge in peers {
+                edge.findings.push(finding);
+            }
+        }
+
+        Self { nodes, edges }
+    }
+
+    /// the graph in the DOT language of Graphviz. Interfaces and peers with findings are drawn in
+    /// the color of their worst one, with the checks in their label and the messages in a tooltip.
+    pub fn dot(&self) -> String {
+        let mut out = String::new();
+        out.push_str("digraph caetun {\n    rankdir=LR;\n    node [shape=box];\n");
+        for (i, node) in self.nodes.iter().enumerate() {
+            let mut label = vec![node.name.clone()];
+            label.extend(node.addresses.iter().cloned());
+            let mut attrs = highlight(&mut label, &node.findings);
+            if !node.known {
+                attrs.push_str(", style=dashed");
+            }
+            writeln!(out, "    n{i} [label={}{attrs}];", dot_string(&label)).unwrap();
+        }
+        for edge in &self.edges {
+            let mut label = edge.allowed_ips.clone();
+            label.extend(edge.endpoint.iter().cloned());
+            let attrs = highlight(&mut label, &edge.findings);
+            writeln!(
+                out,
+                "    n{} -> n{} [label={}{attrs}];",
+                edge.from,
+                edge.to,
+                dot_string(&label)
+            )
+            .unwrap();
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// the graph as a Mermaid flowchart, highlighted like `dot` does without the tooltips.
+    pub fn mermaid(&self) -> String {
+        let mut out = String::from("graph LR\n");
+        let mut styles = String::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let mut label = vec![node.name.clone()];
+            label.extend(node.addresses.iter().cloned());
+            let severity = severity(&node.findings);
+            checks(&mut label, &node.findings);
+            let (open, close) = if node.known { ("[", "]") } else { ("([", "])") };
+            writeln!(out, "    n{i}{open}{}{close}", mermaid_string(&label)).unwrap();
+            if let Some(severity) = severity {
+                writeln!(styles, "    style n{i} stroke:{}", color(severity)).unwrap();
+            }
+        }
+        for (i, edge) in self.edges.iter().enumerate() {
+            let mut label = edge.allowed_ips.clone();
+            label.extend(edge.endpoint.iter().cloned());
+            let severity = severity(&edge.findings);
+            checks(&mut label, &edge.findings);
+            let arrow = match label.is_empty() {
+                true => String::new(),
+                false => format!("|{}|", mermaid_string(&label)),
+            };
+            writeln!(out, "    n{} -->{arrow} n{}", edge.from, edge.to).unwrap();
+            if let Some(severity) = severity {
+                let color = color(severity);
+                writeln!(styles, "    linkStyle {i} stroke:{color},color:{color}").unwrap();
+            }
+        }
+        out + &styles
+    }
+}
+
+fn cidrs(cidrs: &[(std::net::IpAddr, u8)]) -> Vec<String> {
+    cidrs
+        .iter()
+        .map(|(ip, prefix)| format!("{ip}/{prefix}"))
+        .collect()
+}
+
+fn severity(findings: &[&Finding]) -> Option<Severity> {
+    findings.iter().map(|finding| finding.severity).max()
+}
+
+fn color(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Error => "red",
+        Severity::Warning => "orange",
+    }
+}
+
+/// add the checks of `findings` to `label`, once each.
+fn checks(label: &mut Vec<String>, findings: &[&Finding]) {
+    let mut checks: Vec<&str> = Vec::new();
+    for finding in findings {
+        if !checks.contains(&finding.check) {
+            checks.push(finding.check);
+        }
+    }
+    if !checks.is_empty() {
+        label.push(format!("! {}", checks.join(", ")));
+    }
+}
+
+/// the DOT attributes that highlight `findings`, after adding their checks to `label`.
+fn highlight(label: &mut Vec<String>, findings: &[&Finding]) -> String {
+    let Some(severity) = severity(findings) else {
+        return String::new();
+    };
+    checks(label, findings);
+    let tooltip: Vec<String> = findings.iter().map(ToString::to_string).collect();
+    let color = color(severity);
+    format!(
+        ", color={color}, fontcolor={color}, tooltip={}",
+        dot_string(&tooltip)
+    )
+}
+
+/// `lines` as a quoted DOT string.
+fn dot_string(lines: &[String]) -> String {
+    let escaped: Vec<String> = lines
+        .iter()
+        .map(|line| line.replace('\\', "\\\\").replace('"', "\\\""))
+        .collect();
+    format!("\"{}\"", escaped.join("\\n"))
+}
+
+/// `lines` as a quoted Mermaid label.
+fn mermaid_string(lines: &[String]) -> String {
+    let escaped: Vec<String> = lines
+        .iter()
+        .map(|line| {
+            line.replace('&', "#amp;")
+                .replace('"', "#quot;")
+                .replace('<', "#lt;")
+                .replace('>', "#gt;")
+        })
+        .collect();
+    format!("\"{}\"", escaped.join("<br/>"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate;
+
+    fn confs() -> Vec<(String, Conf)> {
+        [
+            (
+                "server.conf",
+                "[Interface]\nName=server\nAddress=10.8.0.1/24\n\
+                 [Peer]\nName=client1\nAllowedIPs=10.8.0.2/32\n\
+                 [Peer]\nName=laptop\nAllowedIPs=10.8.0.9/32\n",
+            ),
+            (
+                "client1.conf",
+                "[Interface]\nName=client1\nAddress=10.8.0.2/24\n\
+                 [Peer]\nName=server\nEndpoint=192.0.2.1:51820\nAllowedIPs=10.8.0.0/24\n",
+            ),
+        ]
+        .into_iter()
+        .map(|(file, ini)| (file.to_string(), Conf::parse_from(ini).unwrap()))
+        .collect()
+    }
+
+    #[test]
+    fn test_dot() {
+        let confs = confs();
+        let findings = validate::validate_network(&confs);
+        let dot = Graph::new(&confs, &findings).dot();
+        assert_eq!(
+            dot,
+            r#"digraph caetun {
+    rankdir=LR;
+    node [shape=box];
+    n0 [label="server\n10.8.0.1/24"];
+    n1 [label="client1\n10.8.0.2/24"];
+    n2 [label="laptop", style=dashed];
+    n0 -> n1 [label="10.8.0.2/32"];
+    n0 -> n2 [label="10.8.0.9/32\n! unknown-peer", color=orange, fontcolor=orange, tooltip="warning[unknown-peer]: peer laptop: none of the configs is for this peer"];
+    n1 -> n0 [label="10.8.0.0/24\n192.0.2.1:51820\n! endpoint-port", color=red, fontcolor=red, tooltip="error[endpoint-port]: peer server: Endpoint port 51820 isn't the ListenPort 19988 in server.conf"];
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_mermaid() {
+        let confs = confs();
+        let findings = validate::validate_network(&confs);
+        let mermaid = Graph::new(&confs, &findings).mermaid();
+        assert_eq!(
+            mermaid,
+            r#"graph LR
+    n0["server<br/>10.8.0.1/24"]
+    n1["client1<br/>10.8.0.2/24"]
+    n2(["laptop"])
+    n0 -->|"10.8.0.2/32"| n1
+    n0 -->|"10.8.0.9/32<br/>! unknown-peer"| n2
+    n1 -->|"10.8.0.0/24<br/>192.0.2.1:51820<br/>! endpoint-port"| n0
+    linkStyle 1 stroke:orange,color:orange
+    linkStyle 2 stroke:red,color:red
+"#
+        );
+    }
+}
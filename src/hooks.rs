@@ -0,0 +1,238 @@
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::info;
+
+/// how long a hook may run before it's killed.
+pub const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// how long the output of a hook is still read once its shell exited. A job it left in the
+/// background keeps the pipes open, what that writes later is only logged.
+const OUTPUT_GRACE: Duration = Duration::from_millis(100);
+
+/// the `[Interface]` keys of the hooks.
+pub const KEYS: &[&str] = &["PreUp", "PostUp", "PreDown", "PostDown"];
+
+#[derive(Error, Debug)]
+pub enum HookError {
+    #[error("{stage} `{command}`: {source}")]
+    Spawn {
+        stage: Stage,
+        command: String,
+        source: io::Error,
+    },
+
+    #[error("{stage} `{command}` failed with {status}: {output}")]
+    Failed {
+        stage: Stage,
+        command: String,
+        status: ExitStatus,
+        output: String,
+    },
+
+    #[error("{stage} `{command}` did not finish within {timeout:?}: {output}")]
+    Timeout {
+        stage: Stage,
+        command: String,
+        timeout: Duration,
+        output: String,
+    },
+}
+
+/// Stage is the point in the lifecycle of an interface a hook runs at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Stage {
+    PreUp,
+    PostUp,
+    PreDown,
+    PostDown,
+}
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Hooks are the shell commands of an interface, keyed like their `[Interface]` keys.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Hooks {
+    pub pre_up: Option<String>,
+    pub post_up: Option<String>,
+    pub pre_down: Option<String>,
+    pub post_down: Option<String>,
+}
+
+impl Hooks {
+    /// run the hook of `stage` for interface `iface`, if there is one.
+    pub fn run(&self, stage: Stage, iface: &str) -> Result<(), HookError> {
+        let command = match stage {
+            Stage::PreUp => &self.pre_up,
+            Stage::PostUp => &self.post_up,
+            Stage::PreDown => &self.pre_down,
+            Stage::PostDown => &self.post_down,
+        };
+        match command {
+            Some(command) => run(stage, command, iface, HOOK_TIMEOUT),
+            None => Ok(()),
+        }
+    }
+}
+
+/// run `command` with `sh -c`, `%i` replaced by the interface name.
+///
+/// stdout and stderr are captured and logged line by line, a command that fails or runs for
+/// longer than `timeout` is an error that carries its output. Jobs it starts in the background
+/// are left running.
+pub fn run(stage: Stage, command: &str, iface: &str, timeout: Duration) -> Result<(), HookError> {
+    let command = command.replace("%i", iface);
+    info!("{stage}: {command}");
+
+    let spawn_err = |source| HookError::Spawn {
+        stage,
+        command: command.clone(),
+        source,
+    };
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // its own process group, so that a timeout kills whatever the shell started as well
+        .process_group(0)
+        .spawn()
+        .map_err(spawn_err)?;
+
+    // drain both pipes while waiting, a chatty command would block on a full pipe otherwise
+    let (done_tx, done_rx) = mpsc::channel();
+    let stdout = child
+        .stdout
+        .take()
+        .map(|pipe| capture(stage, pipe, done_tx.clone()));
+    let stderr = child
+        .stderr
+        .take()
+        .map(|pipe| capture(stage, pipe, done_tx.clone()));
+    drop(done_tx);
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(spawn_err)? {
+            break Some(status);
+        }
+        if start.elapsed() > timeout {
+            unsafe { libc::kill(-(child.id() as i32), libc::SIGKILL) };
+            let _ = child.wait();
+            break None;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    // until both pipes are closed, or the grace period is over
+    let deadline = Instant::now() + OUTPUT_GRACE;
+    while done_rx
+        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        .is_ok()
+    {}
+
+    let mut output = String::new();
+    for captured in [stdout, stderr].into_iter().flatten() {
+        output += &captured.lock();
+    }
+    let output = output.trim().to_string();
+
+    match status {
+        Some(status) if status.success() => Ok(()),
+        Some(status) => Err(HookError::Failed {
+            stage,
+            command,
+            status,
+            output,
+        }),
+        None => Err(HookError::Timeout {
+            stage,
+            command,
+            timeout,
+            output,
+        }),
+    }
+}
+
+/// read `pipe` to its end in a thread, logging it line by line, and signal `done` then.
+fn capture(
+    stage: Stage,
+    pipe: impl Read + Send + 'static,
+    done: mpsc::Sender<()>,
+) -> Arc<Mutex<String>> {
+    let captured = Arc::new(Mutex::new(String::new()));
+    let output = captured.clone();
+    thread::spawn(move || {
+        let mut pipe = BufReader::new(pipe);
+        let mut buf = Vec::new();
+        while matches!(pipe.read_until(b'\n', &mut buf), Ok(n) if n > 0) {
+            let line = String::from_utf8_lossy(&buf);
+            info!("{stage}: {}", line.trim_end());
+            output.lock().push_str(&line);
+            buf.clear();
+        }
+        let _ = done.send(());
+    });
+    captured
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_hook() {
+        let timeout = Duration::from_secs(5);
+        assert!(run(Stage::PostUp, "test %i = tun7", "tun7", timeout).is_ok());
+
+        match run(
+            Stage::PreUp,
+            "echo out; echo err >&2; exit 3",
+            "tun7",
+            timeout,
+        ) {
+            Err(HookError::Failed { status, output, .. }) => {
+                assert_eq!(status.code(), Some(3));
+                assert_eq!(output, "out\nerr");
+            }
+            res => panic!("unexpected {res:?}"),
+        }
+
+        let res = run(Stage::PreUp, "sleep 5", "tun7", Duration::from_millis(100));
+        assert!(matches!(res, Err(HookError::Timeout { .. })));
+
+        // a job left in the background holds the pipes, but not the hook
+        let start = Instant::now();
+        match run(Stage::PostUp, "echo out; sleep 3 & exit 4", "tun7", timeout) {
+            Err(HookError::Failed { status, output, .. }) => {
+                assert_eq!(status.code(), Some(4));
+                assert_eq!(output, "out");
+            }
+            res => panic!("unexpected {res:?}"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_hooks_run_stage() {
+        let hooks = Hooks {
+            post_down: Some("false".into()),
+            ..Default::default()
+        };
+        assert!(hooks.run(Stage::PreUp, "tun7").is_ok());
+        assert!(hooks.run(Stage::PostDown, "tun7").is_err());
+    }
+}
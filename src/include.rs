@@ -0,0 +1,363 @@
+use std::env::VarError;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::conf::{self, ConfError, ErrorKind};
+use crate::hooks;
+use crate::ini;
+
+/// the environment variable of a name, `std::env::var` outside of tests.
+type Env<'a> = &'a dyn Fn(&str) -> Result<String, VarError>;
+
+/// resolve the `Include` entries of `sections`, the config in the file at `path` or in no file,
+/// and substitute `${...}` in the values of the others:
+///
+/// - `Include = peers.d/*.conf` adds the sections of every file the path or glob pattern names,
+///   in the order of their names, right after the section it's in. Paths are relative to the
+///   directory of the file, several are separated by commas. Including a file that is already
+///   being included is a cycle, and an error.
+/// - `${NAME}` is the environment variable `NAME`, which must be set.
+/// - `${file:secret.txt}` is the contents of a file without its trailing newlines, relative like
+///   includes are.
+/// - `$${` is a literal `${`. What is substituted isn't substituted again.
+///
+/// The hooks are left as they are, `${...}` in them is the shell's. Errors in included files name
+/// them.
+pub fn resolve(
+    sections: Vec<ini::Section>,
+    path: Option<&Path>,
+) -> Result<Vec<ini::Section>, ConfError> {
+    resolve_with(sections, path, &|name| std::env::var(name))
+}
+
+/// resolve `sections` like `resolve`, with the environment variables of `env`.
+fn resolve_with(
+    sections: Vec<ini::Section>,
+    path: Option<&Path>,
+    env: Env,
+) -> Result<Vec<ini::Section>, ConfError> {
+    let mut stack = Vec::new();
+    if let Some(path) = path {
+        stack.push(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+    }
+    let dir = path.and_then(Path::parent).unwrap_or(Path::new(""));
+    let mut resolved = Vec::new();
+    resolve_into(sections, dir, env, &mut stack, &mut resolved)?;
+    Ok(resolved)
+}
+
+/// an error at the first entry of `sections` that `resolve` would change, an `Include` or a value
+/// with `${...}` in it, for a config that is about to be written back: it would have the included
+/// peers inlined and the values substituted for good.
+pub fn check_unresolved(sections: &[ini::Section]) -> Result<(), ConfError> {
+    for section in sections {
+        for entry in &section.entries {
+            let column = match entry.value.find("${") {
+                _ if entry.key == "Include" => entry.value_column,
+                _ if hooks::KEYS.contains(&entry.key.as_str()) => continue,
+                Some(offset) => entry.value_column + entry.value[..offset].chars().count(),
+                None => continue,
+            };
+            return Err(ConfError {
+                kind: ErrorKind::Unresolved,
+                file: section.file.clone(),
+                section: Some(section.name.clone()),
+                key: Some(entry.key.clone()),
+                position: conf::position(entry.line, column),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// resolve `sections`, of a file in `dir`, into `resolved`. `stack` is the files being included,
+/// canonical.
+fn resolve_into(
+    sections: Vec<ini::Section>,
+    dir: &Path,
+    env: Env,
+    stack: &mut Vec<PathBuf>,
+    resolved: &mut Vec<ini::Section>,
+) -> Result<(), ConfError> {
+    for mut section in sections {
+        let (file, name) = (section.file.clone(), section.name.clone());
+        let error = |entry: &ini::Entry, column: usize, kind: ErrorKind| ConfError {
+            kind,
+            file: file.clone(),
+            section: Some(name.clone()),
+            key: Some(entry.key.clone()),
+            position: conf::position(entry.line, column),
+        };
+
+        let mut includes = Vec::new();
+        for mut entry in std::mem::take(&mut section.entries) {
+            if hooks::KEYS.contains(&entry.key.as_str()) {
+                section.entries.push(entry);
+                continue;
+            }
+            match substitute(&entry.value, dir, env) {
+                Ok(value) => entry.value = value,
+                Err((offset, kind)) => {
+                    let column = entry.value_column + entry.value[..offset].chars().count();
+                    return Err(error(&entry, column, kind));
+                }
+            }
+            match entry.key == "Include" {
+                true => includes.push(entry),
+                false => section.entries.push(entry),
+            }
+        }
+
+        let mut files = Vec::new();
+        for entry in &includes {
+            for pattern in entry.value.split(',').map(str::trim) {
+                let paths =
+                    paths(dir, pattern).map_err(|kind| error(entry, entry.value_column, kind))?;
+                files.extend(paths.into_iter().map(|path| (entry, path)));
+            }
+        }
+
+        resolved.push(section);
+        for (entry, path) in files {
+            let include = |kind| error(entry, entry.value_column, kind);
+            let source = fs::read_to_string(&path)
+                .map_err(|err| include(ErrorKind::Read(format!("{}: {err}", path.display()))))?;
+            let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+            if let Some(first) = stack.iter().position(|file| *file == canonical) {
+                let cycle: Vec<String> = stack[first..]
+                    .iter()
+                    .chain([&canonical])
+                    .map(|file| file.display().to_string())
+                    .collect();
+                return Err(include(ErrorKind::IncludeCycle(cycle.join(" -> "))));
+            }
+
+            let file = Arc::new(path.clone());
+            let mut sections = ini::parse(&source).map_err(|err| ConfError {
+                file: Some(file.clone()),
+                ..err.into()
+            })?;
+            for section in &mut sections {
+                section.file = Some(file.clone());
+            }
+            stack.push(canonical);
+            let dir = path.parent().unwrap_or(Path::new(""));
+            resolve_into(sections, dir, env, stack, resolved)?;
+            stack.pop();
+        }
+    }
+    Ok(())
+}
+
+/// the files `pattern` names in `dir`: the one of a path, the existing ones of a glob pattern.
+fn paths(dir: &Path, pattern: &str) -> Result<Vec<PathBuf>, ErrorKind> {
+    let invalid = || ErrorKind::Invalid {
+        value: pattern.to_string(),
+        expected: "a path or a glob pattern",
+    };
+    if pattern.is_empty() {
+        return Err(invalid());
+    }
+    let path = dir.join(pattern);
+    if !pattern.contains(['*', '?', '[']) {
+        return Ok(vec![path]);
+    }
+
+    let pattern = path.to_str().ok_or_else(invalid)?;
+    let mut paths = Vec::new();
+    for path in glob::glob(pattern).map_err(|_| invalid())? {
+        let path = path
+            .map_err(|err| ErrorKind::Read(format!("{}: {}", err.path().display(), err.error())))?;
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// `value` with its `${...}` substituted, or the byte offset of the one that can't be and why.
+fn substitute(value: &str, dir: &Path, env: Env) -> Result<String, (usize, ErrorKind)> {
+    let mut substituted = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let offset = value.len() - rest.len() + start;
+        if rest[..start].ends_with('$') {
+            substituted.push_str(&rest[..start - 1]);
+            substituted.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        substituted.push_str(&rest[..start]);
+
+        let Some(len) = rest[start + 2..].find('}') else {
+            let kind = ErrorKind::Syntax("`${` is missing its closing `}`".to_string());
+            return Err((offset, kind));
+        };
+        let expr = &rest[start + 2..start + 2 + len];
+        let replacement = lookup(expr, dir, env).map_err(|reason| {
+            (
+                offset,
+                ErrorKind::Substitution(format!("${{{expr}}}: {reason}")),
+            )
+        })?;
+        substituted.push_str(&replacement);
+        rest = &rest[start + 2 + len + 1..];
+    }
+    substituted.push_str(rest);
+    Ok(substituted)
+}
+
+/// the value of `${expr}`, or why there is none.
+fn lookup(expr: &str, dir: &Path, env: Env) -> Result<String, String> {
+    if let Some(path) = expr.strip_prefix("file:") {
+        let contents = fs::read_to_string(dir.join(path)).map_err(|err| err.to_string())?;
+        return Ok(contents.trim_end_matches(['\n', '\r']).to_string());
+    }
+    match env(expr) {
+        Ok(value) => Ok(value),
+        Err(VarError::NotPresent) => Err("the environment variable is not set".into()),
+        Err(VarError::NotUnicode(_)) => Err("the environment variable isn't valid UTF-8".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::Conf;
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("caetun-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("peers.d")).unwrap();
+        let path = dir.join("tun0.conf");
+        let write = |name: &str, contents: &str| fs::write(dir.join(name), contents).unwrap();
+
+        write(
+            "tun0.conf",
+            "[Interface]\nName=tun0\nAddress=10.8.0.1/24\nInclude=peers.d/*.conf\n",
+        );
+        write("peers.d/b.conf", "[Peer]\nName=b\nAllowedIPs=10.8.0.3/32\n");
+        write(
+            "peers.d/a.conf",
+            "# the first\n[Peer]\nName=a\nAllowedIPs=10.8.0.2/32\nInclude=../more.ini\n",
+        );
+        write("more.ini", "[Peer]\nName=c\n");
+        write("peers.d/notes.txt", "not a config");
+        let source = fs::read_to_string(&path).unwrap();
+        let conf = Conf::parse_file(&source, &path).unwrap();
+        let names: Vec<&str> = conf.peers.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["a", "c", "b"]);
+
+        // errors name the file they're in
+        write(
+            "peers.d/b.conf",
+            "[Peer]\nName=b\nAllowedIPs=10.8.0.300/32\n",
+        );
+        let err = Conf::parse_file(&source, &path).unwrap_err();
+        assert_eq!(err.file.as_deref(), Some(&dir.join("peers.d/b.conf")));
+        assert!(
+            err.to_string().starts_with(&format!(
+                "{}: line 3, column 12: [Peer] AllowedIPs: ",
+                dir.join("peers.d/b.conf").display()
+            )),
+            "{err}"
+        );
+
+        // a cycle
+        write("more.ini", "[Peer]\nName=c\nInclude=tun0.conf\n");
+        let err = Conf::parse_file(&source, &path).unwrap_err().to_string();
+        let canonical = |name: &str| fs::canonicalize(dir.join(name)).unwrap();
+        assert_eq!(
+            err,
+            format!(
+                "{}: line 3, column 9: [Peer] Include: include cycle {} -> {} -> {} -> {}",
+                dir.join("peers.d/../more.ini").display(),
+                canonical("tun0.conf").display(),
+                canonical("peers.d/a.conf").display(),
+                canonical("more.ini").display(),
+                canonical("tun0.conf").display(),
+            )
+        );
+
+        // a path that isn't there, unlike a pattern that matches nothing
+        write(
+            "more.ini",
+            "[Peer]\nName=c\nInclude=missing/*.conf, missing.conf\n",
+        );
+        let err = Conf::parse_file(&source, &path).unwrap_err().to_string();
+        assert!(
+            err.contains(&format!(
+                "line 3, column 9: [Peer] Include: cannot read {}: No such file",
+                dir.join("peers.d/../missing.conf").display()
+            )),
+            "{err}"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_substitute() {
+        let dir = std::env::temp_dir().join(format!("caetun-substitute-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("endpoint"), "vpn.example.com:19988\n").unwrap();
+        let env: Env = &|name| match name {
+            "SUBNET" => Ok("10.8".to_string()),
+            _ => Err(VarError::NotPresent),
+        };
+
+        let dir = dir.as_path();
+        assert_eq!(
+            substitute("${SUBNET}.0.1/24", dir, env).unwrap(),
+            "10.8.0.1/24"
+        );
+        assert_eq!(
+            substitute("${file:endpoint}", dir, env).unwrap(),
+            "vpn.example.com:19988"
+        );
+        assert_eq!(
+            substitute("echo $${SUBNET} $HOME", dir, env).unwrap(),
+            "echo ${SUBNET} $HOME"
+        );
+        let err = substitute("a ${file:missing", dir, env).unwrap_err();
+        assert_eq!(err.0, 2);
+
+        // hooks are the shell's
+        let source = "[Interface]\nName=tun0\nAddress=${SUBNET}.0.1/24\n\
+                      PostUp=for i in a b; do echo ${i} ${SUBNET}; done\n";
+        let sections = resolve_with(ini::parse(source).unwrap(), None, env).unwrap();
+        let conf = Conf::from_sections(sections).unwrap();
+        assert_eq!(conf.interface.address, [("10.8.0.1".parse().unwrap(), 24)]);
+        assert_eq!(
+            conf.interface.hooks.post_up.as_deref(),
+            Some("for i in a b; do echo ${i} ${SUBNET}; done")
+        );
+
+        let source = "[Interface]\nName=tun0\nAddress=10.8.0.1/24\nListenPort=${PORT}\n";
+        let err = resolve_with(ini::parse(source).unwrap(), None, env).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 4, column 12: [Interface] ListenPort: cannot substitute ${PORT}: the \
+             environment variable is not set"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_unresolved() {
+        let check =
+            |source| check_unresolved(&ini::parse(source).unwrap()).map_err(|e| e.to_string());
+        assert!(check("[Interface]\nName=tun0\nPostUp=echo ${i}\n").is_ok());
+        assert_eq!(
+            check("[Interface]\nName=tun0\n[Peer]\nName=a\nEndpoint=${file:endpoint}\n")
+                .unwrap_err(),
+            "line 5, column 10: [Peer] Endpoint: Include and ${...} would be lost when the \
+             config is written back"
+        );
+        assert!(check("[Interface]\nName=tun0\nInclude=peers.d/*.conf\n")
+            .unwrap_err()
+            .starts_with("line 3, column 9: [Interface] Include: "));
+    }
+}
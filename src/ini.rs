@@ -0,0 +1,515 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use thiserror::Error;
+
+/// Error is a line that is not a section header, a `key = value` entry, a comment or blank.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("line {line}, column {column}: {message}")]
+pub struct Error {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+/// Section is a `[Name]` header and the entries up to the next one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    /// the 1-based line and column of the name.
+    pub line: usize,
+    pub column: usize,
+    pub entries: Vec<Entry>,
+    /// the file it was included from, shared by its sections, none for the sections of the config
+    /// itself.
+    pub file: Option<Arc<PathBuf>>,
+}
+
+/// Entry is a `key = value` line, both trimmed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+    /// the 1-based line, and the columns the key and the value start at.
+    pub line: usize,
+    pub key_column: usize,
+    pub value_column: usize,
+}
+
+/// Line is what a line of an ini file is, with the byte offsets of its parts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Line {
+    /// a blank line or a comment.
+    Blank {
+        comment: bool,
+    },
+    Header {
+        name: String,
+        name_start: usize,
+    },
+    Entry {
+        key: String,
+        key_start: usize,
+        key_end: usize,
+        value_start: usize,
+    },
+}
+
+/// classify `line`, the `number`th of its file.
+fn classify(line: &str, number: usize) -> Result<Line, Error> {
+    let error = |byte: usize, message: &str| Error {
+        line: number,
+        column: column(line, byte),
+        message: message.to_string(),
+    };
+    let content = line.trim();
+    let start = line.len() - line.trim_start().len();
+
+    if content.is_empty() || content.starts_with('#') || content.starts_with(';') {
+        return Ok(Line::Blank {
+            comment: !content.is_empty(),
+        });
+    }
+
+    if let Some(header) = content.strip_prefix('[') {
+        let Some(name) = header.strip_suffix(']') else {
+            return Err(error(start, "section header is missing its closing `]`"));
+        };
+        if name.trim().is_empty() {
+            return Err(error(start, "empty section name"));
+        }
+        return Ok(Line::Header {
+            name: name.trim().to_string(),
+            name_start: start + 1 + (name.len() - name.trim_start().len()),
+        });
+    }
+
+    let Some(eq) = line.find('=') else {
+        return Err(error(
+            start,
+            "expected a `[Section]`, a `Key = value` entry or a comment",
+        ));
+    };
+    let key = line[..eq].trim();
+    if key.is_empty() {
+        return Err(error(start, "entry without a key"));
+    }
+    let value = &line[eq + 1..];
+    Ok(Line::Entry {
+        key: key.to_string(),
+        key_start: start,
+        key_end: start + key.len(),
+        value_start: eq + 1 + (value.len() - value.trim_start().len()),
+    })
+}
+
+/// parse the sections of an ini file, in the dialect wg-quick configs are written in: `#` and `;`
+/// start a comment line, keys and values are split at the first `=`, keys are case sensitive.
+pub fn parse(source: &str) -> Result<Vec<Section>, Error> {
+    let mut sections: Vec<Section> = Vec::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let number = i + 1;
+        match classify(line, number)? {
+            Line::Blank { .. } => (),
+            Line::Header { name, name_start } => sections.push(Section {
+                name,
+                line: number,
+                column: column(line, name_start),
+                entries: Vec::new(),
+                file: None,
+            }),
+            Line::Entry {
+                key,
+                key_start,
+                value_start,
+                ..
+            } => {
+                let Some(section) = sections.last_mut() else {
+                    return Err(Error {
+                        line: number,
+                        column: column(line, key_start),
+                        message: "entry outside of a section".to_string(),
+                    });
+                };
+                section.entries.push(Entry {
+                    key,
+                    value: line[value_start..].trim().to_string(),
+                    line: number,
+                    key_column: column(line, key_start),
+                    value_column: column(line, value_start),
+                });
+            }
+        }
+    }
+
+    Ok(sections)
+}
+
+/// Document is an ini file that can be edited without losing what `parse` skips: comments, blank
+/// lines, the spacing around `=` and the order of sections and keys are all kept. Sections are
+/// addressed by their index among the sections of the document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Document {
+    lines: Vec<(String, Line)>,
+    newline: &'static str,
+}
+
+impl Document {
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        parse(source)?;
+        let lines = source
+            .lines()
+            .enumerate()
+            .map(|(i, line)| Ok((line.to_string(), classify(line, i + 1)?)))
+            .collect::<Result<_, Error>>()?;
+        Ok(Self {
+            lines,
+            newline: if source.contains("\r\n") {
+                "\r\n"
+            } else {
+                "\n"
+            },
+        })
+    }
+
+    /// the line index of the header of every section.
+    fn headers(&self) -> Vec<usize> {
+        (0..self.lines.len())
+            .filter(|&i| matches!(self.lines[i].1, Line::Header { .. }))
+            .collect()
+    }
+
+    /// the lines after the header of `section`, up to the next header.
+    fn body(&self, section: usize) -> std::ops::Range<usize> {
+        let headers = self.headers();
+        let end = headers
+            .get(section + 1)
+            .copied()
+            .unwrap_or(self.lines.len());
+        headers[section] + 1..end
+    }
+
+    fn entry(&self, line: usize) -> Option<(&str, &str)> {
+        let (text, kind) = &self.lines[line];
+        match kind {
+            Line::Entry {
+                key, value_start, ..
+            } => Some((key, text[*value_start..].trim_end())),
+            _ => None,
+        }
+    }
+
+    /// the index of the first section named `name` that has `key` set to `value`, if `with` is
+    /// given.
+    pub fn section(&self, name: &str, with: Option<(&str, &str)>) -> Option<usize> {
+        self.headers()
+            .into_iter()
+            .enumerate()
+            .find_map(|(section, line)| {
+                let Line::Header { name: header, .. } = &self.lines[line].1 else {
+                    unreachable!()
+                };
+                let matches = header == name
+                    && with.is_none_or(|(key, value)| self.get(section, key) == Some(value));
+                matches.then_some(section)
+            })
+    }
+
+    pub fn get(&self, section: usize, key: &str) -> Option<&str> {
+        self.body(section)
+            .filter_map(|line| self.entry(line))
+            .find_map(|(k, value)| (k == key).then_some(value))
+    }
+
+    /// set `key` of `section` to `value`: in place when it's set already, after the last entry of
+    /// the section otherwise. A value with a line break is an error at the line it would go on.
+    pub fn set(&mut self, section: usize, key: &str, value: &str) -> Result<(), Error> {
+        let body = self.body(section);
+        let existing = body
+            .clone()
+            .find(|&line| self.entry(line).is_some_and(|(k, _)| k == key));
+        if let Some(line) = existing {
+            let (text, Line::Entry { value_start, .. }) = &mut self.lines[line] else {
+                unreachable!()
+            };
+            single_line(value, line + 1, column(text, *value_start))?;
+            text.truncate(*value_start);
+            text.push_str(value);
+            return Ok(());
+        }
+
+        let after = body
+            .rev()
+            .find(|&line| self.entry(line).is_some())
+            .unwrap_or(self.headers()[section]);
+        let line = self.entry_line(key, value, after + 2)?;
+        self.lines.insert(after + 1, line);
+        Ok(())
+    }
+
+    /// remove `key` from `section`, false when it isn't set.
+    pub fn unset(&mut self, section: usize, key: &str) -> bool {
+        let lines: Vec<usize> = self
+            .body(section)
+            .filter(|&line| self.entry(line).is_some_and(|(k, _)| k == key))
+            .collect();
+        for &line in lines.iter().rev() {
+            self.lines.remove(line);
+        }
+        !lines.is_empty()
+    }
+
+    /// append a section, separated from the previous one by a blank line. A value with a line
+    /// break is an error at the line it would go on, and leaves the document as it is.
+    pub fn push_section(&mut self, name: &str, entries: &[(&str, String)]) -> Result<(), Error> {
+        let mut lines = Vec::new();
+        if self
+            .lines
+            .last()
+            .is_some_and(|(_, line)| *line != Line::Blank { comment: false })
+        {
+            lines.push((String::new(), Line::Blank { comment: false }));
+        }
+        let header = format!("[{name}]");
+        lines.push((
+            header,
+            Line::Header {
+                name: name.to_string(),
+                name_start: 1,
+            },
+        ));
+        for (key, value) in entries {
+            let number = self.lines.len() + lines.len() + 1;
+            lines.push(self.entry_line(key, value, number)?);
+        }
+        self.lines.extend(lines);
+        Ok(())
+    }
+
+    /// remove `section`, with the comment lines right above its header.
+    pub fn remove_section(&mut self, section: usize) {
+        let headers = self.headers();
+        let start = self.block_start(headers[section]);
+        let end = match headers.get(section + 1) {
+            Some(&next) => self.block_start(next),
+            None => self.lines.len(),
+        };
+        let last = end == self.lines.len();
+        self.lines.drain(start..end);
+        // the blank lines that separated the last section from the one before
+        while last
+            && self
+                .lines
+                .last()
+                .is_some_and(|(_, line)| *line == Line::Blank { comment: false })
+        {
+            self.lines.pop();
+        }
+    }
+
+    /// the first of the comment lines right above the header at line `header`.
+    fn block_start(&self, header: usize) -> usize {
+        let mut start = header;
+        while start > 0 && self.lines[start - 1].1 == (Line::Blank { comment: true }) {
+            start -= 1;
+        }
+        start
+    }
+
+    /// an entry line, spaced like the first entry of the document, to go on line `number`.
+    fn entry_line(&self, key: &str, value: &str, number: usize) -> Result<(String, Line), Error> {
+        let separator = self
+            .lines
+            .iter()
+            .find_map(|(text, line)| match line {
+                Line::Entry {
+                    key_end,
+                    value_start,
+                    ..
+                } => Some(&text[*key_end..*value_start]),
+                _ => None,
+            })
+            .filter(|separator| separator.trim() == "=")
+            .unwrap_or("=");
+        let text = format!("{key}{separator}");
+        single_line(value, number, column(&text, text.len()))?;
+        let line = Line::Entry {
+            key: key.to_string(),
+            key_start: 0,
+            key_end: key.len(),
+            value_start: text.len(),
+        };
+        Ok((text + value, line))
+    }
+}
+
+impl std::fmt::Display for Document {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (text, _) in &self.lines {
+            write!(f, "{text}{}", self.newline)?;
+        }
+        Ok(())
+    }
+}
+
+/// fail when `value`, starting at `column` of line `number`, has a line break. It would end the
+/// entry there and start lines the document doesn't know about, a whole section even.
+fn single_line(value: &str, number: usize, column: usize) -> Result<(), Error> {
+    match value.find(['\n', '\r']) {
+        Some(byte) => Err(Error {
+            line: number,
+            column: column + value[..byte].chars().count(),
+            message: "a value can't contain a line break".to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// the 1-based column of byte offset `byte` in `line`, counted in characters.
+fn column(line: &str, byte: usize) -> usize {
+    line[..byte].chars().count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let source = "# a comment\n\n[Interface]\nName = server\n  ; indented comment\nPostUp=echo a=b\n[ Peer ]\nAllowedIPs=\r\n";
+        let sections = parse(source).unwrap();
+        assert_eq!(sections.len(), 2);
+
+        let interface = &sections[0];
+        assert_eq!((interface.name.as_str(), interface.line), ("Interface", 3));
+        assert_eq!(
+            interface.entries[0],
+            Entry {
+                key: "Name".into(),
+                value: "server".into(),
+                line: 4,
+                key_column: 1,
+                value_column: 8,
+            }
+        );
+        assert_eq!(interface.entries[1].value, "echo a=b");
+
+        let peer = &sections[1];
+        assert_eq!((peer.name.as_str(), peer.column), ("Peer", 3));
+        assert_eq!(peer.entries[0].value, "");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (source, line, column) in [
+            ("[Interface\n", 1, 1),
+            ("[Interface]\n  Name\n", 2, 3),
+            ("[Interface]\n = x\n", 2, 2),
+            ("Name=server\n", 1, 1),
+            ("[]\n", 1, 1),
+        ] {
+            let err = parse(source).unwrap_err();
+            assert_eq!((err.line, err.column), (line, column), "{source:?}: {err}");
+        }
+    }
+
+    const CONF: &str = "# tun0, the hub\n\
+        [Interface]\n\
+        Name = server\n\
+        Address = 10.8.0.1/24\n\
+        \n\
+        # laptop\n\
+        [Peer]\n\
+        Name = client1\n\
+        AllowedIPs = 10.8.0.2/32\n\
+        ; kept\n\
+        \n\
+        [Peer]\n\
+        Name = client2\n";
+
+    #[test]
+    fn test_document() {
+        let mut doc = Document::parse(CONF).unwrap();
+        assert_eq!(doc.to_string(), CONF);
+
+        let client1 = doc.section("Peer", Some(("Name", "client1"))).unwrap();
+        assert_eq!(client1, 1);
+        assert_eq!(doc.get(client1, "AllowedIPs"), Some("10.8.0.2/32"));
+        assert_eq!(doc.section("Peer", Some(("Name", "client3"))), None);
+
+        doc.set(client1, "AllowedIPs", "10.8.0.2/32, 192.168.1.0/24")
+            .unwrap();
+        doc.set(client1, "Batch", "true").unwrap();
+        doc.set(0, "MTU", "1380").unwrap();
+        assert!(doc.unset(0, "Address"));
+        assert!(!doc.unset(0, "Address"));
+        doc.push_section("Peer", &[("Name", "client3".into())])
+            .unwrap();
+        assert_eq!(
+            doc.to_string(),
+            "# tun0, the hub\n\
+            [Interface]\n\
+            Name = server\n\
+            MTU = 1380\n\
+            \n\
+            # laptop\n\
+            [Peer]\n\
+            Name = client1\n\
+            AllowedIPs = 10.8.0.2/32, 192.168.1.0/24\n\
+            Batch = true\n\
+            ; kept\n\
+            \n\
+            [Peer]\n\
+            Name = client2\n\
+            \n\
+            [Peer]\n\
+            Name = client3\n"
+        );
+        assert_eq!(parse(&doc.to_string()).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_document_line_breaks() {
+        let mut doc = Document::parse(CONF).unwrap();
+        let err = doc.set(1, "Name", "x\n[Peer]\nName = evil").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 8, column 9: a value can't contain a line break"
+        );
+        let err = doc.set(0, "PostUp", "true\r\n[Peer]").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 5, column 14: a value can't contain a line break"
+        );
+        let entries = [
+            ("Name", "client3".to_string()),
+            ("Endpoint", "\n".to_string()),
+        ];
+        let err = doc.push_section("Peer", &entries).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 17, column 12: a value can't contain a line break"
+        );
+        assert_eq!(doc.to_string(), CONF);
+    }
+
+    #[test]
+    fn test_remove_section() {
+        let mut doc = Document::parse(CONF).unwrap();
+        doc.remove_section(1);
+        assert_eq!(
+            doc.to_string(),
+            "# tun0, the hub\n[Interface]\nName = server\nAddress = 10.8.0.1/24\n\n[Peer]\nName = client2\n"
+        );
+        doc.remove_section(1);
+        assert_eq!(
+            doc.to_string(),
+            "# tun0, the hub\n[Interface]\nName = server\nAddress = 10.8.0.1/24\n"
+        );
+
+        let doc = Document::parse("[Interface]\r\nName=a\r\n").unwrap();
+        assert_eq!(doc.to_string(), "[Interface]\r\nName=a\r\n");
+        assert!(Document::parse("[Interface\n").is_err());
+    }
+}
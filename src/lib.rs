@@ -1,8 +1,26 @@
 mod allowed_ip;
+pub mod bench;
 pub mod conf;
 pub mod device;
+pub mod dns;
+pub mod edit;
+pub mod format;
+pub mod generate;
+pub mod graph;
+pub mod hooks;
+pub mod include;
+pub mod ini;
+mod mtu;
+#[cfg(target_os = "linux")]
+mod netlink;
 mod packet;
 pub mod peer;
+pub mod peer_table;
+pub mod qr;
+pub mod quick;
+pub mod resolve;
+pub mod validate;
+pub mod wg;
 
 #[cfg(target_os = "linux")]
 #[path = "poll_epoll.rs"]
@@ -12,6 +30,14 @@ mod poll;
 #[path = "poll_kqueue.rs"]
 mod poll;
 
+#[cfg(target_os = "linux")]
+#[path = "route_linux.rs"]
+mod route;
+
+#[cfg(target_os = "macos")]
+#[path = "route_darwin.rs"]
+mod route;
+
 #[cfg(target_os = "linux")]
 #[path = "tun_linux.rs"]
 mod tun;
@@ -1,50 +1,176 @@
-use anyhow::{bail, Context};
+use anyhow::bail;
+use caetun::bench::{self, BenchConfig};
 use caetun::conf::Conf;
-use caetun::device::{Device, DeviceConfig};
-use caetun::peer::{Peer, PeerName};
-use clap::Parser;
-use std::fs;
-use std::path::PathBuf;
+use caetun::format;
+use caetun::quick::{self, State};
+use caetun::validate;
+use clap::{Args, Parser, Subcommand};
+use std::path::{Path, PathBuf};
 use tracing::level_filters::LevelFilter;
 use tracing::Level;
 use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
 
 #[derive(Parser)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Cli {
-    #[arg(long)]
+    #[arg(long, required = true)]
+    conf: Option<PathBuf>,
+    #[arg(long, global = true)]
+    log_level: Option<Level>,
+    /// Serve every peer from the listening socket instead of a connected socket per peer,
+    /// recommended for hubs with many peers since it keeps the number of fds constant
+    #[arg(long, global = true)]
+    no_peer_sockets: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Measure throughput and latency between two in-process devices over loopback udp
+    Bench(BenchArgs),
+    /// Create the interface of a config, configure its addresses and routes and run the device in
+    /// the background
+    Up(UpArgs),
+    /// Stop the device of an interface brought up with `up` and undo what it set up
+    Down(DownArgs),
+}
+
+#[derive(Args)]
+struct UpArgs {
+    /// Config file, the interface is named after it
     conf: PathBuf,
+    /// Stay in the foreground instead of detaching once the interface is up
     #[arg(long)]
-    log_level: Option<Level>,
+    foreground: bool,
+}
+
+#[derive(Args)]
+struct DownArgs {
+    /// Interface name or the config file it was brought up from
+    name: String,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    /// Comma separated inner packet sizes in bytes, including the ip header
+    #[arg(long, value_delimiter = ',', default_value = "64,512,1400")]
+    sizes: Vec<usize>,
+    /// Number of packets sent for every size
+    #[arg(long, default_value_t = 100_000)]
+    count: usize,
+    /// Maximum number of packets in flight
+    #[arg(long, default_value_t = 64)]
+    window: usize,
+    /// Aggregate packets into batch datagrams
+    #[arg(long)]
+    batch: bool,
+    /// Connect the devices over IPv6 loopback
+    #[arg(long)]
+    ipv6: bool,
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
 }
 
-fn run(tun_name: &str, conf: Conf) -> anyhow::Result<()> {
-    let mut dev = Device::new(DeviceConfig::new(
-        PeerName::new(&conf.interface.name)?,
-        tun_name,
-        conf.interface.listen_port,
-        true,
-    ))?;
-
-    for peer_conf in &conf.peers {
-        let peer_name = PeerName::new(&peer_conf.name)?;
-        let mut peer = Peer::default();
-        if let Some(endpoint) = peer_conf.endpoint {
-            peer.set_endpoint(endpoint);
+fn run(tun_name: &str, conf: Conf, use_connected_peer: bool) -> anyhow::Result<()> {
+    validate::check(&conf)?;
+    let dev = quick::device(tun_name, &conf, use_connected_peer)?;
+
+    dev.start()?;
+    dev.wait();
+
+    Ok(())
+}
+
+/// read a config, in any format `caetun-conf convert` writes, picked by its extension.
+fn read_conf(path: &Path) -> anyhow::Result<Conf> {
+    format::load(path, None)
+}
+
+fn run_up(args: UpArgs, log_level: Option<Level>, use_connected_peer: bool) -> anyhow::Result<()> {
+    let name = quick::iface_name(&args.conf)?;
+    let conf = read_conf(&args.conf)?;
+    let run_dir = State::dir();
+    quick::check_not_up(&run_dir, name)?;
+
+    let ready = if args.foreground {
+        None
+    } else {
+        Some(quick::daemonize(&run_dir, name)?)
+    };
+    init_tracing(log_level, LevelFilter::INFO);
+
+    match quick::up(&run_dir, name, &args.conf, &conf, use_connected_peer) {
+        Ok(dev) => {
+            if let Some(ready) = ready {
+                ready.ok();
+            }
+            dev.wait();
+            Ok(())
         }
-        for (ip, cidr) in &peer_conf.allowed_ips {
-            peer.add_allowed_ip(*ip, *cidr);
+        Err(err) => {
+            if let Some(ready) = ready {
+                ready.fail(&err);
+            }
+            Err(err)
         }
-        dev.add_peer(peer_name, peer);
     }
+}
 
-    dev.start()?;
-    dev.wait();
+fn run_down(args: DownArgs) -> anyhow::Result<()> {
+    // accept the config path too, like wg-quick
+    let name = quick::iface_name(Path::new(&args.name))?;
+    let state = quick::down(&State::dir(), name)?;
+    println!("{name} is down, stopped pid {}", state.pid);
+    Ok(())
+}
+
+fn run_bench(args: BenchArgs, use_connected_peer: bool) -> anyhow::Result<()> {
+    let report = bench::run(&BenchConfig {
+        sizes: args.sizes,
+        count: args.count,
+        window: args.window,
+        batch: args.batch,
+        ipv6: args.ipv6,
+        use_connected_peer,
+    })?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{report}");
+    }
 
     Ok(())
 }
 
+fn init_tracing(level: Option<Level>, default: LevelFilter) {
+    let layer = Layer::new()
+        .event_format(tracing_subscriber::fmt::format().with_source_location(true))
+        .with_filter(level.map_or(default, LevelFilter::from_level));
+    tracing_subscriber::registry().with(layer).init();
+}
+
 fn main() -> anyhow::Result<()> {
+    let args = Cli::parse();
+
+    match args.command {
+        Some(Command::Bench(bench_args)) => {
+            // per packet logging would dominate the measurement
+            init_tracing(args.log_level, LevelFilter::WARN);
+            return run_bench(bench_args, !args.no_peer_sockets);
+        }
+        Some(Command::Up(up_args)) => {
+            return run_up(up_args, args.log_level, !args.no_peer_sockets);
+        }
+        Some(Command::Down(down_args)) => {
+            init_tracing(args.log_level, LevelFilter::WARN);
+            return run_down(down_args);
+        }
+        None => (),
+    }
+
     println!(
         r#"
                       __
@@ -56,23 +182,15 @@ _/ ___\\__  \ _/ __ \   __\  |  \/    \
     "#
     );
 
-    let args = Cli::parse();
-
-    let Some(tun) = args.conf.file_stem().and_then(|s| s.to_str()) else {
-        bail!("invalid filename")
+    let Some(conf_path) = args.conf else {
+        bail!("--conf is required")
     };
-    let conf = fs::read_to_string(&args.conf).context("failed to read config")?;
-    let conf = Conf::parse_from(&conf)?;
+    let tun = quick::iface_name(&conf_path)?;
+    let conf = read_conf(&conf_path)?;
 
-    let layer = Layer::new()
-        .event_format(tracing_subscriber::fmt::format().with_source_location(true))
-        .with_filter(
-            args.log_level
-                .map_or(LevelFilter::DEBUG, LevelFilter::from_level),
-        );
-    tracing_subscriber::registry().with(layer).init();
+    init_tracing(args.log_level, LevelFilter::DEBUG);
 
-    run(tun, conf)?;
+    run(tun, conf, !args.no_peer_sockets)?;
 
     Ok(())
 }
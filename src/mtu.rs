@@ -0,0 +1,10 @@
+/// The bytes caetun adds around every inner packet: an outer IPv6 header, since the dual-stack
+/// socket may reach any peer over IPv6, the udp header and the 5 bytes of the `Data` header. There
+/// is no encryption, so no nonce or tag to account for.
+pub const OVERHEAD: u32 = 40 + 8 + 5;
+
+/// The smallest tun mtu, the minimum every IPv4 host must accept.
+pub const MIN_MTU: u32 = 576;
+
+/// The largest tun mtu, an encapsulated packet must still fit into a single 64K ip packet.
+pub const MAX_MTU: u32 = u16::MAX as u32 - OVERHEAD;
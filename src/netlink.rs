@@ -0,0 +1,610 @@
+use std::ffi::CString;
+use std::io;
+use std::mem::size_of;
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use libc::*;
+
+/// Error is returned by rtnetlink requests.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("netlink socket: {0}")]
+    Socket(io::Error),
+    #[error("no such interface: {0}")]
+    NoSuchInterface(String),
+    #[error(
+        "{0}: operation not permitted, caetun needs CAP_NET_ADMIN \
+         (run it as root or `setcap cap_net_admin=eip` the binary)"
+    )]
+    PermissionDenied(String),
+    #[error("{0}: {1}")]
+    Request(String, io::Error),
+}
+
+/// Netlink is a minimal synchronous rtnetlink client, it covers exactly the requests caetun needs to
+/// configure its interface.
+///
+/// Every request is sent with NLM_F_ACK and waits for the kernel's answer, so errors are reported on
+/// the request that caused them.
+pub struct Netlink {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+const NLMSG_HDR_LEN: usize = size_of::<nlmsghdr>();
+const RECV_BUF_SIZE: usize = 8192;
+const RTMSG_LEN: usize = 12;
+const IFINFOMSG_LEN: usize = 16;
+// struct rtattr: len, type, not exported by libc
+const RTATTR_LEN: usize = 4;
+// linux/rtnetlink.h, not exported by libc
+const RTAX_MTU: u16 = 2;
+// linux/fib_rules.h, not exported by libc
+const FRA_FWMARK: u16 = 10;
+const FRA_SUPPRESS_PREFIXLEN: u16 = 14;
+const FRA_TABLE: u16 = 15;
+const FR_ACT_TO_TBL: u8 = 1;
+const FIB_RULE_INVERT: u32 = 2;
+
+/// Rule is a policy routing rule that sends route lookups to a routing table, one of the few forms
+/// `ip rule` takes that caetun needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule {
+    pub ipv6: bool,
+    pub table: u32,
+    /// match only packets without this firewall mark, `not fwmark <mark>`.
+    pub not_fwmark: Option<u32>,
+    /// ignore routes with a prefix this short or shorter, `suppress_prefixlength <len>`.
+    pub suppress_prefixlen: Option<u32>,
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(if self.ipv6 { "-6 " } else { "-4 " })?;
+        if let Some(mark) = self.not_fwmark {
+            write!(f, "not fwmark {mark:#x} ")?;
+        }
+        write!(f, "table {}", self.table)?;
+        if let Some(len) = self.suppress_prefixlen {
+            write!(f, " suppress_prefixlength {len}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Netlink {
+    pub fn new() -> Result<Self, Error> {
+        let fd = match unsafe { socket(AF_NETLINK, SOCK_RAW | SOCK_CLOEXEC, NETLINK_ROUTE) } {
+            -1 => return Err(Error::Socket(io::Error::last_os_error())),
+            fd => unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+
+        let mut addr: sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = AF_NETLINK as _;
+        if unsafe {
+            bind(
+                fd.as_raw_fd(),
+                &addr as *const sockaddr_nl as _,
+                size_of::<sockaddr_nl>() as _,
+            )
+        } < 0
+        {
+            return Err(Error::Socket(io::Error::last_os_error()));
+        }
+
+        Ok(Self { fd, seq: 0 })
+    }
+
+    /// add `addr/prefix_len` to the interface, replacing it if it's already there.
+    pub fn add_address(&mut self, iface: &str, addr: IpAddr, prefix_len: u8) -> Result<(), Error> {
+        let index = if_index(iface)?;
+
+        let mut msg = Message::new(RTM_NEWADDR, NLM_F_CREATE | NLM_F_REPLACE);
+        // struct ifaddrmsg
+        msg.push(&[family(addr), prefix_len, 0, RT_SCOPE_UNIVERSE]);
+        msg.push(&index.to_ne_bytes());
+        msg.attr(IFA_LOCAL, &octets(addr));
+        msg.attr(IFA_ADDRESS, &octets(addr));
+
+        self.request(msg, || {
+            format!("add address {addr}/{prefix_len} to {iface}")
+        })
+    }
+
+    /// set the mtu of the interface.
+    pub fn set_mtu(&mut self, iface: &str, mtu: u32) -> Result<(), Error> {
+        let mut msg = Message::new(RTM_NEWLINK, 0);
+        push_ifinfomsg(&mut msg, if_index(iface)?, 0, 0);
+        msg.attr(IFLA_MTU, &mtu.to_ne_bytes());
+
+        self.request(msg, || format!("set mtu {mtu} on {iface}"))
+    }
+
+    /// bring the interface up or down.
+    pub fn set_up(&mut self, iface: &str, up: bool) -> Result<(), Error> {
+        let flags = if up { IFF_UP as u32 } else { 0 };
+
+        let mut msg = Message::new(RTM_NEWLINK, 0);
+        push_ifinfomsg(&mut msg, if_index(iface)?, flags, IFF_UP as u32);
+
+        let state = if up { "up" } else { "down" };
+        self.request(msg, || format!("set {iface} {state}"))
+    }
+
+    /// route `dst/prefix_len` into the interface in routing table `table`, replacing an existing
+    /// route to the same prefix.
+    pub fn add_route(
+        &mut self,
+        iface: &str,
+        dst: IpAddr,
+        prefix_len: u8,
+        table: u32,
+    ) -> Result<(), Error> {
+        let msg = route_message(
+            RTM_NEWROUTE,
+            NLM_F_CREATE | NLM_F_REPLACE,
+            if_index(iface)?,
+            (dst, prefix_len),
+            table,
+        );
+        self.request(msg, || {
+            format!("add route {dst}/{prefix_len} dev {iface} table {table}")
+        })
+    }
+
+    /// remove the route to `dst/prefix_len` through the interface from routing table `table`.
+    pub fn del_route(
+        &mut self,
+        iface: &str,
+        dst: IpAddr,
+        prefix_len: u8,
+        table: u32,
+    ) -> Result<(), Error> {
+        let msg = route_message(RTM_DELROUTE, 0, if_index(iface)?, (dst, prefix_len), table);
+        self.request(msg, || {
+            format!("delete route {dst}/{prefix_len} dev {iface} table {table}")
+        })
+    }
+
+    /// add the policy routing rule `rule`, a rule that is already there is left alone.
+    pub fn add_rule(&mut self, rule: &Rule) -> Result<(), Error> {
+        let msg = rule_message(RTM_NEWRULE, NLM_F_CREATE | NLM_F_EXCL, rule);
+        match self.request(msg, || format!("add rule {rule}")) {
+            Err(Error::Request(_, err)) if err.raw_os_error() == Some(EEXIST) => Ok(()),
+            res => res,
+        }
+    }
+
+    /// remove the policy routing rule `rule`, a rule that is already gone is no error.
+    pub fn del_rule(&mut self, rule: &Rule) -> Result<(), Error> {
+        let msg = rule_message(RTM_DELRULE, 0, rule);
+        match self.request(msg, || format!("delete rule {rule}")) {
+            Err(Error::Request(_, err)) if err.raw_os_error() == Some(ENOENT) => Ok(()),
+            res => res,
+        }
+    }
+
+    /// find the mtu of the route the kernel would use to reach `dst`: the mtu metric of the route
+    /// if it has one, like `ip route add ... mtu 1400` sets, the mtu of its output interface
+    /// otherwise.
+    pub fn route_mtu(&mut self, dst: IpAddr) -> Result<u32, Error> {
+        let prefix_len = match dst {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let mut msg = Message::new(RTM_GETROUTE, 0);
+        // struct rtmsg: family, dst_len, src_len, tos, table, protocol, scope, type, flags
+        msg.push(&[family(dst), prefix_len, 0, 0, 0, 0, 0, 0]);
+        msg.push(&0u32.to_ne_bytes());
+        msg.attr(RTA_DST, &octets(dst));
+
+        let mut oif = None;
+        let mut mtu = None;
+        self.request_with(
+            msg,
+            || format!("get route to {dst}"),
+            |ty, payload| {
+                if ty != RTM_NEWROUTE || payload.len() < RTMSG_LEN {
+                    return;
+                }
+                for (ty, data) in attrs(&payload[RTMSG_LEN..]) {
+                    match ty {
+                        RTA_OIF => oif = read_u32(data),
+                        RTA_METRICS => {
+                            mtu = attrs(data)
+                                .find(|(ty, _)| *ty == RTAX_MTU)
+                                .and_then(|(_, data)| read_u32(data))
+                        }
+                        _ => (),
+                    }
+                }
+            },
+        )?;
+
+        match (mtu, oif) {
+            (Some(mtu), _) if mtu > 0 => Ok(mtu),
+            (_, Some(oif)) => self.link_mtu(oif),
+            _ => Err(Error::Request(
+                format!("get route to {dst}"),
+                io::Error::new(io::ErrorKind::NotFound, "route has no output interface"),
+            )),
+        }
+    }
+
+    fn link_mtu(&mut self, index: u32) -> Result<u32, Error> {
+        let mut msg = Message::new(RTM_GETLINK, 0);
+        push_ifinfomsg(&mut msg, index, 0, 0);
+
+        let mut mtu = None;
+        self.request_with(
+            msg,
+            || format!("get link {index}"),
+            |ty, payload| {
+                if ty == RTM_NEWLINK && payload.len() >= IFINFOMSG_LEN {
+                    mtu = attrs(&payload[IFINFOMSG_LEN..])
+                        .find(|(ty, _)| *ty == IFLA_MTU)
+                        .and_then(|(_, data)| read_u32(data));
+                }
+            },
+        )?;
+
+        mtu.ok_or_else(|| {
+            Error::Request(
+                format!("get link {index}"),
+                io::Error::new(io::ErrorKind::NotFound, "link has no mtu"),
+            )
+        })
+    }
+
+    fn request(&mut self, msg: Message, op: impl Fn() -> String) -> Result<(), Error> {
+        self.request_with(msg, op, |_, _| ())
+    }
+
+    /// send `msg` and wait for its ACK, `on_reply` is called with the type and the payload of every
+    /// other message the kernel answers with.
+    fn request_with(
+        &mut self,
+        mut msg: Message,
+        op: impl Fn() -> String,
+        mut on_reply: impl FnMut(u16, &[u8]),
+    ) -> Result<(), Error> {
+        self.seq = self.seq.wrapping_add(1);
+        let seq = self.seq;
+        let buf = msg.finish(seq);
+
+        if unsafe { send(self.fd.as_raw_fd(), buf.as_ptr() as _, buf.len(), 0) } < 0 {
+            return Err(Error::Request(op(), io::Error::last_os_error()));
+        }
+
+        let mut buf = [0u8; RECV_BUF_SIZE];
+        loop {
+            let n = match unsafe { recv(self.fd.as_raw_fd(), buf.as_mut_ptr() as _, buf.len(), 0) }
+            {
+                -1 => return Err(Error::Request(op(), io::Error::last_os_error())),
+                n => n as usize,
+            };
+
+            let mut rest = &buf[..n];
+            while rest.len() >= NLMSG_HDR_LEN {
+                let len = u32::from_ne_bytes(rest[0..4].try_into().unwrap()) as usize;
+                let ty = u16::from_ne_bytes(rest[4..6].try_into().unwrap());
+                let msg_seq = u32::from_ne_bytes(rest[8..12].try_into().unwrap());
+                if len < NLMSG_HDR_LEN || len > rest.len() {
+                    break;
+                }
+
+                if msg_seq == seq && ty == NLMSG_ERROR as u16 && len >= NLMSG_HDR_LEN + 4 {
+                    let err = i32::from_ne_bytes(
+                        rest[NLMSG_HDR_LEN..NLMSG_HDR_LEN + 4].try_into().unwrap(),
+                    );
+                    return match -err {
+                        0 => Ok(()),
+                        EPERM => Err(Error::PermissionDenied(op())),
+                        errno => Err(Error::Request(op(), io::Error::from_raw_os_error(errno))),
+                    };
+                }
+                if msg_seq == seq {
+                    on_reply(ty, &rest[NLMSG_HDR_LEN..len]);
+                }
+
+                rest = &rest[align(len).min(rest.len())..];
+            }
+        }
+    }
+}
+
+/// Message builds one netlink request: header, fixed size family header and attributes.
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new(ty: u16, flags: c_int) -> Self {
+        let mut buf = vec![0u8; NLMSG_HDR_LEN];
+        buf[4..6].copy_from_slice(&ty.to_ne_bytes());
+        let flags = (NLM_F_REQUEST | NLM_F_ACK | flags) as u16;
+        buf[6..8].copy_from_slice(&flags.to_ne_bytes());
+        Self { buf }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    fn attr(&mut self, ty: u16, data: &[u8]) {
+        let len = (RTATTR_LEN + data.len()) as u16;
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&ty.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+
+    fn finish(&mut self, seq: u32) -> &[u8] {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        &self.buf
+    }
+}
+
+/// a static device route, as `ip route add <dst> dev <iface> table <table>` sends it.
+fn route_message(
+    ty: u16,
+    flags: c_int,
+    index: u32,
+    (dst, prefix_len): (IpAddr, u8),
+    table: u32,
+) -> Message {
+    // tables past 255 only fit into RTA_TABLE
+    let rtm_table = u8::try_from(table).unwrap_or(RT_TABLE_UNSPEC);
+    // deleting with RT_SCOPE_NOWHERE matches the route whatever its scope
+    let (protocol, scope, kind) = match ty {
+        RTM_DELROUTE => (0, RT_SCOPE_NOWHERE, 0),
+        _ => (RTPROT_STATIC, RT_SCOPE_LINK, RTN_UNICAST),
+    };
+
+    let mut msg = Message::new(ty, flags);
+    // struct rtmsg: family, dst_len, src_len, tos, table, protocol, scope, type, flags
+    msg.push(&[
+        family(dst),
+        prefix_len,
+        0,
+        0,
+        rtm_table,
+        protocol,
+        scope,
+        kind,
+    ]);
+    msg.push(&0u32.to_ne_bytes());
+    msg.attr(RTA_TABLE, &table.to_ne_bytes());
+    msg.attr(RTA_DST, &octets(dst));
+    msg.attr(RTA_OIF, &index.to_ne_bytes());
+    msg
+}
+
+/// a policy routing rule, as `ip rule add` sends it.
+fn rule_message(ty: u16, flags: c_int, rule: &Rule) -> Message {
+    let family = if rule.ipv6 { AF_INET6 } else { AF_INET } as u8;
+    let table = u8::try_from(rule.table).unwrap_or(RT_TABLE_UNSPEC);
+    let rule_flags = match rule.not_fwmark {
+        Some(_) => FIB_RULE_INVERT,
+        None => 0,
+    };
+
+    let mut msg = Message::new(ty, flags);
+    // struct fib_rule_hdr: family, dst_len, src_len, tos, table, res1, res2, action, flags
+    msg.push(&[family, 0, 0, 0, table, 0, 0, FR_ACT_TO_TBL]);
+    msg.push(&rule_flags.to_ne_bytes());
+    msg.attr(FRA_TABLE, &rule.table.to_ne_bytes());
+    if let Some(mark) = rule.not_fwmark {
+        msg.attr(FRA_FWMARK, &mark.to_ne_bytes());
+    }
+    if let Some(len) = rule.suppress_prefixlen {
+        msg.attr(FRA_SUPPRESS_PREFIXLEN, &len.to_ne_bytes());
+    }
+    msg
+}
+
+// struct ifinfomsg
+fn push_ifinfomsg(msg: &mut Message, index: u32, flags: u32, change: u32) {
+    msg.push(&[AF_UNSPEC as u8, 0]);
+    msg.push(&0u16.to_ne_bytes());
+    msg.push(&index.to_ne_bytes());
+    msg.push(&flags.to_ne_bytes());
+    msg.push(&change.to_ne_bytes());
+}
+
+/// iterate over the `(type, payload)` of the attributes in `buf`.
+fn attrs(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < RTATTR_LEN {
+            return None;
+        }
+        let len = u16::from_ne_bytes(buf[0..2].try_into().unwrap()) as usize;
+        let ty = u16::from_ne_bytes(buf[2..4].try_into().unwrap()) & NLA_TYPE_MASK as u16;
+        if len < RTATTR_LEN || len > buf.len() {
+            return None;
+        }
+        let data = &buf[RTATTR_LEN..len];
+        buf = &buf[align(len).min(buf.len())..];
+        Some((ty, data))
+    })
+}
+
+fn read_u32(data: &[u8]) -> Option<u32> {
+    Some(u32::from_ne_bytes(data.get(..4)?.try_into().unwrap()))
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn family(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => AF_INET as u8,
+        IpAddr::V6(_) => AF_INET6 as u8,
+    }
+}
+
+fn octets(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(v4) => v4.octets().to_vec(),
+        IpAddr::V6(v6) => v6.octets().to_vec(),
+    }
+}
+
+fn if_index(iface: &str) -> Result<u32, Error> {
+    let name = CString::new(iface).map_err(|_| Error::NoSuchInterface(iface.to_string()))?;
+    match unsafe { if_nametoindex(name.as_ptr()) } {
+        0 => Err(Error::NoSuchInterface(iface.to_string())),
+        index => Ok(index),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_layout() {
+        let mut msg = Message::new(RTM_NEWADDR, NLM_F_CREATE);
+        msg.push(&[AF_INET as u8, 24, 0, 0]);
+        msg.push(&7u32.to_ne_bytes());
+        msg.attr(IFA_LOCAL, &[10, 8, 0, 1]);
+        msg.attr(IFA_LABEL, b"tun0\0");
+        let buf = msg.finish(42).to_vec();
+
+        // header + ifaddrmsg + 8 byte attr + 9 byte attr padded to 12
+        assert_eq!(buf.len(), 16 + 8 + 8 + 12);
+        assert_eq!(u32::from_ne_bytes(buf[0..4].try_into().unwrap()), 44);
+        assert_eq!(
+            u16::from_ne_bytes(buf[4..6].try_into().unwrap()),
+            RTM_NEWADDR
+        );
+        assert_eq!(
+            u16::from_ne_bytes(buf[6..8].try_into().unwrap()),
+            (NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE) as u16
+        );
+        assert_eq!(u32::from_ne_bytes(buf[8..12].try_into().unwrap()), 42);
+        assert_eq!(u16::from_ne_bytes(buf[24..26].try_into().unwrap()), 8);
+        assert_eq!(&buf[28..32], &[10, 8, 0, 1]);
+        assert_eq!(u16::from_ne_bytes(buf[32..34].try_into().unwrap()), 9);
+    }
+
+    #[test]
+    fn test_attrs() {
+        let mut msg = Message::new(RTM_NEWROUTE, 0);
+        msg.attr(RTA_OIF, &3u32.to_ne_bytes());
+        msg.attr(RTA_TABLE, &[1]);
+        msg.attr(RTA_PRIORITY, &7u32.to_ne_bytes());
+        let buf = msg.finish(1).to_vec();
+
+        let attrs: Vec<_> = attrs(&buf[NLMSG_HDR_LEN..]).collect();
+        assert_eq!(attrs.len(), 3);
+        assert_eq!(attrs[0].0, RTA_OIF);
+        assert_eq!(read_u32(attrs[0].1), Some(3));
+        assert_eq!(attrs[1], (RTA_TABLE, &[1u8][..]));
+        assert_eq!(read_u32(attrs[1].1), None);
+        assert_eq!(read_u32(attrs[2].1), Some(7));
+    }
+
+    #[test]
+    fn test_route_message() {
+        let dst = IpAddr::from([192, 168, 1, 0]);
+        let mut msg = route_message(RTM_NEWROUTE, NLM_F_CREATE, 7, (dst, 24), 1000);
+        let buf = msg.finish(1).to_vec();
+
+        let rtmsg = &buf[NLMSG_HDR_LEN..NLMSG_HDR_LEN + RTMSG_LEN];
+        assert_eq!(rtmsg[..2], [AF_INET as u8, 24]);
+        assert_eq!(rtmsg[4], RT_TABLE_UNSPEC);
+        assert_eq!(rtmsg[6], RT_SCOPE_LINK);
+
+        let attrs: Vec<_> = attrs(&buf[NLMSG_HDR_LEN + RTMSG_LEN..]).collect();
+        assert_eq!(attrs[0].0, RTA_TABLE);
+        assert_eq!(read_u32(attrs[0].1), Some(1000));
+        assert_eq!(attrs[1], (RTA_DST, &[192u8, 168, 1, 0][..]));
+        assert_eq!(attrs[2].0, RTA_OIF);
+        assert_eq!(read_u32(attrs[2].1), Some(7));
+
+        let mut msg = route_message(RTM_DELROUTE, 0, 7, (dst, 24), 254);
+        let buf = msg.finish(2).to_vec();
+        assert_eq!(buf[NLMSG_HDR_LEN + 4], 254);
+        assert_eq!(buf[NLMSG_HDR_LEN + 6], RT_SCOPE_NOWHERE);
+    }
+
+    #[test]
+    fn test_rule_message() {
+        const FIB_RULE_HDR_LEN: usize = 12;
+
+        let rule = Rule {
+            ipv6: true,
+            table: 51820,
+            not_fwmark: Some(0xca6c),
+            suppress_prefixlen: None,
+        };
+        assert_eq!(rule.to_string(), "-6 not fwmark 0xca6c table 51820");
+
+        let mut msg = rule_message(RTM_NEWRULE, NLM_F_CREATE, &rule);
+        let buf = msg.finish(1).to_vec();
+        let hdr = &buf[NLMSG_HDR_LEN..NLMSG_HDR_LEN + FIB_RULE_HDR_LEN];
+        assert_eq!(
+            hdr[..8],
+            [
+                AF_INET6 as u8,
+                0,
+                0,
+                0,
+                RT_TABLE_UNSPEC,
+                0,
+                0,
+                FR_ACT_TO_TBL
+            ]
+        );
+        assert_eq!(read_u32(&hdr[8..]), Some(FIB_RULE_INVERT));
+
+        let rule_attrs: Vec<_> = attrs(&buf[NLMSG_HDR_LEN + FIB_RULE_HDR_LEN..]).collect();
+        assert_eq!(rule_attrs.len(), 2);
+        assert_eq!(
+            (rule_attrs[0].0, read_u32(rule_attrs[0].1)),
+            (FRA_TABLE, Some(51820))
+        );
+        assert_eq!(
+            (rule_attrs[1].0, read_u32(rule_attrs[1].1)),
+            (FRA_FWMARK, Some(0xca6c))
+        );
+
+        let rule = Rule {
+            ipv6: false,
+            table: 254,
+            not_fwmark: None,
+            suppress_prefixlen: Some(0),
+        };
+        assert_eq!(rule.to_string(), "-4 table 254 suppress_prefixlength 0");
+        let mut msg = rule_message(RTM_DELRULE, 0, &rule);
+        let buf = msg.finish(2).to_vec();
+        assert_eq!(buf[NLMSG_HDR_LEN + 4], 254);
+        assert_eq!(read_u32(&buf[NLMSG_HDR_LEN + 8..]), Some(0));
+        let rule_attrs: Vec<_> = attrs(&buf[NLMSG_HDR_LEN + FIB_RULE_HDR_LEN..]).collect();
+        assert_eq!(rule_attrs[1].0, FRA_SUPPRESS_PREFIXLEN);
+        assert_eq!(read_u32(rule_attrs[1].1), Some(0));
+    }
+
+    #[test]
+    fn test_route_mtu() {
+        let mut nl = Netlink::new().unwrap();
+        // the loopback route, its interface has a large mtu everywhere
+        let mtu = nl.route_mtu(IpAddr::from([127, 0, 0, 1])).unwrap();
+        assert!(mtu >= 1500, "{mtu}");
+    }
+
+    #[test]
+    fn test_no_such_interface() {
+        let mut nl = Netlink::new().unwrap();
+        assert!(matches!(
+            nl.set_up("caetun-missing", true),
+            Err(Error::NoSuchInterface(_))
+        ));
+    }
+}
@@ -1,4 +1,5 @@
 use crate::peer::PeerName;
+use std::net::IpAddr;
 use thiserror::Error;
 
 /// Packet is the type of the packets that are sent between peers.
@@ -9,6 +10,12 @@ use thiserror::Error;
 /// for `HandshakeInit`, the payload is the assigned index and the sender's name.
 /// for `HandshakeResponse`, the payload is the assigned index and the sender's index.
 /// for `Data`, the payload is the sender's index and the data.
+/// for `Batch`, the payload is the sender's index and several inner packets, each prefixed with its
+/// length as u16.
+///
+/// both handshake packets may carry one extra trailing byte of feature flags (see `FLAG_BATCH`), it is
+/// only sent when a feature is enabled, so peers without any feature enabled stay compatible with
+/// older versions.
 ///
 /// all bytes are sent in little-endian order.
 #[derive(Debug, PartialEq)]
@@ -16,19 +23,25 @@ pub enum Packet<'a> {
     HandshakeInit(HandshakeInit<'a>),
     HandshakeResponse(HandshakeResponse),
     Data(PacketData<'a>),
+    Batch(PacketBatch<'a>),
     Empty,
 }
 
+/// FLAG_BATCH announces that the sender accepts `Batch` packets.
+pub const FLAG_BATCH: u8 = 1;
+
 #[derive(Debug, PartialEq)]
 pub struct HandshakeInit<'a> {
     pub sender_name: PeerName<&'a [u8]>,
     pub assigned_idx: u32,
+    pub flags: u8,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct HandshakeResponse {
     pub assigned_idx: u32,
     pub sender_idx: u32,
+    pub flags: u8,
 }
 
 #[derive(Debug, PartialEq)]
@@ -37,11 +50,22 @@ pub struct PacketData<'a> {
     pub data: &'a [u8],
 }
 
+/// PacketBatch carries several inner packets for the same peer in one datagram.
+///
+/// `data` holds the length prefixed packets, build it with `PacketBatch::append`. A parsed batch has
+/// been checked to be well-formed, so `packets` yields every inner packet.
+#[derive(Debug, PartialEq)]
+pub struct PacketBatch<'a> {
+    pub sender_idx: u32,
+    pub data: &'a [u8],
+}
+
 #[repr(u8)]
 enum PacketType {
     HandshakeInit = 1,
     HandshakeResponse = 2,
     PacketData = 3,
+    PacketBatch = 4,
 }
 
 impl TryFrom<u8> for PacketType {
@@ -52,6 +76,7 @@ impl TryFrom<u8> for PacketType {
             1 => Ok(PacketType::HandshakeInit),
             2 => Ok(PacketType::HandshakeResponse),
             3 => Ok(PacketType::PacketData),
+            4 => Ok(PacketType::PacketBatch),
             _ => Err(PackeParseError::InvalidPacketType(value)),
         }
     }
@@ -59,7 +84,9 @@ impl TryFrom<u8> for PacketType {
 
 const HANDSHAKE_INIT_SIZE: usize = PeerName::max_len() + 5;
 const HANDSHAKE_RESPONSE_SIZE: usize = 9;
-const DATA_MIN_SIZE: usize = 5;
+pub(crate) const DATA_MIN_SIZE: usize = 5;
+/// the length prefix of every packet inside a `Batch`.
+pub(crate) const BATCH_LEN_SIZE: usize = 2;
 
 #[derive(Error, Debug, Copy, Clone)]
 pub enum PackeParseError {
@@ -75,21 +102,27 @@ impl<'a> Packet<'a> {
             return Ok(Packet::Empty);
         }
         match (PacketType::try_from(src[0])?, src.len()) {
-            (PacketType::HandshakeInit, HANDSHAKE_INIT_SIZE) => {
+            (PacketType::HandshakeInit, n)
+                if n == HANDSHAKE_INIT_SIZE || n == HANDSHAKE_INIT_SIZE + 1 =>
+            {
                 let remote_idx = u32::from_le_bytes(src[1..5].try_into().unwrap());
                 let sender_name = PeerName::from(&src[5..105]);
                 Ok(Packet::HandshakeInit(HandshakeInit {
                     sender_name,
                     assigned_idx: remote_idx,
+                    flags: src.get(HANDSHAKE_INIT_SIZE).copied().unwrap_or(0),
                 }))
             }
-            (PacketType::HandshakeResponse, HANDSHAKE_RESPONSE_SIZE) => {
+            (PacketType::HandshakeResponse, n)
+                if n == HANDSHAKE_RESPONSE_SIZE || n == HANDSHAKE_RESPONSE_SIZE + 1 =>
+            {
                 let assigned_idx = u32::from_le_bytes(src[1..5].try_into().unwrap());
                 let sender_idx = u32::from_le_bytes(src[5..9].try_into().unwrap());
 
                 Ok(Packet::HandshakeResponse(HandshakeResponse {
                     assigned_idx,
                     sender_idx,
+                    flags: src.get(HANDSHAKE_RESPONSE_SIZE).copied().unwrap_or(0),
                 }))
             }
             (PacketType::PacketData, n) if n >= DATA_MIN_SIZE => {
@@ -100,6 +133,19 @@ impl<'a> Packet<'a> {
                     data: &src[5..],
                 }))
             }
+            (PacketType::PacketBatch, n) if n >= DATA_MIN_SIZE => {
+                let sender_idx = u32::from_le_bytes(src[1..5].try_into().unwrap());
+                let batch = PacketBatch {
+                    sender_idx,
+                    data: &src[5..],
+                };
+
+                // reject truncated packets here, so that `packets` does not have to
+                if batch.split().any(|packet| packet.is_none()) {
+                    return Err(PackeParseError::ProtocolErr);
+                }
+                Ok(Packet::Batch(batch))
+            }
             _ => Err(PackeParseError::ProtocolErr),
         }
     }
@@ -107,25 +153,33 @@ impl<'a> Packet<'a> {
 
 impl<'a> HandshakeInit<'a> {
     pub fn format(&self, dst: &mut [u8]) -> usize {
-        assert!(dst.len() >= HANDSHAKE_INIT_SIZE);
+        assert!(dst.len() > HANDSHAKE_INIT_SIZE);
 
         dst[0] = PacketType::HandshakeInit as u8;
         dst[1..5].copy_from_slice(&self.assigned_idx.to_le_bytes());
         dst[5..105].copy_from_slice(self.sender_name.as_slice());
 
-        HANDSHAKE_INIT_SIZE
+        if self.flags == 0 {
+            return HANDSHAKE_INIT_SIZE;
+        }
+        dst[HANDSHAKE_INIT_SIZE] = self.flags;
+        HANDSHAKE_INIT_SIZE + 1
     }
 }
 
 impl HandshakeResponse {
     pub fn format(&self, dst: &mut [u8]) -> usize {
-        assert!(dst.len() >= HANDSHAKE_RESPONSE_SIZE);
+        assert!(dst.len() > HANDSHAKE_RESPONSE_SIZE);
 
         dst[0] = PacketType::HandshakeResponse as u8;
         dst[1..5].copy_from_slice(&self.assigned_idx.to_le_bytes());
         dst[5..9].copy_from_slice(&self.sender_idx.to_le_bytes());
 
-        HANDSHAKE_RESPONSE_SIZE
+        if self.flags == 0 {
+            return HANDSHAKE_RESPONSE_SIZE;
+        }
+        dst[HANDSHAKE_RESPONSE_SIZE] = self.flags;
+        HANDSHAKE_RESPONSE_SIZE + 1
     }
 }
 
@@ -143,6 +197,68 @@ impl<'a> PacketData<'a> {
     }
 }
 
+impl<'a> PacketBatch<'a> {
+    /// append a length prefixed `packet` to the batch payload in `batch`.
+    pub fn append(batch: &mut Vec<u8>, packet: &[u8]) {
+        let len = u16::try_from(packet.len()).expect("packet fits into a batch");
+        batch.extend_from_slice(&len.to_le_bytes());
+        batch.extend_from_slice(packet);
+    }
+
+    pub fn format(&self, dst: &mut [u8]) -> usize {
+        let n = self.data.len();
+        let len = n + 5;
+        assert!(dst.len() >= len);
+
+        dst[0] = PacketType::PacketBatch as u8;
+        dst[1..5].copy_from_slice(&self.sender_idx.to_le_bytes());
+        dst[5..(5 + n)].copy_from_slice(self.data);
+
+        len
+    }
+
+    /// returns the inner packets of the batch.
+    pub fn packets(&self) -> impl Iterator<Item = &'a [u8]> {
+        self.split().map_while(|packet| packet)
+    }
+
+    // yields `None` once for a truncated packet
+    fn split(&self) -> impl Iterator<Item = Option<&'a [u8]>> {
+        let mut rest = self.data;
+        std::iter::from_fn(move || {
+            if rest.is_empty() {
+                return None;
+            }
+            let Some((len, tail)) = rest.split_first_chunk::<BATCH_LEN_SIZE>() else {
+                rest = &[];
+                return Some(None);
+            };
+            let len = u16::from_le_bytes(*len) as usize;
+            if tail.len() < len {
+                rest = &[];
+                return Some(None);
+            }
+            let (packet, tail) = tail.split_at(len);
+            rest = tail;
+            Some(Some(packet))
+        })
+    }
+}
+
+/// returns the source and destination address of an inner ipv4 or ipv6 packet, or `None` if `data`
+/// does not start with a valid ip header.
+pub fn ip_addrs(data: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    match data.first().map(|b| b >> 4) {
+        Some(4) => etherparse::Ipv4HeaderSlice::from_slice(data)
+            .ok()
+            .map(|h| (h.source_addr().into(), h.destination_addr().into())),
+        Some(6) => etherparse::Ipv6HeaderSlice::from_slice(data)
+            .ok()
+            .map(|h| (h.source_addr().into(), h.destination_addr().into())),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -154,6 +270,7 @@ mod tests {
         let handshake_init = HandshakeInit {
             sender_name: PeerName::from(slice.as_slice()),
             assigned_idx: 9,
+            flags: 0,
         };
         let mut dst = [0u8; 1024];
         let n = handshake_init.format(&mut dst);
@@ -163,6 +280,34 @@ mod tests {
         assert_eq!(Packet::HandshakeInit(handshake_init), packet);
     }
 
+    #[test]
+    fn test_handshake_flags() {
+        let slice = [0u8; 100];
+
+        let handshake_init = HandshakeInit {
+            sender_name: PeerName::from(slice.as_slice()),
+            assigned_idx: 9,
+            flags: FLAG_BATCH,
+        };
+        let mut dst = [0u8; 1024];
+        let n = handshake_init.format(&mut dst);
+        assert_eq!(HANDSHAKE_INIT_SIZE + 1, n);
+
+        let packet = Packet::parse_from(&dst[..n]).unwrap();
+        assert_eq!(Packet::HandshakeInit(handshake_init), packet);
+
+        let response = HandshakeResponse {
+            assigned_idx: 1,
+            sender_idx: 2,
+            flags: FLAG_BATCH,
+        };
+        let n = response.format(&mut dst);
+        assert_eq!(HANDSHAKE_RESPONSE_SIZE + 1, n);
+
+        let packet = Packet::parse_from(&dst[..n]).unwrap();
+        assert_eq!(Packet::HandshakeResponse(response), packet);
+    }
+
     #[test]
     fn test_packet_data() {
         let data = PacketData {
@@ -177,4 +322,62 @@ mod tests {
         let packet = Packet::parse_from(&dst[..n]).unwrap();
         assert_eq!(Packet::Data(data), packet);
     }
+
+    #[test]
+    fn test_packet_batch() {
+        let mut payload = vec![];
+        PacketBatch::append(&mut payload, &[1, 2, 3]);
+        PacketBatch::append(&mut payload, &[]);
+        PacketBatch::append(&mut payload, &[4; 300]);
+
+        let batch = PacketBatch {
+            sender_idx: 8,
+            data: &payload,
+        };
+
+        let mut dst = [0u8; 1024];
+        let n = batch.format(&mut dst);
+        assert_eq!(5 + 2 + 3 + 2 + 2 + 300, n);
+
+        let Packet::Batch(parsed) = Packet::parse_from(&dst[..n]).unwrap() else {
+            panic!("not a batch");
+        };
+        assert_eq!(batch, parsed);
+        assert_eq!(
+            parsed.packets().collect::<Vec<_>>(),
+            vec![&[1, 2, 3][..], &[], &[4; 300][..]]
+        );
+
+        // truncated inner packet
+        assert!(Packet::parse_from(&dst[..n - 1]).is_err());
+        // truncated length prefix
+        assert!(Packet::parse_from(&dst[..5 + 1]).is_err());
+    }
+
+    #[test]
+    fn test_ip_addrs() {
+        let mut v4 = [0u8; 20];
+        v4[0] = 0x45;
+        v4[2..4].copy_from_slice(&20u16.to_be_bytes());
+        v4[12..16].copy_from_slice(&[10, 8, 0, 2]);
+        v4[16..20].copy_from_slice(&[10, 8, 0, 1]);
+        assert_eq!(
+            ip_addrs(&v4),
+            Some((IpAddr::from([10, 8, 0, 2]), IpAddr::from([10, 8, 0, 1])))
+        );
+
+        let src = "fd00::2".parse::<std::net::Ipv6Addr>().unwrap();
+        let dst = "fd00::1".parse::<std::net::Ipv6Addr>().unwrap();
+        let mut v6 = [0u8; 40];
+        v6[0] = 0x60;
+        v6[6] = 59;
+        v6[7] = 64;
+        v6[8..24].copy_from_slice(&src.octets());
+        v6[24..40].copy_from_slice(&dst.octets());
+        assert_eq!(ip_addrs(&v6), Some((src.into(), dst.into())));
+
+        assert_eq!(ip_addrs(&v6[..39]), None);
+        assert_eq!(ip_addrs(&[]), None);
+        assert_eq!(ip_addrs(&[0x12; 40]), None);
+    }
 }
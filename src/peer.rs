@@ -1,11 +1,14 @@
-use crate::allowed_ip::AllowedIps;
-use crate::device::new_udp_socket;
-use crate::packet::{HandshakeInit, HandshakeResponse, Packet, PacketData};
+use crate::device::{new_udp_socket, SocketOptions};
+use crate::packet::{
+    self, HandshakeInit, HandshakeResponse, Packet, PacketBatch, PacketData, FLAG_BATCH,
+};
+use crate::resolve::Host;
 use anyhow::bail;
 use parking_lot::{RwLock, RwLockReadGuard};
 use std::io;
-use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tracing::{debug, info, instrument};
 
 /// Peer is responsible for the state machine and identity management for a peer.
@@ -21,20 +24,24 @@ pub struct Peer {
     local_idx: u32,
     handshake_state: RwLock<HandshakeState>,
     endpoint: RwLock<Endpoint>,
-    allowed_ips: AllowedIps<()>,
+    /// Whether we offer to aggregate small packets into `Batch` packets, it is only used once the
+    /// other side agreed to it during the handshake.
+    batch: bool,
 }
 
 /// Endpoint is a struct that represents a peer's endpoint.
 #[derive(Default, Debug)]
 pub struct Endpoint {
-    pub addr: Option<SocketAddrV4>,
+    pub addr: Option<SocketAddr>,
     pub conn: Option<Arc<UdpSocket>>,
+    /// the host name `addr` is resolved from, the device resolves it again from time to time.
+    pub host: Option<Host>,
 }
 
 /// Action is a type that represents an action to be taken by the device.
 pub enum Action<'a> {
     /// WriteToTun is an action that writes data to the tun interface.
-    WriteToTun(&'a Peer, &'a [u8], Ipv4Addr),
+    WriteToTun(&'a Peer, &'a [u8], IpAddr),
     /// WriteToNetwork is an action that writes data to the network.
     WriteToNetwork(&'a Peer, &'a [u8]),
     /// None is an action that does nothing.
@@ -49,12 +56,13 @@ pub enum Action<'a> {
 enum HandshakeState {
     /// None is the initial handshake state.
     None,
-    /// HandshakeSent is the handshake state when the handshake has been sent.
-    HandshakeSent,
+    /// HandshakeSent is the handshake state when the handshake has been sent, `at` is when.
+    HandshakeSent { at: Instant },
     /// HandshakeReceived is the handshake state when the handshake has been received.
-    HandshakeReceived { remote_idx: u32 },
-    /// Connected is the handshake state when the handshake is complete.
-    Connected { remote_idx: u32 },
+    HandshakeReceived { remote_idx: u32, batch: bool },
+    /// Connected is the handshake state when the handshake is complete, `batch` tells whether both
+    /// sides agreed to send `Batch` packets.
+    Connected { remote_idx: u32, batch: bool },
 }
 
 impl Default for HandshakeState {
@@ -63,7 +71,7 @@ impl Default for HandshakeState {
     }
 }
 
-const PEER_NAME_MAX_LEN: usize = 100;
+pub const PEER_NAME_MAX_LEN: usize = 100;
 
 /// PeerName is used to identify a peer.
 /// Wireguard identifies peers by their PublicKeys, but we simply use string names here.
@@ -86,6 +94,12 @@ impl<'a> PeerName<&'a [u8]> {
     pub fn as_slice(&self) -> &'a [u8] {
         self.0
     }
+
+    /// returns the name without the trailing zero padding of the wire format.
+    pub fn trim_padding(&self) -> &'a [u8] {
+        let len = self.0.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+        &self.0[..len]
+    }
 }
 
 impl PeerName<[u8; PEER_NAME_MAX_LEN]> {
@@ -118,7 +132,7 @@ impl Peer {
             local_idx: 0,
             handshake_state: RwLock::new(HandshakeState::None),
             endpoint: RwLock::new(peer),
-            allowed_ips: AllowedIps::new(),
+            batch: false,
         }
     }
 
@@ -134,22 +148,31 @@ impl Peer {
         self.local_idx = idx
     }
 
-    pub fn allowed_ips(&self) -> &AllowedIps<()> {
-        &self.allowed_ips
+    /// offer packet aggregation to this peer in the next handshake.
+    pub fn set_batch(&mut self, batch: bool) {
+        self.batch = batch
     }
 
-    pub fn add_allowed_ip(&mut self, addr: Ipv4Addr, cidr: u8) {
-        self.allowed_ips.insert(addr.into(), cidr, ());
+    /// returns whether packets to this peer should be aggregated into `Batch` packets.
+    pub fn batching(&self) -> bool {
+        matches!(
+            *self.handshake_state.read(),
+            HandshakeState::Connected { batch: true, .. }
+        )
     }
 
-    pub fn is_allowed_ip(&self, addr: Ipv4Addr) -> bool {
-        self.allowed_ips.find(addr.into()).is_some()
+    fn flags(&self) -> u8 {
+        if self.batch {
+            FLAG_BATCH
+        } else {
+            0
+        }
     }
 
     // updates the peer endpoint address, and returns if it had a different address
     // and a previous connected UdpSocket
     #[instrument(name = "set_endpoint", skip_all, ret)]
-    pub fn set_endpoint(&self, addr: SocketAddrV4) -> (bool, Option<Arc<UdpSocket>>) {
+    pub fn set_endpoint(&self, addr: SocketAddr) -> (bool, Option<Arc<UdpSocket>>) {
         debug!("setting endpoint to {}", addr);
 
         let endpoint = self.endpoint.read();
@@ -165,26 +188,56 @@ impl Peer {
         (true, endpoint.conn.take())
     }
 
-    pub fn connect_endpoint(&self, port: u16) -> io::Result<Arc<UdpSocket>> {
+    /// set the host name the endpoint is resolved from, the device resolves it when it starts.
+    pub fn set_endpoint_host(&mut self, host: Host) {
+        self.endpoint.get_mut().host = Some(host);
+    }
+
+    /// returns whether a handshake was sent more than `timeout` ago and is still unanswered.
+    pub fn handshake_timed_out(&self, timeout: Duration) -> bool {
+        matches!(
+            *self.handshake_state.read(),
+            HandshakeState::HandshakeSent { at } if at.elapsed() > timeout
+        )
+    }
+
+    /// forget the handshake, so that the next `initiate_handshake` starts a new one.
+    pub fn reset_handshake(&self) {
+        *self.handshake_state.write() = HandshakeState::None;
+    }
+
+    /// connect a socket to the endpoint address and `register` it, in one go under the endpoint
+    /// lock. The event loop and the re-resolution of host names both move the endpoint, when one
+    /// connected a socket since the last move there's nothing left to do for the other: it's
+    /// connected to the current address already, `set_endpoint` takes it on every move, and
+    /// registered before anyone can take it.
+    pub fn connect_endpoint(
+        &self,
+        opts: &SocketOptions,
+        register: impl FnOnce(&UdpSocket) -> io::Result<()>,
+    ) -> io::Result<()> {
         info!("[peer] connect endpoint, peer: {}", self.local_idx);
 
         let mut endpoint = self.endpoint.write();
         let addr = endpoint.addr.expect("addr must not be None");
 
-        assert!(endpoint.conn.is_none());
+        if endpoint.conn.is_some() {
+            debug!("peer {} is connected already", self.local_idx);
+            return Ok(());
+        }
 
-        let conn = new_udp_socket(Some(addr.into()), port)?;
+        let conn = new_udp_socket(Some(addr), opts)?;
 
         info!(
             message="Connected endpoint",
-            port=port,
+            port=opts.port,
             endpoint=?endpoint.addr.unwrap()
         );
 
-        let conn = Arc::new(conn);
-        endpoint.conn = Some(conn.clone());
+        register(&conn)?;
+        endpoint.conn = Some(Arc::new(conn));
 
-        Ok(conn)
+        Ok(())
     }
 
     /// initiate_handshake initiates a handshake with the peer.
@@ -203,10 +256,11 @@ impl Peer {
             let packet = HandshakeInit {
                 sender_name,
                 assigned_idx: self.local_idx(),
+                flags: self.flags(),
             };
             let n = packet.format(dst);
 
-            *state = HandshakeState::HandshakeSent;
+            *state = HandshakeState::HandshakeSent { at: Instant::now() };
 
             debug!("sending handshake");
             Action::WriteToNetwork(self, &dst[..n])
@@ -219,7 +273,7 @@ impl Peer {
     /// if the handshake is complete.
     pub fn encapsulate<'a>(&'a self, src: &'a [u8], dst: &'a mut [u8]) -> Action<'a> {
         let state = self.handshake_state.read();
-        if let HandshakeState::Connected { remote_idx } = &*state {
+        if let HandshakeState::Connected { remote_idx, .. } = &*state {
             let data = PacketData {
                 sender_idx: *remote_idx,
                 data: src,
@@ -231,6 +285,26 @@ impl Peer {
         }
     }
 
+    /// encapsulate_batch is like encapsulate, but `src` holds several length prefixed packets
+    /// built with `PacketBatch::append`. Only use it when `batching` is true.
+    pub fn encapsulate_batch<'a>(&'a self, src: &'a [u8], dst: &'a mut [u8]) -> Action<'a> {
+        let state = self.handshake_state.read();
+        if let HandshakeState::Connected {
+            remote_idx,
+            batch: true,
+        } = &*state
+        {
+            let batch = PacketBatch {
+                sender_idx: *remote_idx,
+                data: src,
+            };
+            let n = batch.format(dst);
+            Action::WriteToNetwork(self, &dst[..n])
+        } else {
+            Action::None
+        }
+    }
+
     pub fn handle_incoming_packet<'a>(
         &'a self,
         packet: Packet<'a>,
@@ -241,6 +315,8 @@ impl Peer {
             Packet::HandshakeInit(msg) => self.handle_handshake_init(msg, dst),
             Packet::HandshakeResponse(msg) => self.handle_handshake_response(msg, dst),
             Packet::Data(msg) => self.handle_packet_data(msg, dst),
+            // the device unpacks batches and hands each inner packet over as `Data`
+            Packet::Batch(_) => Action::None,
         }
     }
 
@@ -253,8 +329,10 @@ impl Peer {
 
         if let HandshakeState::None | HandshakeState::Connected { .. } = &*state {
             debug!("received handshake");
+            let batch = self.batch && msg.flags & FLAG_BATCH != 0;
             *state = HandshakeState::HandshakeReceived {
                 remote_idx: msg.assigned_idx,
+                batch,
             };
             drop(state);
 
@@ -262,6 +340,8 @@ impl Peer {
             let response = HandshakeResponse {
                 assigned_idx: local_idx,
                 sender_idx: msg.assigned_idx,
+                // only acknowledge what both sides support
+                flags: if batch { FLAG_BATCH } else { 0 },
             };
             let n = response.format(dst);
             Action::WriteToNetwork(self, &dst[..n])
@@ -276,11 +356,12 @@ impl Peer {
         dst: &'a mut [u8],
     ) -> Action<'a> {
         let mut state = self.handshake_state.write();
-        if let HandshakeState::HandshakeSent = &*state {
+        if let HandshakeState::HandshakeSent { .. } = &*state {
             debug!("received handshake response, transitioning to Connected state");
 
             *state = HandshakeState::Connected {
                 remote_idx: msg.assigned_idx,
+                batch: self.batch && msg.flags & FLAG_BATCH != 0,
             };
             drop(state);
 
@@ -298,22 +379,93 @@ impl Peer {
             HandshakeState::Connected { .. } => {
                 debug!("peer is connected");
             }
-            HandshakeState::HandshakeReceived { remote_idx } => {
+            HandshakeState::HandshakeReceived { remote_idx, batch } => {
                 debug!("received a first data packet, transitioning to Connected state");
-                let remote_idx = *remote_idx;
+                let (remote_idx, batch) = (*remote_idx, *batch);
                 drop(state);
 
                 let mut state = self.handshake_state.write();
-                *state = HandshakeState::Connected { remote_idx };
+                *state = HandshakeState::Connected { remote_idx, batch };
             }
             _ => return Action::None,
         };
-        match etherparse::Ipv4HeaderSlice::from_slice(msg.data) {
-            Ok(iph) => {
-                let src = iph.source_addr();
-                Action::WriteToTun(self, msg.data, src)
-            }
-            _ => Action::None,
+        match packet::ip_addrs(msg.data) {
+            Some((src, _)) => Action::WriteToTun(self, msg.data, src),
+            None => Action::None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    // runs a handshake from `client` to `server` and the first data packet back
+    fn handshake(client: &Peer, server: &Peer) {
+        let name = PeerName::new("client").unwrap();
+        let mut init = [0u8; 1024];
+        let mut response = [0u8; 1024];
+        let mut data = [0u8; 1024];
+
+        let Action::WriteToNetwork(_, init) = client.initiate_handshake(name.as_ref(), &mut init)
+        else {
+            panic!("no handshake sent");
+        };
+        let packet = Packet::parse_from(init).unwrap();
+        let Action::WriteToNetwork(_, response) =
+            server.handle_incoming_packet(packet, &mut response)
+        else {
+            panic!("no handshake response sent");
+        };
+        let packet = Packet::parse_from(response).unwrap();
+        let Action::WriteToNetwork(_, data) = client.handle_incoming_packet(packet, &mut data)
+        else {
+            panic!("no data sent");
+        };
+        let packet = Packet::parse_from(data).unwrap();
+        server.handle_incoming_packet(packet, &mut [0u8; 1024]);
+    }
+
+    fn peer(batch: bool) -> Peer {
+        let mut peer = Peer::default();
+        peer.set_batch(batch);
+        peer
+    }
+
+    #[test]
+    fn test_connect_endpoint() {
+        let peer = Peer::default();
+        let opts = SocketOptions::default();
+        let mut ports = Vec::new();
+        let mut register = |conn: &UdpSocket| {
+            ports.push(conn.peer_addr()?.port());
+            Ok(())
+        };
+
+        peer.set_endpoint(SocketAddr::from((Ipv4Addr::LOCALHOST, 19988)));
+        peer.connect_endpoint(&opts, &mut register).unwrap();
+        // connected by the other thread in the meantime
+        peer.connect_endpoint(&opts, &mut register).unwrap();
+
+        let (changed, conn) = peer.set_endpoint(SocketAddr::from((Ipv4Addr::LOCALHOST, 19989)));
+        assert!(changed && conn.is_some());
+        peer.connect_endpoint(&opts, &mut register).unwrap();
+        assert_eq!(ports, [19988, 19989]);
+    }
+
+    #[test]
+    fn test_batch_negotiation() {
+        for (client_batch, server_batch) in [(true, true), (true, false), (false, true)] {
+            let client = peer(client_batch);
+            client.set_endpoint(SocketAddr::from((Ipv4Addr::LOCALHOST, 19988)));
+            let server = peer(server_batch);
+
+            handshake(&client, &server);
+
+            let expected = client_batch && server_batch;
+            assert_eq!(client.batching(), expected);
+            assert_eq!(server.batching(), expected);
         }
     }
 }
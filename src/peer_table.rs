@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::allowed_ip::{AllowedIps, SharedAllowedIps};
+use crate::peer::{Peer, PeerName};
+
+/// PeerTable owns every peer of a device and the indexes used to find them.
+///
+/// All three lookups are independent of the number of peers:
+///
+/// - by name: used for `HandshakeInit`, keyed by the name without its zero padding, so a peer only
+///   costs as many key bytes as its name is long rather than `PeerName::max_len()`.
+/// - by index: used for `HandshakeResponse`, `Data` and connected socket tokens, a plain `Vec` slot.
+/// - by ip: used for routing outgoing packets and filtering the source of incoming ones, a longest
+///   prefix match on the shared `AllowedIps` trie.
+///
+/// The names and indexes are fixed once the device runs, the trie is not: it lives in a
+/// `SharedAllowedIps` so prefixes can be moved between peers from another thread while the event
+/// loop keeps reading consistent snapshots.
+///
+/// Peers do not keep a trie, or a list, of their own prefixes: the shared trie is the only record
+/// of them, so moving a prefix to another peer can't leave a stale copy behind. Source filtering
+/// looks the source address up in the shared trie and checks that it resolves back to the peer the
+/// packet came from, which is also what keeps overlapping prefixes consistent between the two
+/// directions.
+#[derive(Default)]
+pub struct PeerTable {
+    by_name: HashMap<Box<[u8]>, u32>,
+    by_idx: Vec<Arc<Peer>>,
+    by_ip: SharedAllowedIps<Arc<Peer>>,
+}
+
+impl PeerTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// reserve room for `additional` more peers, so that loading a large config does not rehash
+    /// the name index over and over.
+    pub fn reserve(&mut self, additional: usize) {
+        self.by_name.reserve(additional);
+        self.by_idx.reserve(additional);
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_idx.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_idx.is_empty()
+    }
+
+    /// insert a peer that is allowed to use `allowed_ips`, assigning it the next local index.
+    ///
+    /// a peer reusing an existing name takes over the name lookup, the previous peer stays
+    /// reachable by its index.
+    pub fn insert(
+        &mut self,
+        name: PeerName,
+        mut peer: Peer,
+        allowed_ips: &[(IpAddr, u8)],
+    ) -> Arc<Peer> {
+        let local_idx = self.by_idx.len() as u32;
+        peer.set_local_idx(local_idx);
+
+        let peer = Arc::new(peer);
+
+        self.by_name
+            .insert(name.as_ref().trim_padding().into(), local_idx);
+        self.by_ip.get_mut().extend(
+            allowed_ips
+                .iter()
+                .map(|&(ip, cidr)| (ip, cidr, Arc::clone(&peer))),
+        );
+        self.by_idx.push(Arc::clone(&peer));
+
+        peer
+    }
+
+    /// find a peer by name, `name` may still carry the zero padding of the wire format.
+    pub fn by_name(&self, name: PeerName<&[u8]>) -> Option<&Arc<Peer>> {
+        self.by_name
+            .get(name.trim_padding())
+            .and_then(|idx| self.by_idx(*idx))
+    }
+
+    pub fn by_idx(&self, idx: u32) -> Option<&Arc<Peer>> {
+        self.by_idx.get(idx as usize)
+    }
+
+    /// find the peer an outgoing packet to `dst` should be routed to.
+    ///
+    /// every call looks at the latest snapshot, use `routes` to route a batch of packets with the
+    /// same one.
+    pub fn by_ip(&self, dst: IpAddr) -> Option<Arc<Peer>> {
+        self.by_ip.load().find(dst).cloned()
+    }
+
+    /// returns a consistent snapshot of the routing table.
+    pub(crate) fn routes(&self) -> Arc<AllowedIps<Arc<Peer>>> {
+        self.by_ip.load()
+    }
+
+    /// check that a packet with source address `src` may be accepted from `peer`.
+    pub fn is_allowed_ip(&self, peer: &Peer, src: IpAddr) -> bool {
+        self.by_ip
+            .load()
+            .find(src)
+            .is_some_and(|p| p.local_idx() == peer.local_idx())
+    }
+
+    /// route `ip/cidr` to `peer`, returning the peer it was routed to before, if any.
+    ///
+    /// safe to call while the device is running, see `SharedAllowedIps` for the cost.
+    pub fn insert_allowed_ip(&self, peer: &Arc<Peer>, ip: IpAddr, cidr: u8) -> Option<Arc<Peer>> {
+        self.by_ip.insert(ip, cidr, Arc::clone(peer))
+    }
+
+    /// remove every prefix routed to `peer`, returning the removed prefixes.
+    ///
+    /// safe to call while the device is running, see `SharedAllowedIps` for the cost.
+    pub fn remove_allowed_ips(&self, peer: &Peer) -> Vec<(IpAddr, u8)> {
+        let local_idx = peer.local_idx();
+        self.by_ip.remove(|p| p.local_idx() == local_idx)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Peer>> {
+        self.by_idx.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn insert(table: &mut PeerTable, name: &str, allowed_ips: &[(Ipv4Addr, u8)]) -> Arc<Peer> {
+        let allowed_ips: Vec<(IpAddr, u8)> = allowed_ips
+            .iter()
+            .map(|&(ip, cidr)| (ip.into(), cidr))
+            .collect();
+        table.insert(PeerName::new(name).unwrap(), Peer::default(), &allowed_ips)
+    }
+
+    #[test]
+    fn test_peer_table_lookup() {
+        let mut table = PeerTable::new();
+        insert(&mut table, "client1", &[(Ipv4Addr::new(10, 8, 0, 2), 32)]);
+        insert(&mut table, "server", &[(Ipv4Addr::new(10, 8, 0, 0), 24)]);
+
+        assert_eq!(table.len(), 2);
+
+        let padded = PeerName::new("server").unwrap();
+        assert_eq!(table.by_name(padded.as_ref()).unwrap().local_idx(), 1);
+        assert_eq!(
+            table
+                .by_name(PeerName::from(b"client1".as_slice()))
+                .unwrap()
+                .local_idx(),
+            0
+        );
+        assert!(table
+            .by_name(PeerName::from(b"client".as_slice()))
+            .is_none());
+
+        assert_eq!(table.by_idx(0).unwrap().local_idx(), 0);
+        assert!(table.by_idx(2).is_none());
+
+        let dst = IpAddr::from([10, 8, 0, 2]);
+        assert_eq!(table.by_ip(dst).unwrap().local_idx(), 0);
+        let dst = IpAddr::from([10, 8, 0, 3]);
+        assert_eq!(table.by_ip(dst).unwrap().local_idx(), 1);
+    }
+
+    #[test]
+    fn test_peer_table_is_allowed_ip() {
+        let mut table = PeerTable::new();
+        let client = insert(&mut table, "client1", &[(Ipv4Addr::new(10, 8, 0, 2), 32)]);
+        let server = insert(&mut table, "server", &[(Ipv4Addr::new(10, 8, 0, 0), 24)]);
+
+        assert!(table.is_allowed_ip(&client, IpAddr::from([10, 8, 0, 2])));
+        assert!(!table.is_allowed_ip(&client, IpAddr::from([10, 8, 0, 3])));
+        // the more specific prefix belongs to client1, so server can not spoof it
+        assert!(!table.is_allowed_ip(&server, IpAddr::from([10, 8, 0, 2])));
+        assert!(table.is_allowed_ip(&server, IpAddr::from([10, 8, 0, 3])));
+        assert!(!table.is_allowed_ip(&server, IpAddr::from([10, 9, 0, 1])));
+    }
+
+    #[test]
+    fn test_peer_table_update_allowed_ips() {
+        let mut table = PeerTable::new();
+        let client = insert(&mut table, "client1", &[(Ipv4Addr::new(10, 8, 0, 2), 32)]);
+        let site = insert(&mut table, "site", &[]);
+
+        let lan = IpAddr::from([192, 168, 1, 10]);
+        assert!(table.by_ip(lan).is_none());
+
+        let prev = table.insert_allowed_ip(&site, IpAddr::from([192, 168, 1, 0]), 24);
+        assert!(prev.is_none());
+        assert_eq!(table.by_ip(lan).unwrap().local_idx(), site.local_idx());
+        assert!(table.is_allowed_ip(&site, lan));
+
+        let prev = table.insert_allowed_ip(&client, IpAddr::from([192, 168, 1, 0]), 24);
+        assert_eq!(prev.unwrap().local_idx(), site.local_idx());
+        assert!(!table.is_allowed_ip(&site, lan));
+
+        let mut removed = table.remove_allowed_ips(&client);
+        removed.sort();
+        assert_eq!(
+            removed,
+            vec![
+                (IpAddr::from([10, 8, 0, 2]), 32),
+                (IpAddr::from([192, 168, 1, 0]), 24)
+            ]
+        );
+        assert!(table.by_ip(lan).is_none());
+        assert!(table.by_ip(IpAddr::from([10, 8, 0, 2])).is_none());
+    }
+}
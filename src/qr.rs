@@ -0,0 +1,178 @@
+use anyhow::bail;
+use qrcode::types::QrError;
+use qrcode::{Color, EcLevel, QrCode};
+
+use crate::conf::Conf;
+
+/// the light modules around a code scanners need to find it.
+const QUIET_ZONE: usize = 4;
+
+/// the pixels per module of a PNG.
+const PNG_SCALE: usize = 8;
+
+/// the most bytes a QR code holds at the error correction level of `encode`.
+pub const MAX_BYTES: usize = 2331;
+
+/// encode a config as a QR code of the INI `convert` writes, the same settings the JSON output
+/// has. Configs of more than `MAX_BYTES` don't fit in one.
+pub fn encode(conf: &Conf) -> anyhow::Result<QrCode> {
+    let ini = conf.to_ini();
+    // M survives a smudged screen, and leaves room for the config of a hub with a few dozen peers
+    match QrCode::with_error_correction_level(&ini, EcLevel::M) {
+        Ok(code) => Ok(code),
+        Err(QrError::DataTooLong) => bail!(
+            "config is {} bytes, a QR code holds {MAX_BYTES} at most",
+            ini.len()
+        ),
+        Err(err) => bail!("failed to encode config: {err}"),
+    }
+}
+
+/// the modules of `code` with its quiet zone, by row.
+fn modules(code: &QrCode) -> Vec<Vec<bool>> {
+    let width = code.width();
+    let colors = code.to_colors();
+    let size = width + 2 * QUIET_ZONE;
+    (0..size)
+        .map(|y| {
+            (0..size)
+                .map(|x| {
+                    let (x, y) = (x.wrapping_sub(QUIET_ZONE), y.wrapping_sub(QUIET_ZONE));
+                    x < width && y < width && colors[y * width + x] == Color::Dark
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// render `code` for a terminal, two rows of modules per line in half blocks, dark on a light
+/// background whatever the colors of the terminal are.
+pub fn ansi(code: &QrCode) -> String {
+    let modules = modules(code);
+    let mut out = String::new();
+    for rows in modules.chunks(2) {
+        out.push_str("\x1b[30;107m");
+        for (x, &top) in rows[0].iter().enumerate() {
+            let bottom = rows.get(1).is_some_and(|row| row[x]);
+            out.push(match (top, bottom) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            });
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    out
+}
+
+pub fn svg(code: &QrCode) -> String {
+    code.render::<qrcode::render::svg::Color>()
+        .quiet_zone(true)
+        .min_dimensions(256, 256)
+        .build()
+}
+
+/// render `code` as a grayscale PNG, `PNG_SCALE` pixels per module.
+pub fn png(code: &QrCode) -> anyhow::Result<Vec<u8>> {
+    let modules = modules(code);
+    let size = modules.len() * PNG_SCALE;
+    let mut pixels = Vec::with_capacity(size * size);
+    for row in &modules {
+        let line: Vec<u8> = row
+            .iter()
+            .flat_map(|&dark| [if dark { 0 } else { 255 }; PNG_SCALE])
+            .collect();
+        for _ in 0..PNG_SCALE {
+            pixels.extend_from_slice(&line);
+        }
+    }
+
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Write as _;
+
+    /// the text of the modules, `#` for dark ones.
+    fn text(code: &QrCode) -> String {
+        let mut out = String::new();
+        for row in modules(code) {
+            let line: String = row
+                .iter()
+                .map(|&dark| if dark { '#' } else { ' ' })
+                .collect();
+            writeln!(out, "{line}").unwrap();
+        }
+        out
+    }
+
+    fn conf(peers: usize) -> Conf {
+        let mut ini = "[Interface]\nName=phone\nAddress=10.8.0.2/24\n".to_string();
+        for i in 0..peers {
+            write!(
+                ini,
+                "[Peer]\nName=server{i}\nEndpoint=vpn{i}.example.com:19988\n\
+                 AllowedIPs=10.{i}.0.0/16\n"
+            )
+            .unwrap();
+        }
+        Conf::parse_from(&ini).unwrap()
+    }
+
+    #[test]
+    fn test_render() {
+        let code = encode(&conf(1)).unwrap();
+        let size = code.width() + 2 * QUIET_ZONE;
+
+        let text = text(&code);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), size);
+        assert!(lines[..QUIET_ZONE]
+            .iter()
+            .all(|line| line.trim().is_empty()));
+        // the finder pattern in the top left corner
+        assert_eq!(&lines[QUIET_ZONE][QUIET_ZONE..QUIET_ZONE + 8], "####### ");
+        assert_eq!(
+            &lines[QUIET_ZONE + 2][QUIET_ZONE..QUIET_ZONE + 8],
+            "# ### # "
+        );
+
+        let ansi = ansi(&code);
+        assert_eq!(ansi.lines().count(), size.div_ceil(2));
+        let line = ansi.lines().nth(QUIET_ZONE / 2).unwrap();
+        assert!(line.starts_with("\x1b[30;107m    █▀▀▀▀▀█ "), "{line}");
+
+        let png = png(&code).unwrap();
+        let decoder = png::Decoder::new(png.as_slice());
+        let reader = decoder.read_info().unwrap();
+        let info = reader.info();
+        assert_eq!(info.width as usize, size * PNG_SCALE);
+        assert_eq!(info.color_type, png::ColorType::Grayscale);
+
+        let svg = svg(&code);
+        assert!(svg.starts_with("<?xml"), "{svg}");
+    }
+
+    #[test]
+    fn test_too_large() {
+        assert!(encode(&conf(20)).is_ok());
+        let Err(err) = encode(&conf(60)) else {
+            panic!("60 peers fit in a QR code");
+        };
+        let err = err.to_string();
+        assert!(
+            err.ends_with("bytes, a QR code holds 2331 at most"),
+            "{err}"
+        );
+    }
+}
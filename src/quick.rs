@@ -0,0 +1,533 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::os::fd::{FromRawFd, IntoRawFd};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::conf::{Conf, Endpoint};
+use crate::device::{self, Device, DeviceConfig};
+use crate::dns;
+use crate::hooks::{Hooks, Stage};
+use crate::peer::{Peer, PeerName};
+use crate::route;
+use crate::validate;
+
+/// where the state of the running interfaces is kept, `CAETUN_RUN_DIR` overrides it.
+const RUN_DIR: &str = "/run/caetun";
+
+/// how long `down` waits for the daemon to exit before it kills it.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// State records what `up` set up for an interface, so that `down` can tear it down without the
+/// config, which may have changed in the meantime.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct State {
+    pub name: String,
+    pub pid: u32,
+    pub conf: PathBuf,
+    pub address: Vec<(IpAddr, u8)>,
+    pub mtu: u32,
+    pub table: Option<u32>,
+    /// the firewall mark, its routing rules are removed with the interface.
+    #[serde(default)]
+    pub fwmark: Option<u32>,
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// how the dns setting was applied, if there was one.
+    #[serde(default)]
+    pub dns: Option<dns::Applied>,
+}
+
+impl State {
+    /// the directory the states are kept in, `CAETUN_RUN_DIR` or `RUN_DIR`. The functions here
+    /// take it as an argument, so only the binary reads the environment.
+    pub fn dir() -> PathBuf {
+        std::env::var_os("CAETUN_RUN_DIR").map_or_else(|| PathBuf::from(RUN_DIR), PathBuf::from)
+    }
+
+    pub fn path(dir: &Path, name: &str) -> PathBuf {
+        dir.join(format!("{name}.json"))
+    }
+
+    /// where the resolv.conf replaced for interface `name` is kept.
+    pub fn resolv_conf_backup(dir: &Path, name: &str) -> PathBuf {
+        dir.join(format!("{name}.resolv.conf"))
+    }
+
+    /// the file the daemon of interface `name` logs to.
+    pub fn log_path(dir: &Path, name: &str) -> PathBuf {
+        dir.join(format!("{name}.log"))
+    }
+
+    pub fn load(dir: &Path, name: &str) -> anyhow::Result<Option<Self>> {
+        let path = Self::path(dir, name);
+        match fs::read(&path) {
+            Ok(data) => {
+                Ok(Some(serde_json::from_slice(&data).with_context(|| {
+                    format!("invalid state file {}", path.display())
+                })?))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, dir: &Path) -> anyhow::Result<()> {
+        let path = Self::path(dir, &self.name);
+        fs::create_dir_all(dir)?;
+        // write and rename, so that `down` never reads half a file
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, &path).with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    pub fn remove(&self, dir: &Path) -> io::Result<()> {
+        match fs::remove_file(Self::path(dir, &self.name)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// whether the daemon recorded in the state is still running.
+    pub fn is_running(&self) -> bool {
+        is_alive(self.pid)
+    }
+}
+
+/// the interface name for a config file: its file stem, like wg-quick.
+pub fn iface_name(conf_path: &Path) -> anyhow::Result<&str> {
+    match conf_path.file_stem().and_then(|s| s.to_str()) {
+        Some(name) if !name.is_empty() => Ok(name),
+        _ => bail!("invalid filename {}", conf_path.display()),
+    }
+}
+
+/// create the device of `conf` on tun interface `tun_name`: configure the interface, install the
+/// routes and add every peer.
+pub fn device(tun_name: &str, conf: &Conf, use_connected_peer: bool) -> anyhow::Result<Device> {
+    // host name endpoints are only resolved once the device starts, they don't count
+    let mtu = conf.interface.mtu.unwrap_or_else(|| {
+        device::auto_mtu(
+            conf.peers
+                .iter()
+                .filter_map(|p| p.endpoint.as_ref()?.addr()),
+        )
+    });
+
+    let mut config = DeviceConfig::new(
+        PeerName::new(&conf.interface.name)?,
+        tun_name,
+        conf.interface.listen_port,
+        use_connected_peer,
+    )
+    .with_addresses(conf.interface.address.clone())
+    .with_mtu(mtu);
+    if let Some(table) = conf.interface.table {
+        config = config.with_table(table);
+    }
+    if let Some(address) = conf.interface.listen_address {
+        config = config.with_listen_address(address);
+    }
+    if let Some(iface) = &conf.interface.bind_interface {
+        config = config.with_bind_interface(iface);
+    }
+    if let Some(fwmark) = conf.interface.fwmark {
+        config = config.with_fwmark(fwmark);
+    }
+    if let Some(interval) = conf.interface.resolve_interval {
+        config = config.with_resolve_interval(interval);
+    }
+    let mut dev = Device::new(config)?;
+
+    dev.reserve_peers(conf.peers.len());
+    for peer_conf in &conf.peers {
+        let peer_name = PeerName::new(&peer_conf.name)?;
+        let mut peer = Peer::default();
+        match &peer_conf.endpoint {
+            Some(Endpoint::Addr(addr)) => {
+                peer.set_endpoint(*addr);
+            }
+            Some(Endpoint::Host(host)) => peer.set_endpoint_host(host.clone()),
+            None => (),
+        }
+        peer.set_batch(peer_conf.batch);
+        dev.add_peer(peer_name, peer, &peer_conf.allowed_ips);
+    }
+
+    Ok(dev)
+}
+
+/// fail if interface `name` is already up according to the states in `run_dir`, a state left
+/// behind by a daemon that is gone is removed.
+pub fn check_not_up(run_dir: &Path, name: &str) -> anyhow::Result<()> {
+    if let Some(state) = State::load(run_dir, name)? {
+        if state.is_running() {
+            bail!("{name} is already up, pid {}", state.pid);
+        }
+        warn!(
+            "removing the stale state of {name}, pid {} is gone",
+            state.pid
+        );
+        state.remove(run_dir)?;
+    }
+    Ok(())
+}
+
+/// bring up interface `name` from `conf`: run PreUp, create and start the device, apply the dns
+/// setting, run PostUp and record the state in `run_dir`. A failing PreUp or PostUp fails it all,
+/// what failed after PreUp is rolled back like `down` tears it down.
+///
+/// the caller keeps the returned device running with `wait`.
+pub fn up(
+    run_dir: &Path,
+    name: &str,
+    conf_path: &Path,
+    conf: &Conf,
+    use_connected_peer: bool,
+) -> anyhow::Result<Device> {
+    let hooks = &conf.interface.hooks;
+
+    validate::check(conf)?;
+    hooks.run(Stage::PreUp, name)?;
+    let dev = match device(name, conf, use_connected_peer) {
+        Ok(dev) => dev,
+        Err(err) => return Err(rollback(name, hooks, None, None, err)),
+    };
+    if let Err(err) = dev.start() {
+        return Err(rollback(name, hooks, Some(dev), None, err.into()));
+    }
+
+    let dns = match conf.interface.dns.is_empty() {
+        true => None,
+        false => {
+            let backup = State::resolv_conf_backup(run_dir, name);
+            let applied = fs::create_dir_all(run_dir)
+                .map_err(anyhow::Error::from)
+                .and_then(|()| dns::apply(name, &conf.interface.dns, &backup))
+                .context("failed to apply dns");
+            match applied {
+                Ok(applied) => Some(applied),
+                Err(err) => return Err(rollback(name, hooks, Some(dev), None, err)),
+            }
+        }
+    };
+    if let Err(err) = hooks.run(Stage::PostUp, name) {
+        return Err(rollback(name, hooks, Some(dev), dns.as_ref(), err.into()));
+    }
+
+    let state = State {
+        name: name.to_string(),
+        pid: process::id(),
+        conf: conf_path.canonicalize().unwrap_or(conf_path.to_path_buf()),
+        address: conf.interface.address.clone(),
+        mtu: dev.mtu() as u32,
+        table: dev.route_table(),
+        fwmark: conf.interface.fwmark,
+        hooks: hooks.clone(),
+        dns,
+    };
+    if let Err(err) = state.save(run_dir) {
+        let err = err.context("failed to record the interface state");
+        return Err(rollback(name, hooks, Some(dev), state.dns.as_ref(), err));
+    }
+
+    info!("{name} is up, pid {}", state.pid);
+    Ok(dev)
+}
+
+/// undo what a failed `up` of interface `name` set up after its PreUp: run PreDown, drop the
+/// device, which takes the interface, its routes and the routing rules of the firewall mark with
+/// it, restore the dns configuration and run PostDown. Failures along the way are logged, `err` is
+/// what failed `up` and is returned.
+fn rollback(
+    name: &str,
+    hooks: &Hooks,
+    dev: Option<Device>,
+    dns: Option<&dns::Applied>,
+    err: anyhow::Error,
+) -> anyhow::Error {
+    warn!("{name} failed to come up, rolling back: {err:#}");
+    if let Err(err) = hooks.run(Stage::PreDown, name) {
+        warn!("{err}");
+    }
+    drop(dev);
+    if let Some(dns) = dns {
+        if let Err(err) = dns::restore(dns) {
+            warn!("failed to restore dns: {err:#}");
+        }
+    }
+    if let Err(err) = hooks.run(Stage::PostDown, name) {
+        warn!("{err}");
+    }
+    err
+}
+
+/// tear down interface `name` from its state in `run_dir`: run PreDown, stop its daemon, which
+/// takes the interface, its addresses and routes with it, remove the routing rules of the firewall
+/// mark, restore the dns configuration, run PostDown and remove the recorded state.
+///
+/// failing hooks are logged, they don't stop the tear down. Neither do the routing rules or the
+/// dns configuration when they can't be undone, the state is removed all the same and they fail
+/// `down` once it's done.
+pub fn down(run_dir: &Path, name: &str) -> anyhow::Result<State> {
+    let Some(state) = State::load(run_dir, name)? else {
+        bail!("{name} is not up, no state in {}", run_dir.display());
+    };
+
+    if let Err(err) = state.hooks.run(Stage::PreDown, name) {
+        warn!("{err}");
+    }
+    if state.is_running() {
+        stop(state.pid)?;
+    } else {
+        warn!("pid {} of {name} is already gone", state.pid);
+    }
+    let mut failed = Vec::new();
+    // a daemon that is killed leaves them behind
+    if let (Some(fwmark), Some(table)) = (state.fwmark, state.table) {
+        if let Err(err) = route::remove_rules(fwmark, table) {
+            let err = format!("failed to remove the routing rules: {err}");
+            warn!("{err}");
+            failed.push(err);
+        }
+    }
+    if let Some(dns) = &state.dns {
+        if let Err(err) = dns::restore(dns) {
+            let err = format!("failed to restore dns: {err:#}");
+            warn!("{err}");
+            failed.push(err);
+        }
+    }
+    if let Err(err) = state.hooks.run(Stage::PostDown, name) {
+        warn!("{err}");
+    }
+    state.remove(run_dir)?;
+
+    if !failed.is_empty() {
+        bail!("{name} is down, but {}", failed.join(", "));
+    }
+    Ok(state)
+}
+
+fn stop(pid: u32) -> anyhow::Result<()> {
+    signal(pid, libc::SIGTERM)?;
+
+    let start = Instant::now();
+    while is_alive(pid) {
+        if start.elapsed() > STOP_TIMEOUT {
+            warn!("pid {pid} did not exit within {STOP_TIMEOUT:?}, killing it");
+            signal(pid, libc::SIGKILL)?;
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
+
+fn signal(pid: u32, sig: libc::c_int) -> io::Result<()> {
+    match unsafe { libc::kill(pid as _, sig) } {
+        -1 if io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH) => {
+            Err(io::Error::last_os_error())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn is_alive(pid: u32) -> bool {
+    // signal 0 only checks that the process exists, EPERM means it does but belongs to someone else
+    match unsafe { libc::kill(pid as _, 0) } {
+        0 => true,
+        _ => io::Error::last_os_error().raw_os_error() == Some(libc::EPERM),
+    }
+}
+
+/// Ready tells the process that started a daemon whether it came up.
+pub struct Ready(File);
+
+impl Ready {
+    pub fn ok(mut self) {
+        let _ = self.0.write_all(b"\0");
+    }
+
+    pub fn fail(mut self, err: &anyhow::Error) {
+        let _ = write!(self.0, "{err:#}");
+    }
+}
+
+/// the pipe the daemon reports on, closed on exec: a hook that leaves a job in the background
+/// would keep it open otherwise, and the parent waiting for its end.
+fn ready_pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    #[cfg(target_os = "linux")]
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // no pipe2 on macOS, nothing can fork in between as there are no other threads yet
+    #[cfg(not(target_os = "linux"))]
+    {
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for fd in fds {
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+/// detach from the terminal, like wg-quick returns once the interface is up.
+///
+/// the calling process waits until the daemon reports through `Ready` and exits with its result,
+/// only the daemon returns from here. Its output goes to `State::log_path` in `run_dir`. Call it
+/// before any thread is spawned.
+pub fn daemonize(run_dir: &Path, name: &str) -> anyhow::Result<Ready> {
+    let log_path = State::log_path(run_dir, name);
+    fs::create_dir_all(run_dir)?;
+    let log = File::create(&log_path)
+        .with_context(|| format!("failed to create {}", log_path.display()))?;
+
+    let (mut rx, tx) = ready_pipe()?;
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error().into()),
+        0 => {
+            drop(rx);
+            unsafe { libc::setsid() };
+
+            // both are closed on exec, only their duplicates are inherited by the hooks
+            let log = log.into_raw_fd();
+            let null = File::open("/dev/null")?.into_raw_fd();
+            unsafe {
+                libc::dup2(null, libc::STDIN_FILENO);
+                libc::dup2(log, libc::STDOUT_FILENO);
+                libc::dup2(log, libc::STDERR_FILENO);
+                libc::close(null);
+                libc::close(log);
+            }
+            Ok(Ready(tx))
+        }
+        pid => {
+            drop(tx);
+            let mut status = Vec::new();
+            rx.read_to_end(&mut status)?;
+
+            match status.as_slice() {
+                b"\0" => {
+                    println!("{name} is up, pid {pid}, logging to {}", log_path.display());
+                    process::exit(0)
+                }
+                b"" => bail!("the daemon exited early, see {}", log_path.display()),
+                err => bail!("{}", String::from_utf8_lossy(err)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::AsRawFd;
+
+    #[test]
+    fn test_ready_pipe() {
+        let (rx, tx) = ready_pipe().unwrap();
+        for fd in [rx.as_raw_fd(), tx.as_raw_fd()] {
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+            assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+        }
+    }
+
+    #[test]
+    fn test_state() {
+        let dir = std::env::temp_dir().join(format!("caetun-state-{}", process::id()));
+        let dir = dir.as_path();
+
+        assert!(State::load(dir, "tun9").unwrap().is_none());
+        assert!(check_not_up(dir, "tun9").is_ok());
+
+        let mut state = State {
+            name: "tun9".into(),
+            pid: process::id(),
+            conf: "/etc/caetun/tun9.conf".into(),
+            address: vec![(IpAddr::from([10, 8, 0, 2]), 24)],
+            mtu: 1420,
+            table: Some(254),
+            fwmark: None,
+            hooks: Hooks::default(),
+            dns: None,
+        };
+        state.save(dir).unwrap();
+        assert_eq!(State::load(dir, "tun9").unwrap(), Some(state.clone()));
+        assert!(check_not_up(dir, "tun9").is_err());
+
+        // a pid that can't exist
+        state.pid = i32::MAX as u32;
+        state.save(dir).unwrap();
+        assert!(check_not_up(dir, "tun9").is_ok());
+        assert!(State::load(dir, "tun9").unwrap().is_none());
+
+        state.save(dir).unwrap();
+        assert_eq!(down(dir, "tun9").unwrap().name, "tun9");
+        assert!(down(dir, "tun9").is_err());
+
+        // dns that can't be restored doesn't keep the interface up, or skip PostDown
+        let post_down = dir.join("post-down");
+        state.hooks.post_down = Some(format!("touch {}", post_down.display()));
+        state.dns = Some(dns::Applied::File {
+            path: dir.join("resolv.conf"),
+            previous: dns::Previous::File {
+                backup: dir.join("missing"),
+            },
+        });
+        state.save(dir).unwrap();
+        let err = down(dir, "tun9").unwrap_err().to_string();
+        assert!(
+            err.starts_with("tun9 is down, but failed to restore dns: failed to restore "),
+            "{err}"
+        );
+        assert!(post_down.exists());
+        assert!(State::load(dir, "tun9").unwrap().is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rollback() {
+        let dir = std::env::temp_dir().join(format!("caetun-rollback-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("hooks.log");
+        let hooks = Hooks {
+            pre_down: Some(format!("echo pre-down %i >> {}; exit 1", log.display())),
+            post_down: Some(format!("echo post-down %i >> {}", log.display())),
+            ..Hooks::default()
+        };
+
+        // a failing PreDown doesn't stop the rollback, or replace the error of `up`
+        let err = rollback("tun9", &hooks, None, None, anyhow::anyhow!("PostUp failed"));
+        assert_eq!(err.to_string(), "PostUp failed");
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "pre-down tun9\npost-down tun9\n"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_iface_name() {
+        assert_eq!(
+            iface_name(Path::new("/etc/caetun/tun0.conf")).unwrap(),
+            "tun0"
+        );
+        assert!(iface_name(Path::new("/")).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+
+use crate::dns;
+
+/// Resolver looks up the addresses of a host name. The device resolves the host name endpoints of
+/// its peers through one, `SystemResolver` unless another is configured.
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, host: &Host) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// SystemResolver resolves through getaddrinfo(3), so /etc/hosts and nsswitch apply.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &Host) -> io::Result<Vec<SocketAddr>> {
+        Ok((host.name.as_str(), host.port).to_socket_addrs()?.collect())
+    }
+}
+
+/// Host is an endpoint given by name, `vpn.example.com:19988`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Host {
+    pub name: String,
+    pub port: u16,
+}
+
+impl FromStr for Host {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("missing port in {s}"))?;
+        let port = port.parse().map_err(|_| format!("invalid port in {s}"))?;
+        if !dns::is_domain(name) {
+            return Err(format!("invalid host name in {s}"));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.name, self.port)
+    }
+}
+
+/// pick the address to use out of those a host resolved to: `current` while it's still among
+/// them, so that round-robin dns doesn't move a peer around, the first one otherwise.
+pub fn pick(
+    addrs: impl IntoIterator<Item = SocketAddr>,
+    current: Option<SocketAddr>,
+) -> Option<SocketAddr> {
+    let mut first = None;
+    for addr in addrs {
+        if Some(addr) == current {
+            return current;
+        }
+        first = first.or(Some(addr));
+    }
+    first
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
+
+    /// StubResolver answers from a table that tests fill in, names missing from it fail.
+    #[derive(Default)]
+    pub(crate) struct StubResolver {
+        pub(crate) hosts: Mutex<HashMap<String, Vec<SocketAddr>>>,
+    }
+
+    impl Resolver for StubResolver {
+        fn resolve(&self, host: &Host) -> io::Result<Vec<SocketAddr>> {
+            self.hosts.lock().get(&host.name).cloned().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("unknown host {host}"))
+            })
+        }
+    }
+
+    #[test]
+    fn test_parse_host() {
+        let host: Host = "vpn.example.com:19988".parse().unwrap();
+        assert_eq!(host.name, "vpn.example.com");
+        assert_eq!(host.port, 19988);
+        assert_eq!(host.to_string(), "vpn.example.com:19988");
+
+        assert!("vpn.example.com".parse::<Host>().is_err());
+        assert!("vpn.example.com:http".parse::<Host>().is_err());
+        assert!("vpn_example:19988".parse::<Host>().is_err());
+    }
+
+    #[test]
+    fn test_pick() {
+        let a: SocketAddr = "192.0.2.1:19988".parse().unwrap();
+        let b: SocketAddr = "192.0.2.2:19988".parse().unwrap();
+        assert_eq!(pick([a, b], None), Some(a));
+        assert_eq!(pick([a, b], Some(b)), Some(b));
+        assert_eq!(pick([b], Some(a)), Some(b));
+        assert_eq!(pick([], Some(a)), None);
+    }
+
+    #[test]
+    fn test_system_resolver() {
+        let host = Host {
+            name: "localhost".into(),
+            port: 19988,
+        };
+        let addrs = SystemResolver.resolve(&host).unwrap();
+        assert!(addrs
+            .iter()
+            .all(|addr| addr.ip().is_loopback() && addr.port() == 19988));
+    }
+}
@@ -0,0 +1,41 @@
+use std::io;
+use std::net::IpAddr;
+
+use tracing::warn;
+
+/// Routes would install kernel routes for the allowed prefixes of the peers, this is not supported
+/// on macos yet: routes have to be added with route(8).
+pub struct Routes {
+    iface: String,
+    table: u32,
+}
+
+/// policy routing rules are never added on macos.
+pub fn remove_rules(_fwmark: u32, _table: u32) -> io::Result<()> {
+    Ok(())
+}
+
+impl Routes {
+    pub fn new(
+        iface: &str,
+        table: u32,
+        _addresses: &[(IpAddr, u8)],
+        _fwmark: Option<u32>,
+    ) -> io::Result<Self> {
+        warn!("installing routes is not supported on macos, add them to {iface} with route(8)");
+        Ok(Self {
+            iface: iface.to_string(),
+            table,
+        })
+    }
+
+    pub fn table(&self) -> u32 {
+        self.table
+    }
+
+    pub fn add(&self, ip: IpAddr, cidr: u8) {
+        warn!("not installing a route to {ip}/{cidr} dev {}", self.iface);
+    }
+
+    pub fn remove(&self, _ip: IpAddr, _cidr: u8) {}
+}
@@ -0,0 +1,228 @@
+use std::net::IpAddr;
+
+use ip_network::IpNetwork;
+use parking_lot::Mutex;
+use tracing::{debug, error, info, warn};
+
+use crate::netlink::{self, Netlink, Rule};
+
+/// Routes installs a kernel route into the tun interface for every allowed prefix of its peers.
+///
+/// Prefixes inside the subnet of an interface address are skipped, the kernel already routes
+/// those, and so is a default route into the main table, which would also capture the tunnel's
+/// own udp traffic.
+///
+/// With a firewall mark, `FwMark=`, the routes go into a table of their own, the main table is
+/// swapped for the table numbered like the mark, and two rules per address family steer every
+/// other lookup into it, like wg-quick does for a default route:
+///
+/// - `lookup main suppress_prefixlength 0`: the main table without its default route still wins,
+///   so local networks stay reachable.
+/// - `not fwmark <mark> lookup <table>`: everything else goes into the tunnel, except caetun's own
+///   marked udp packets.
+///
+/// The kernel drops the routes together with the interface, the rules outlive it and are removed
+/// when `Routes` is dropped, or by `remove_rules` from another process.
+pub struct Routes {
+    iface: String,
+    table: u32,
+    connected: Vec<IpNetwork>,
+    rules: Vec<Rule>,
+    nl: Mutex<Netlink>,
+}
+
+/// the main routing table, where routes go without `Table=`.
+const RT_TABLE_MAIN: u32 = libc::RT_TABLE_MAIN as u32;
+
+const DEFAULT_ROUTE: &str = "default route in the main table";
+
+/// the routing table routes go into: `table`, unless it's the main table and packets are marked.
+pub fn table(table: u32, fwmark: Option<u32>) -> u32 {
+    match fwmark {
+        Some(mark) if table == RT_TABLE_MAIN => mark,
+        _ => table,
+    }
+}
+
+/// the rules that exclude the packets marked with `fwmark` from routing table `table`, in the
+/// order they are added.
+pub fn rules(fwmark: u32, table: u32) -> Vec<Rule> {
+    [false, true]
+        .into_iter()
+        .flat_map(|ipv6| {
+            [
+                Rule {
+                    ipv6,
+                    table,
+                    not_fwmark: Some(fwmark),
+                    suppress_prefixlen: None,
+                },
+                // added last, a rule without a priority goes in front of the ones before it
+                Rule {
+                    ipv6,
+                    table: RT_TABLE_MAIN,
+                    not_fwmark: None,
+                    suppress_prefixlen: Some(0),
+                },
+            ]
+        })
+        .collect()
+}
+
+/// remove the rules `Routes::new` added for `fwmark` and `table`.
+pub fn remove_rules(fwmark: u32, table: u32) -> Result<(), netlink::Error> {
+    let mut nl = Netlink::new()?;
+    for rule in rules(fwmark, table) {
+        nl.del_rule(&rule)?;
+        info!("removed rule {rule}");
+    }
+    Ok(())
+}
+
+impl Routes {
+    /// route into `iface` in routing table `table`, see `table` for the table it ends up as. With
+    /// `fwmark` the rules that keep the marked packets out of it are added.
+    pub fn new(
+        iface: &str,
+        table: u32,
+        addresses: &[(IpAddr, u8)],
+        fwmark: Option<u32>,
+    ) -> Result<Self, netlink::Error> {
+        let connected = addresses
+            .iter()
+            .filter_map(|&(ip, cidr)| IpNetwork::new_truncate(ip, cidr).ok())
+            .collect();
+        let table = self::table(table, fwmark);
+
+        let mut routes = Self {
+            iface: iface.to_string(),
+            table,
+            connected,
+            rules: Vec::new(),
+            nl: Mutex::new(Netlink::new()?),
+        };
+        if let Some(mark) = fwmark {
+            for rule in rules(mark, table) {
+                routes.nl.get_mut().add_rule(&rule)?;
+                info!("rule {rule}");
+                routes.rules.push(rule);
+            }
+        }
+        Ok(routes)
+    }
+
+    /// the routing table the routes go into.
+    pub fn table(&self) -> u32 {
+        self.table
+    }
+
+    /// route `ip/cidr` into the interface.
+    pub fn add(&self, ip: IpAddr, cidr: u8) {
+        match self.skip_reason(ip, cidr) {
+            Some(DEFAULT_ROUTE) => {
+                warn!(
+                    "not routing {ip}/{cidr} into {}: it would route caetun's own traffic into \
+                     the tunnel, set FwMark= or Table= to another routing table",
+                    self.iface
+                );
+                return;
+            }
+            Some(reason) => {
+                debug!("not installing a route to {ip}/{cidr}: {reason}");
+                return;
+            }
+            None => (),
+        }
+
+        match self.nl.lock().add_route(&self.iface, ip, cidr, self.table) {
+            Ok(()) => info!("route {ip}/{cidr} dev {} table {}", self.iface, self.table),
+            Err(err) => error!("{err}"),
+        }
+    }
+
+    /// remove the route to `ip/cidr` installed by `add`.
+    pub fn remove(&self, ip: IpAddr, cidr: u8) {
+        if self.skip_reason(ip, cidr).is_some() {
+            return;
+        }
+
+        match self.nl.lock().del_route(&self.iface, ip, cidr, self.table) {
+            Ok(()) => info!("removed route {ip}/{cidr} dev {}", self.iface),
+            Err(netlink::Error::Request(_, err)) if err.raw_os_error() == Some(libc::ESRCH) => {
+                debug!("route {ip}/{cidr} dev {} is already gone", self.iface)
+            }
+            Err(err) => error!("{err}"),
+        }
+    }
+
+    fn skip_reason(&self, ip: IpAddr, cidr: u8) -> Option<&'static str> {
+        let Ok(network) = IpNetwork::new_truncate(ip, cidr) else {
+            return Some("invalid prefix");
+        };
+
+        if cidr == 0 && self.table == RT_TABLE_MAIN {
+            return Some(DEFAULT_ROUTE);
+        }
+
+        let covered = self.connected.iter().any(|subnet| {
+            subnet.netmask() <= network.netmask() && subnet.contains(network.network_address())
+        });
+        covered.then_some("covered by an interface address")
+    }
+}
+
+impl Drop for Routes {
+    fn drop(&mut self) {
+        for rule in self.rules.iter().rev() {
+            if let Err(err) = self.nl.get_mut().del_rule(rule) {
+                error!("{err}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_reason() {
+        let routes = Routes::new(
+            "caetun-test",
+            RT_TABLE_MAIN,
+            &[(IpAddr::from([10, 8, 0, 1]), 24)],
+            None,
+        )
+        .unwrap();
+
+        assert!(routes
+            .skip_reason(IpAddr::from([10, 8, 0, 2]), 32)
+            .is_some());
+        assert!(routes
+            .skip_reason(IpAddr::from([10, 8, 0, 0]), 24)
+            .is_some());
+        assert!(routes
+            .skip_reason(IpAddr::from([10, 8, 0, 0]), 16)
+            .is_none());
+        assert!(routes
+            .skip_reason(IpAddr::from([192, 168, 1, 0]), 24)
+            .is_none());
+        assert!(routes.skip_reason(IpAddr::from([0, 0, 0, 0]), 0).is_some());
+
+        let routes = Routes::new("caetun-test", 1000, &[], None).unwrap();
+        assert!(routes.skip_reason(IpAddr::from([0, 0, 0, 0]), 0).is_none());
+    }
+
+    #[test]
+    fn test_fwmark_table() {
+        assert_eq!(table(RT_TABLE_MAIN, None), RT_TABLE_MAIN);
+        assert_eq!(table(RT_TABLE_MAIN, Some(51820)), 51820);
+        assert_eq!(table(1000, Some(51820)), 1000);
+
+        let rules = rules(51820, 1000);
+        assert_eq!(rules.len(), 4);
+        assert_eq!(rules[0].to_string(), "-4 not fwmark 0xca6c table 1000");
+        assert_eq!(rules[1].to_string(), "-4 table 254 suppress_prefixlength 0");
+        assert!(rules[2].ipv6 && rules[3].ipv6);
+    }
+}
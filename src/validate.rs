@@ -0,0 +1,584 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+
+use anyhow::bail;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::conf::{Conf, Endpoint};
+use crate::peer::PEER_NAME_MAX_LEN;
+
+/// Severity tells whether a finding keeps a config from working, or only looks like a mistake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// Finding is a problem in a config that parses, `check` names the kind of problem.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    pub check: &'static str,
+    /// the peer it's about, none for the interface.
+    pub peer: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]: ", self.severity, self.check)?;
+        if let Some(peer) = &self.peer {
+            write!(f, "peer {peer}: ")?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+impl Finding {
+    fn new(severity: Severity, check: &'static str, peer: Option<&str>, message: String) -> Self {
+        Self {
+            severity,
+            check,
+            peer: peer.map(str::to_string),
+            message,
+        }
+    }
+}
+
+/// check `conf` for the mistakes the parser accepts: names that clash or don't fit a handshake,
+/// AllowedIPs that shadow each other and interface addresses no peer routes to.
+pub fn validate(conf: &Conf) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    check_names(conf, &mut findings);
+    check_allowed_ips(conf, &mut findings);
+    check_addresses(conf, &mut findings);
+    findings
+}
+
+/// what the daemon does with a config at startup: log the warnings of `validate`, fail on its
+/// errors.
+pub fn check(conf: &Conf) -> anyhow::Result<()> {
+    let findings = validate(conf);
+    for finding in findings.iter().filter(|f| f.severity == Severity::Warning) {
+        warn!("{finding}");
+    }
+    let errors: Vec<String> = findings
+        .iter()
+        .filter(|f| f.severity == Severity::Error)
+        .map(Finding::to_string)
+        .collect();
+    if !errors.is_empty() {
+        bail!("invalid config:\n  {}", errors.join("\n  "));
+    }
+    Ok(())
+}
+
+fn check_names(conf: &Conf, findings: &mut Vec<Finding>) {
+    let too_long = |what: &str, name: &str| {
+        format!(
+            "{what} `{name}` is {} bytes long, the handshake carries at most {PEER_NAME_MAX_LEN}",
+            name.len()
+        )
+    };
+    if conf.interface.name.len() > PEER_NAME_MAX_LEN {
+        findings.push(Finding::new(
+            Severity::Error,
+            "name-too-long",
+            None,
+            too_long("interface name", &conf.interface.name),
+        ));
+    }
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for peer in &conf.peers {
+        *counts.entry(&peer.name).or_default() += 1;
+    }
+    for (i, peer) in conf.peers.iter().enumerate() {
+        if peer.name.len() > PEER_NAME_MAX_LEN {
+            findings.push(Finding::new(
+                Severity::Error,
+                "name-too-long",
+                Some(&peer.name),
+                too_long("name", &peer.name),
+            ));
+        }
+        let count = counts[peer.name.as_str()];
+        // reported once, at the first of them
+        if count > 1 && !conf.peers[..i].iter().any(|p| p.name == peer.name) {
+            findings.push(Finding::new(
+                Severity::Error,
+                "duplicate-name",
+                Some(&peer.name),
+                format!(
+                    "{count} peers are named `{}`, only the last one is used",
+                    peer.name
+                ),
+            ));
+        }
+    }
+}
+
+/// NetworkFinding is a finding of `validate_network`, `conf` names the config it's in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NetworkFinding {
+    pub conf: String,
+    #[serde(flatten)]
+    pub finding: Finding,
+}
+
+impl fmt::Display for NetworkFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.conf, self.finding)
+    }
+}
+
+/// check configs that describe one network, each given with the name of its file, against each
+/// other, on top of `validate` for each of them: interface names and tunnel addresses are unique,
+/// peers know each other, endpoints use the listen port of their peer and the addresses of an
+/// interface are inside the AllowedIPs its peers have for it.
+pub fn validate_network(confs: &[(String, Conf)]) -> Vec<NetworkFinding> {
+    let mut findings = Vec::new();
+    let mut report = |i: usize, finding: Finding| {
+        findings.push(NetworkFinding {
+            conf: confs[i].0.clone(),
+            finding,
+        })
+    };
+
+    for (i, (_, conf)) in confs.iter().enumerate() {
+        for finding in validate(conf) {
+            report(i, finding);
+        }
+    }
+
+    let mut by_name: HashMap<&str, usize> = HashMap::new();
+    let mut by_address: HashMap<IpAddr, usize> = HashMap::new();
+    for (i, (_, conf)) in confs.iter().enumerate() {
+        let name = conf.interface.name.as_str();
+        if let Some(&first) = by_name.get(name) {
+            report(
+                i,
+                Finding::new(
+                    Severity::Error,
+                    "duplicate-interface",
+                    None,
+                    format!(
+                        "interface name `{name}` is also the name in {}",
+                        confs[first].0
+                    ),
+                ),
+            );
+        } else {
+            by_name.insert(name, i);
+        }
+
+        for &(ip, _) in &conf.interface.address {
+            match by_address.get(&ip) {
+                Some(&first) if first != i => report(
+                    i,
+                    Finding::new(
+                        Severity::Error,
+                        "address-collision",
+                        None,
+                        format!(
+                            "Address {ip} is also the address of {} in {}",
+                            confs[first].1.interface.name, confs[first].0
+                        ),
+                    ),
+                ),
+                Some(_) => (),
+                None => {
+                    by_address.insert(ip, i);
+                }
+            }
+        }
+    }
+
+    for (i, (_, conf)) in confs.iter().enumerate() {
+        let name = conf.interface.name.as_str();
+        for peer in &conf.peers {
+            let Some(&j) = by_name.get(peer.name.as_str()) else {
+                report(
+                    i,
+                    Finding::new(
+                        Severity::Warning,
+                        "unknown-peer",
+                        Some(&peer.name),
+                        "none of the configs is for this peer".to_string(),
+                    ),
+                );
+                continue;
+            };
+            let (file, remote) = &confs[j];
+
+            let Some(back) = remote.peers.iter().find(|p| p.name == name) else {
+                report(
+                    i,
+                    Finding::new(
+                        Severity::Error,
+                        "missing-peer",
+                        Some(&peer.name),
+                        format!("{file} has no peer named `{name}`, it rejects our handshakes"),
+                    ),
+                );
+                continue;
+            };
+
+            let port = match &peer.endpoint {
+                Some(Endpoint::Addr(addr)) => Some(addr.port()),
+                Some(Endpoint::Host(host)) => Some(host.port),
+                None => None,
+            };
+            if let Some(port) = port.filter(|&port| port != remote.interface.listen_port) {
+                report(
+                    i,
+                    Finding::new(
+                        Severity::Error,
+                        "endpoint-port",
+                        Some(&peer.name),
+                        format!(
+                            "Endpoint port {port} isn't the ListenPort {} in {file}",
+                            remote.interface.listen_port
+                        ),
+                    ),
+                );
+            }
+
+            for &(ip, _) in &conf.interface.address {
+                let allowed = back
+                    .allowed_ips
+                    .iter()
+                    .any(|&(net, prefix)| Net::new(net, prefix).contains(ip));
+                if !allowed {
+                    report(
+                        i,
+                        Finding::new(
+                            Severity::Error,
+                            "address-not-allowed",
+                            Some(&peer.name),
+                            format!(
+                                "Address {ip} is outside the AllowedIPs for `{name}` in {file}, our packets are dropped"
+                            ),
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    findings
+}
+
+/// Net is an ip network as numbers, `addr` already truncated to `prefix`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Net {
+    v6: bool,
+    addr: u128,
+    prefix: u8,
+}
+
+impl Net {
+    pub(crate) fn new(ip: IpAddr, prefix: u8) -> Self {
+        let (v6, addr, bits) = match ip {
+            IpAddr::V4(ip) => (false, u32::from(ip) as u128, 32),
+            IpAddr::V6(ip) => (true, u128::from(ip), 128),
+        };
+        let prefix = prefix.min(bits);
+        Self {
+            v6,
+            addr: addr & Self::mask(bits, prefix),
+            prefix,
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        if self.v6 {
+            128
+        } else {
+            32
+        }
+    }
+
+    fn mask(bits: u8, prefix: u8) -> u128 {
+        let host_bits = bits - prefix;
+        let all = u128::MAX >> (128 - bits);
+        all.checked_shl(host_bits.into()).unwrap_or(0) & all
+    }
+
+    pub(crate) fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// the `n`th address of the network, `None` past its end.
+    pub(crate) fn nth(&self, n: u128) -> Option<IpAddr> {
+        let host_bits = self.bits() - self.prefix;
+        if host_bits < 128 && n >> host_bits != 0 {
+            return None;
+        }
+        let addr = self.addr | n;
+        Some(match self.v6 {
+            true => IpAddr::from(addr.to_be_bytes()),
+            false => IpAddr::from((addr as u32).to_be_bytes()),
+        })
+    }
+
+    /// whether `ip` is in the network.
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        self.covers(&Net::new(ip, 128))
+    }
+
+    /// whether `other` is this network or inside it.
+    fn covers(&self, other: &Net) -> bool {
+        self.v6 == other.v6
+            && self.prefix <= other.prefix
+            && other.addr & Self::mask(self.bits(), self.prefix) == self.addr
+    }
+
+    fn overlaps(&self, other: &Net) -> bool {
+        self.covers(other) || other.covers(self)
+    }
+}
+
+/// AllowedIPs of two peers that are the same network are an error, the later one replaces the
+/// other in the routing trie. One inside the other is a warning, the more specific one wins, which
+/// is often what's meant.
+fn check_allowed_ips(conf: &Conf, findings: &mut Vec<Finding>) {
+    let mut nets: Vec<(Net, usize)> = conf
+        .peers
+        .iter()
+        .enumerate()
+        .flat_map(|(i, peer)| {
+            peer.allowed_ips
+                .iter()
+                .map(move |&(ip, prefix)| (Net::new(ip, prefix), i))
+        })
+        .collect();
+    // every network comes after the networks containing it
+    nets.sort();
+
+    let name = |i: usize| conf.peers[i].name.as_str();
+    let mut enclosing: Vec<(Net, usize)> = Vec::new();
+    for (net, peer) in nets {
+        while enclosing
+            .last()
+            .is_some_and(|(outer, _)| !outer.covers(&net))
+        {
+            enclosing.pop();
+        }
+        if let Some(&(outer, other)) = enclosing.last() {
+            if other != peer {
+                let this = format!("AllowedIPs {}", net);
+                let finding = if outer == net {
+                    Finding::new(
+                        Severity::Error,
+                        "overlapping-allowed-ips",
+                        Some(name(peer)),
+                        format!("{this} are also allowed for peer {}, only one of them gets the traffic", name(other)),
+                    )
+                } else {
+                    Finding::new(
+                        Severity::Warning,
+                        "overlapping-allowed-ips",
+                        Some(name(peer)),
+                        format!(
+                            "{this} are inside {} of peer {}, which doesn't get that traffic",
+                            outer,
+                            name(other)
+                        ),
+                    )
+                };
+                findings.push(finding);
+            }
+        }
+        enclosing.push((net, peer));
+    }
+}
+
+/// an interface address that no peer's AllowedIPs overlap can't be reached over the tunnel.
+fn check_addresses(conf: &Conf, findings: &mut Vec<Finding>) {
+    let routes: Vec<Net> = conf
+        .peers
+        .iter()
+        .flat_map(|peer| &peer.allowed_ips)
+        .map(|&(ip, prefix)| Net::new(ip, prefix))
+        .collect();
+    if routes.is_empty() {
+        return;
+    }
+    for &(ip, prefix) in &conf.interface.address {
+        let net = Net::new(ip, prefix);
+        if !routes.iter().any(|route| route.overlaps(&net)) {
+            findings.push(Finding::new(
+                Severity::Warning,
+                "address-outside-routes",
+                None,
+                format!("Address {ip}/{prefix} is outside the AllowedIPs of every peer"),
+            ));
+        }
+    }
+}
+
+impl fmt::Display for Net {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}",
+            self.nth(0).expect("0 is in every network"),
+            self.prefix
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn findings(source: &str) -> Vec<(Severity, &'static str, Option<String>)> {
+        validate(&Conf::parse_from(source).unwrap())
+            .into_iter()
+            .map(|f| (f.severity, f.check, f.peer))
+            .collect()
+    }
+
+    #[test]
+    fn test_valid() {
+        let source = "[Interface]\nName=hub\nAddress=10.8.0.1/24\n\
+            [Peer]\nName=a\nAllowedIPs=10.8.0.2/32\n\
+            [Peer]\nName=b\nAllowedIPs=10.8.0.3/32, fd00::3/128\n";
+        assert_eq!(findings(source), vec![]);
+        assert!(check(&Conf::parse_from(source).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_names() {
+        let long = "x".repeat(PEER_NAME_MAX_LEN + 1);
+        let source = format!(
+            "[Interface]\nName=hub\nAddress=10.8.0.1/24\n\
+            [Peer]\nName=a\n[Peer]\nName={long}\n[Peer]\nName=a\n[Peer]\nName=a\n"
+        );
+        assert_eq!(
+            findings(&source),
+            vec![
+                (Severity::Error, "duplicate-name", Some("a".into())),
+                (Severity::Error, "name-too-long", Some(long.clone())),
+            ]
+        );
+        assert!(check(&Conf::parse_from(&source).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_overlapping_allowed_ips() {
+        let source = "[Interface]\nName=hub\nAddress=10.8.0.1/24\n\
+            [Peer]\nName=gw\nAllowedIPs=0.0.0.0/0, 10.8.0.0/24\n\
+            [Peer]\nName=a\nAllowedIPs=10.8.0.2/32, 10.8.0.0/24\n\
+            [Peer]\nName=b\nAllowedIPs=10.8.0.2/32, 192.168.0.0/16\n";
+        assert_eq!(
+            findings(source),
+            vec![
+                (Severity::Error, "overlapping-allowed-ips", Some("a".into())),
+                (Severity::Error, "overlapping-allowed-ips", Some("b".into())),
+                (
+                    Severity::Warning,
+                    "overlapping-allowed-ips",
+                    Some("b".into())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_address_outside_routes() {
+        let source = "[Interface]\nName=client\nAddress=10.9.0.2/24, fd00::2/64\n\
+            [Peer]\nName=hub\nAllowedIPs=10.8.0.0/24, fd00::/48\n";
+        let findings = validate(&Conf::parse_from(source).unwrap());
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].check, "address-outside-routes");
+        assert_eq!(
+            findings[0].to_string(),
+            "warning[address-outside-routes]: Address 10.9.0.2/24 is outside the AllowedIPs of every peer"
+        );
+    }
+
+    #[test]
+    fn test_net() {
+        let net = Net::new("10.8.0.7".parse().unwrap(), 24);
+        assert_eq!(net.to_string(), "10.8.0.0/24");
+        assert_eq!(net.nth(255), Some(IpAddr::from([10, 8, 0, 255])));
+        assert_eq!(net.nth(256), None);
+        assert!(net.contains([10, 8, 0, 7].into()));
+        assert!(Net::new([0, 0, 0, 0].into(), 0).covers(&net));
+        assert!(!net.covers(&Net::new([10, 8, 1, 0].into(), 24)));
+        assert!(!net.covers(&Net::new("::".parse().unwrap(), 0)));
+        let net = Net::new("fd00::1".parse().unwrap(), 128);
+        assert_eq!(net.to_string(), "fd00::1/128");
+        assert_eq!(
+            Net::new("::".parse().unwrap(), 0).nth(u128::MAX),
+            Some(IpAddr::from([0xffffu16; 8]))
+        );
+    }
+
+    #[test]
+    fn test_validate_network() {
+        let conf = |file: &str, source: &str| (file.to_string(), Conf::parse_from(source).unwrap());
+        let network = [
+            conf("server.conf", include_str!("../config/server.conf")),
+            conf("client1.conf", include_str!("../config/client1.conf")),
+            conf("client2.conf", include_str!("../config/client2.conf")),
+            conf("utun8.conf", include_str!("../config/utun8.conf")),
+        ];
+        assert_eq!(validate_network(&network), vec![]);
+
+        // the configs drifted apart
+        let network = [
+            conf("server.conf", include_str!("../config/server.conf")),
+            conf(
+                "client1.conf",
+                "[Interface]\nName=client1\nAddress=10.8.0.5/24\n\
+                [Peer]\nName=server\nEndpoint=172.18.0.22:51820\nAllowedIPs=10.8.0.0/24\n\
+                [Peer]\nName=gateway\nAllowedIPs=0.0.0.0/0\n",
+            ),
+            conf(
+                "client2.conf",
+                "[Interface]\nName=client2\nAddress=10.8.0.1/24\n\
+                [Peer]\nName=server\nAllowedIPs=10.8.0.0/24\n",
+            ),
+            conf(
+                "macos.conf",
+                "[Interface]\nName=macos\nAddress=10.8.0.4/24\n\
+                [Peer]\nName=client1\nAllowedIPs=10.8.0.2/32\n",
+            ),
+        ];
+        let findings: Vec<_> = validate_network(&network)
+            .into_iter()
+            .map(|f| (f.conf, f.finding.check, f.finding.peer))
+            .collect();
+        let finding = |file: &str, check, peer: Option<&str>| {
+            (file.to_string(), check, peer.map(str::to_string))
+        };
+        assert_eq!(
+            findings,
+            vec![
+                finding("client1.conf", "overlapping-allowed-ips", Some("server")),
+                finding("client2.conf", "address-collision", None),
+                finding("server.conf", "missing-peer", Some("macos")),
+                finding("client1.conf", "endpoint-port", Some("server")),
+                finding("client1.conf", "address-not-allowed", Some("server")),
+                finding("client1.conf", "unknown-peer", Some("gateway")),
+                finding("client2.conf", "address-not-allowed", Some("server")),
+                finding("macos.conf", "missing-peer", Some("client1")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,330 @@
+use std::fmt;
+use std::fmt::Write as _;
+
+use crate::conf::{Conf, ConfError};
+use crate::hooks;
+use crate::ini;
+
+/// Unmapped is a key of one side that the other has no counterpart for, which `import` and
+/// `export` leave out and report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unmapped {
+    /// `Interface` or `Peer <name>`.
+    pub section: String,
+    pub key: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Unmapped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.section, self.key, self.reason)
+    }
+}
+
+/// the keys of a wg-quick `[Interface]` caetun takes as they are.
+const INTERFACE_KEYS: &[&str] = &[
+    "Address",
+    "ListenPort",
+    "MTU",
+    "Table",
+    "FwMark",
+    "PreUp",
+    "PostUp",
+    "PreDown",
+    "PostDown",
+    "DNS",
+];
+
+/// the keys of a wg-quick `[Peer]` caetun takes as they are.
+const PEER_KEYS: &[&str] = &["Endpoint", "AllowedIPs"];
+
+/// the keys wg-quick takes several times, their values add up.
+const LIST_KEYS: &[&str] = &["Address", "DNS", "AllowedIPs"];
+
+const NO_KEYS: &str = "caetun has no keys, peers are identified by their names";
+
+/// the wg-quick keys caetun has no counterpart for, and why.
+const DROPPED: &[(&str, &str)] = &[
+    ("PrivateKey", NO_KEYS),
+    ("PublicKey", NO_KEYS),
+    ("PresharedKey", NO_KEYS),
+    ("SaveConfig", "caetun never writes its config back"),
+    ("PersistentKeepalive", "caetun sends no keepalives"),
+];
+
+/// import a wg-quick config. The names caetun identifies interfaces and peers by come from a
+/// `# Name = ` comment in their section or right above it, the interface is named `name` and the
+/// peers after their position without one. What has no counterpart in caetun is left out and
+/// returned along, the rest goes through the checks of `Conf::parse_from`.
+pub fn import(source: &str, name: &str) -> Result<(Conf, Vec<Unmapped>), ConfError> {
+    let lines: Vec<&str> = source.lines().collect();
+    let parsed = ini::parse(source)?;
+    let mut sections = Vec::new();
+    let mut unmapped = Vec::new();
+
+    for (i, section) in parsed.iter().enumerate() {
+        let end = parsed.get(i + 1).map_or(lines.len(), |next| next.line - 1);
+        let comment = name_comment(&lines, section.line, end);
+        let (keys, name) = match section.name.as_str() {
+            "Interface" => (
+                INTERFACE_KEYS,
+                comment.clone().unwrap_or_else(|| name.to_string()),
+            ),
+            "Peer" => (
+                PEER_KEYS,
+                comment.clone().unwrap_or_else(|| format!("peer{i}")),
+            ),
+            // an unknown section, for `Conf::from_sections` to report
+            _ => {
+                sections.push(section.clone());
+                continue;
+            }
+        };
+        let title = match section.name.as_str() {
+            "Peer" => format!("Peer {name}"),
+            _ => section.name.clone(),
+        };
+
+        let mut entries: Vec<ini::Entry> = vec![ini::Entry {
+            key: "Name".to_string(),
+            value: name.clone(),
+            line: 0,
+            key_column: 0,
+            value_column: 0,
+        }];
+        for entry in &section.entries {
+            // wg-quick ignores the case of keys and cuts values at a `#`
+            let same = |key: &str| key.eq_ignore_ascii_case(&entry.key);
+            let value = entry.value.split('#').next().unwrap_or_default().trim();
+            let Some(&key) = keys.iter().find(|key| same(key)) else {
+                match DROPPED.iter().find(|(key, _)| same(key)) {
+                    Some((key, reason)) => unmapped.push(Unmapped {
+                        section: title.clone(),
+                        key: key.to_string(),
+                        reason: reason.to_string(),
+                    }),
+                    // an unknown key, for `Conf::from_sections` to report
+                    None => entries.push(entry.clone()),
+                }
+                continue;
+            };
+            if key == "FwMark" && matches!(value, "off" | "0") {
+                continue;
+            }
+            match entries.iter_mut().find(|e| e.key == key) {
+                Some(first) if LIST_KEYS.contains(&key) => {
+                    first.value = format!("{}, {value}", first.value);
+                }
+                // wg-quick takes hooks several times too and runs them in order
+                Some(first) if hooks::KEYS.contains(&key) => {
+                    first.value = format!("{}; {value}", first.value);
+                }
+                _ => entries.push(ini::Entry {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    ..entry.clone()
+                }),
+            }
+        }
+        if section.name == "Peer" && comment.is_none() {
+            unmapped.push(Unmapped {
+                section: title,
+                key: "Name".to_string(),
+                reason: "no `# Name = ` comment with the name of its interface, named after its \
+                    position"
+                    .to_string(),
+            });
+        }
+        sections.push(ini::Section {
+            entries,
+            ..section.clone()
+        });
+    }
+
+    Ok((Conf::from_sections(sections)?, unmapped))
+}
+
+/// the value of a `# Name = ` comment in the section with the header at `line` and ending at
+/// `end`, or in the comment lines right above the header. Those above the next header are its.
+fn name_comment(lines: &[&str], line: usize, end: usize) -> Option<String> {
+    let comment = |line: &&&str| line.trim_start().starts_with('#');
+    let above = lines[..line - 1].iter().rev().take_while(comment);
+    let mut body = &lines[line..end];
+    if end < lines.len() {
+        let next = body.iter().rev().take_while(comment).count();
+        body = &body[..body.len() - next];
+    }
+    above.chain(body).find_map(|line| {
+        let (key, value) = line.trim_start().strip_prefix('#')?.split_once('=')?;
+        let value = value.trim();
+        (key.trim().eq_ignore_ascii_case("Name") && !value.is_empty()).then(|| value.to_string())
+    })
+}
+
+/// export a config as a wg-quick config, with the names in `# Name = ` comments `import` reads
+/// back. WireGuard needs keys caetun doesn't have, they're reported along with the settings
+/// WireGuard has no counterpart for, and have to be added before the config is used.
+pub fn export(conf: &Conf) -> (String, Vec<Unmapped>) {
+    let mut out = String::new();
+    let mut unmapped = Vec::new();
+    let mut report = |section: &str, key: &str, reason: &str| {
+        unmapped.push(Unmapped {
+            section: section.to_string(),
+            key: key.to_string(),
+            reason: reason.to_string(),
+        })
+    };
+
+    out.push_str("[Interface]\n");
+    report(
+        "Interface",
+        "PrivateKey",
+        "caetun has no keys, one has to be added",
+    );
+    // WireGuard listens on a random port without one
+    let mut entries = conf.interface.entries();
+    if !entries.iter().any(|(key, _)| *key == "ListenPort") {
+        entries.insert(2, ("ListenPort", conf.interface.listen_port.to_string()));
+    }
+    for (key, value) in entries {
+        match key {
+            "Name" => writeln!(out, "# Name = {value}"),
+            "ListenAddress" | "BindInterface" => {
+                report("Interface", key, "WireGuard listens on every address");
+                continue;
+            }
+            "ResolveInterval" => {
+                report("Interface", key, "wg-quick resolves endpoints once");
+                continue;
+            }
+            _ => writeln!(out, "{key} = {value}"),
+        }
+        .unwrap();
+    }
+
+    for peer in &conf.peers {
+        let section = format!("Peer {}", peer.name);
+        out.push_str("\n[Peer]\n");
+        report(
+            &section,
+            "PublicKey",
+            "caetun has no keys, one has to be added",
+        );
+        for (key, value) in peer.entries() {
+            match key {
+                "Name" => writeln!(out, "# Name = {value}").unwrap(),
+                "Batch" => report(&section, key, "WireGuard doesn't aggregate packets"),
+                _ => writeln!(out, "{key} = {value}").unwrap(),
+            }
+        }
+    }
+
+    (out, unmapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WG: &str = "[Interface]
+PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+Address = 10.8.0.1/24
+Address = fd00:8::1/64
+listenport = 51820
+DNS = 192.0.2.53 # the resolver
+FwMark = off
+PostUp = iptables -A FORWARD -i %i -j ACCEPT
+PostUp = iptables -t nat -A POSTROUTING -o eth0 -j MASQUERADE
+SaveConfig = true
+
+# Name = laptop
+[Peer]
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+PresharedKey = /UwcSPg38hW/D9Y3tcS1FOV0K1wuURMbS0sesJEP5ak=
+AllowedIPs = 10.8.0.2/32
+AllowedIPs = fd00:8::2/128
+PersistentKeepalive = 25
+
+[Peer]
+PublicKey = TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=
+# name = phone
+Endpoint = phone.example:51820
+AllowedIPs = 10.8.0.3/32
+
+[Peer]
+PublicKey = gN65BkIKy1eCE9pP1wdc8ROUtkHLF2PfAqYdyYBz6EA=
+AllowedIPs = 10.8.0.4/32
+";
+
+    #[test]
+    fn test_import() {
+        let (conf, unmapped) = import(WG, "wg0").unwrap();
+        let expected = Conf::parse_from(
+            "[Interface]\nName=wg0\nAddress=10.8.0.1/24, fd00:8::1/64\nListenPort=51820\n\
+             DNS=192.0.2.53\nPostUp=iptables -A FORWARD -i %i -j ACCEPT; \
+             iptables -t nat -A POSTROUTING -o eth0 -j MASQUERADE\n\
+             [Peer]\nName=laptop\nAllowedIPs=10.8.0.2/32, fd00:8::2/128\n\
+             [Peer]\nName=phone\nEndpoint=phone.example:51820\nAllowedIPs=10.8.0.3/32\n\
+             [Peer]\nName=peer3\nAllowedIPs=10.8.0.4/32\n",
+        )
+        .unwrap();
+        assert_eq!(conf, expected);
+
+        let unmapped: Vec<String> = unmapped.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            unmapped,
+            [
+                "[Interface] PrivateKey: caetun has no keys, peers are identified by their names",
+                "[Interface] SaveConfig: caetun never writes its config back",
+                "[Peer laptop] PublicKey: caetun has no keys, peers are identified by their names",
+                "[Peer laptop] PresharedKey: caetun has no keys, peers are identified by their names",
+                "[Peer laptop] PersistentKeepalive: caetun sends no keepalives",
+                "[Peer phone] PublicKey: caetun has no keys, peers are identified by their names",
+                "[Peer peer3] PublicKey: caetun has no keys, peers are identified by their names",
+                "[Peer peer3] Name: no `# Name = ` comment with the name of its interface, named \
+                 after its position",
+            ]
+        );
+
+        // keys neither side knows fail like they do in a caetun config
+        let err = import("[Interface]\nAddress = 10.8.0.1/24\nMTUU = 1380\n", "wg0").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 3, column 1: [Interface]: unknown key MTUU, did you mean MTU?"
+        );
+        let err = import("[Interface]\nAddress = 10.8.0.1/33\n", "wg0").unwrap_err();
+        assert!(err.to_string().starts_with("line 2, column 11: "), "{err}");
+    }
+
+    #[test]
+    fn test_export() {
+        let conf = Conf::parse_from(
+            "[Interface]\nName=server\nAddress=10.8.0.1/24\nBindInterface=eth0\nMTU=1380\n\
+             [Peer]\nName=laptop\nEndpoint=192.0.2.2:19988\nAllowedIPs=10.8.0.2/32\nBatch=true\n",
+        )
+        .unwrap();
+        let (wg, unmapped) = export(&conf);
+        assert_eq!(
+            wg,
+            "[Interface]\n# Name = server\nAddress = 10.8.0.1/24\nListenPort = 19988\nMTU = 1380\n\
+             \n[Peer]\n# Name = laptop\nEndpoint = 192.0.2.2:19988\nAllowedIPs = 10.8.0.2/32\n"
+        );
+        let unmapped: Vec<String> = unmapped.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            unmapped,
+            [
+                "[Interface] PrivateKey: caetun has no keys, one has to be added",
+                "[Interface] BindInterface: WireGuard listens on every address",
+                "[Peer laptop] PublicKey: caetun has no keys, one has to be added",
+                "[Peer laptop] Batch: WireGuard doesn't aggregate packets",
+            ]
+        );
+
+        // the names survive the way back
+        let (imported, _) = import(&wg, "wg0").unwrap();
+        let mut expected = conf.clone();
+        expected.interface.bind_interface = None;
+        expected.peers[0].batch = false;
+        assert_eq!(imported, expected);
+    }
+}
@@ -0,0 +1,105 @@
+//! Loads a hub config with tens of thousands of peers and reports how much memory the peer
+//! table keeps and how long it takes to get there.
+//!
+//! Run with `cargo test --release --test scale -- --nocapture` to see the numbers.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use caetun::conf::Conf;
+use caetun::peer::{Peer, PeerName};
+use caetun::peer_table::PeerTable;
+
+const PEERS: usize = 50_000;
+
+/// Upper bound of retained heap per peer, a per-peer `AllowedIps` trie alone used to be ~17 KiB.
+const MAX_BYTES_PER_PEER: usize = 1024;
+
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn peer_ip(i: usize) -> [u8; 4] {
+    [10, (i >> 16) as u8, (i >> 8) as u8, i as u8]
+}
+
+fn hub_conf(peers: usize) -> String {
+    let mut conf = String::from("[Interface]\nName=hub\nAddress=10.0.0.1/8\n");
+    for i in 1..=peers {
+        let [a, b, c, d] = peer_ip(i);
+        write!(
+            conf,
+            "\n[Peer]\nName=client{i}\nAllowedIPs={a}.{b}.{c}.{d}/32\n"
+        )
+        .unwrap();
+    }
+    conf
+}
+
+#[test]
+fn test_load_50k_peers() {
+    let source = hub_conf(PEERS);
+
+    let start = Instant::now();
+    let conf = Conf::parse_from(&source).unwrap();
+    let parsed = start.elapsed();
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let start = Instant::now();
+
+    let mut table = PeerTable::new();
+    table.reserve(conf.peers.len());
+    for peer_conf in &conf.peers {
+        let name = PeerName::new(&peer_conf.name).unwrap();
+        table.insert(name, Peer::default(), &peer_conf.allowed_ips);
+    }
+
+    let loaded = start.elapsed();
+    let retained = ALLOCATED.load(Ordering::Relaxed) - before;
+
+    println!(
+        "{PEERS} peers: parse {parsed:?}, load {loaded:?}, {} KiB retained ({} bytes per peer)",
+        retained / 1024,
+        retained / PEERS
+    );
+
+    assert_eq!(table.len(), PEERS);
+    for i in [1, PEERS / 2, PEERS] {
+        let name = format!("client{i}");
+        let peer = table.by_name(PeerName::from(name.as_bytes())).unwrap();
+        assert_eq!(peer.local_idx() as usize, i - 1);
+        assert_eq!(
+            table.by_ip(IpAddr::from(peer_ip(i))).map(|p| p.local_idx()),
+            Some(peer.local_idx())
+        );
+    }
+    assert!(
+        retained / PEERS <= MAX_BYTES_PER_PEER,
+        "{} bytes per peer",
+        retained / PEERS
+    );
+}